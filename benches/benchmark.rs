@@ -23,6 +23,26 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     });
 
+    // Same as above, but instances are allocated from a pool
+    // Each pool slot reserves address space for the maximum memory
+    let mut config = EnvConfig::new(0x1000000, None);
+    config.set_instance_pool_size(Some(100));
+    let environment = Environment::new(config).unwrap();
+
+    let raw_module = std::fs::read("./target/wasm/hello.wasm").unwrap();
+    let module = rt.block_on(environment.create_module(raw_module)).unwrap();
+
+    c.bench_function("spawn process with pooling allocator", |b| {
+        b.to_async(&rt).iter(|| async {
+            module
+                .spawn("hello", Vec::new(), None)
+                .await
+                .unwrap()
+                .0
+                .await;
+        });
+    });
+
     // TODO: Plugin has a bug when run on modules without a table
     // let path = Path::new("target/wasm/stdlib.wasm");
     // let module = fs::read(path).unwrap();
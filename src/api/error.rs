@@ -4,14 +4,14 @@ use anyhow::Result;
 use wasmtime::{Caller, Linker, ValType};
 use wasmtime::{FuncType, Trap};
 
-use crate::{api::get_memory, state::ProcessState};
+use crate::{api::get_memory, capability::Capabilities, state::ProcessState};
 
 use super::link_if_match;
 
 // Register the error APIs to the linker
 pub(crate) fn register(
     linker: &mut Linker<ProcessState>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
     link_if_match(
         linker,
@@ -19,7 +19,7 @@ pub(crate) fn register(
         "string_size",
         FuncType::new([ValType::I64], [ValType::I32]),
         string_size,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -27,7 +27,7 @@ pub(crate) fn register(
         "to_string",
         FuncType::new([ValType::I64, ValType::I32], []),
         to_string,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -35,7 +35,7 @@ pub(crate) fn register(
         "drop",
         FuncType::new([ValType::I64], []),
         drop,
-        namespace_filter,
+        capabilities,
     )?;
     Ok(())
 }
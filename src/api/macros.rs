@@ -22,7 +22,7 @@ macro_rules! for_each_function_signature {
 
 macro_rules! generate_wrap_async_func {
     ($num:tt $($args:ident)*) => (paste::paste!{
-        // Adds async function to linker if the namespace matches the allowed list.
+        // Adds async function to linker if it's allowed by the capabilities.
         #[allow(dead_code)]
         pub(crate) fn [<link_async $num _if_match>]<T, $($args,)* R>(
             linker: &mut Linker<T>,
@@ -30,13 +30,13 @@ macro_rules! generate_wrap_async_func {
             name: &str,
             func_ty: FuncType,
             func: impl for<'a> Fn(Caller<'a, T>, $($args),*) -> Box<dyn Future<Output = R> + Send + 'a> + Send + Sync + 'static,
-            namespace_filter: &[String],
+            capabilities: &Capabilities,
         ) -> Result<()>
         where
             $($args: WasmTy,)*
             R: WasmRet,
         {
-            if namespace_matches_filter(namespace, name, namespace_filter) {
+            if capabilities.is_allowed(namespace, name) {
                 linker.[<func_wrap $num _async>](namespace, name, func)?;
             } else {
                 // If the host function is forbidden, we still want to add a fake function that always
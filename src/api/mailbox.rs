@@ -9,6 +9,7 @@ use wasmtime::{Caller, FuncType, Linker, Trap, ValType};
 
 use crate::{
     api::{error::IntoTrap, get_memory},
+    capability::Capabilities,
     message::{DataMessage, Message},
     process::Signal,
     state::ProcessState,
@@ -19,7 +20,7 @@ use super::{link_async2_if_match, link_if_match};
 // Register the mailbox APIs to the linker
 pub(crate) fn register(
     linker: &mut Linker<ProcessState>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
     link_if_match(
         linker,
@@ -27,7 +28,7 @@ pub(crate) fn register(
         "create_data",
         FuncType::new([ValType::I64, ValType::I64], []),
         create_data,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -35,7 +36,7 @@ pub(crate) fn register(
         "write_data",
         FuncType::new([ValType::I32, ValType::I32], [ValType::I32]),
         write_data,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -43,7 +44,7 @@ pub(crate) fn register(
         "read_data",
         FuncType::new([ValType::I32, ValType::I32], [ValType::I32]),
         read_data,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -51,7 +52,7 @@ pub(crate) fn register(
         "seek_data",
         FuncType::new([ValType::I64], []),
         seek_data,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -59,7 +60,15 @@ pub(crate) fn register(
         "get_tag",
         FuncType::new([], [ValType::I64]),
         get_tag,
-        namespace_filter,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::message",
+        "get_exit_reason",
+        FuncType::new([], [ValType::I32]),
+        get_exit_reason,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -67,7 +76,7 @@ pub(crate) fn register(
         "data_size",
         FuncType::new([], [ValType::I64]),
         data_size,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -75,7 +84,7 @@ pub(crate) fn register(
         "push_process",
         FuncType::new([ValType::I64], [ValType::I64]),
         push_process,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -83,7 +92,7 @@ pub(crate) fn register(
         "take_process",
         FuncType::new([ValType::I64], [ValType::I64]),
         take_process,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -91,7 +100,7 @@ pub(crate) fn register(
         "push_tcp_stream",
         FuncType::new([ValType::I64], [ValType::I64]),
         push_tcp_stream,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -99,7 +108,7 @@ pub(crate) fn register(
         "take_tcp_stream",
         FuncType::new([ValType::I64], [ValType::I64]),
         take_tcp_stream,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -107,7 +116,7 @@ pub(crate) fn register(
         "send",
         FuncType::new([ValType::I64], []),
         send,
-        namespace_filter,
+        capabilities,
     )?;
     link_async2_if_match(
         linker,
@@ -115,7 +124,7 @@ pub(crate) fn register(
         "send_receive_skip_search",
         FuncType::new([ValType::I64, ValType::I32], [ValType::I32]),
         send_receive_skip_search,
-        namespace_filter,
+        capabilities,
     )?;
     link_async2_if_match(
         linker,
@@ -123,18 +132,22 @@ pub(crate) fn register(
         "receive",
         FuncType::new([ValType::I64, ValType::I32], [ValType::I32]),
         receive,
-        namespace_filter,
+        capabilities,
     )?;
     Ok(())
 }
 
 //% lunatic::message
 //%
-//% There are two kinds of messages a lunatic process can receive:
+//% There are four kinds of messages a lunatic process can receive:
 //% * **data message** that contains a buffer of raw `u8` data and host side resources.
 //% * **signal message**, representing a signal that was turned into a message. By setting a flag,
 //%   a process can control if when a link dies the process should die too, or just receive a
 //%   signal message notifying it about the link's death.
+//% * **upgrade message**, requesting the process to continue running a new version of its module
+//%   (see `lunatic::process::code_change`).
+//% * **suspend message**, requesting the process to suspend itself, so that it can be restored
+//%   later from a snapshot (see `lunatic::process::suspend`).
 //%
 //% All messages have a `tag` allowing for selective receives. If there are already messages in the
 //% receiving queue, they will be first searched for a specific tag and the first match returned.
@@ -233,9 +246,15 @@ fn write_data(mut caller: Caller<ProcessState>, data_ptr: u32, data_len: u32) ->
         .or_trap("lunatic::message::write_data")?;
     let bytes = match &mut message {
         Message::Data(data) => data.write(buffer).or_trap("lunatic::message::write_data")?,
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
     // Put message back after writing to it.
     caller.data_mut().message = Some(message);
@@ -263,9 +282,15 @@ fn read_data(mut caller: Caller<ProcessState>, data_ptr: u32, data_len: u32) ->
         .or_trap("lunatic::message::read_data")?;
     let bytes = match &mut message {
         Message::Data(data) => data.read(buffer).or_trap("lunatic::message::read_data")?,
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
     // Put message back after reading from it.
     caller.data_mut().message = Some(message);
@@ -289,9 +314,15 @@ fn seek_data(mut caller: Caller<ProcessState>, index: u64) -> Result<(), Trap> {
         .or_trap("lunatic::message::seek_data")?;
     match &mut message {
         Message::Data(data) => data.seek(index as usize),
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
     Ok(())
 }
@@ -314,6 +345,32 @@ fn get_tag(caller: Caller<ProcessState>) -> Result<i64, Trap> {
     }
 }
 
+//% lunatic::message::get_exit_reason() -> u32
+//%
+//% Returns the reason why the linked or monitored process died:
+//% * 0 - The process finished normally, only reported to monitoring processes.
+//% * 1 - The process trapped or returned an error.
+//% * 2 - The process was killed by a signal or because a linked process died.
+//% * 3 - The process used up all of its fuel.
+//% * 4 - The process used up all of its CPU time.
+//% * 5 - The process was still running after its deadline.
+//%
+//% Traps:
+//% * If it's called without a signal message being inside of the scratch area.
+fn get_exit_reason(caller: Caller<ProcessState>) -> Result<u32, Trap> {
+    let message = caller
+        .data()
+        .message
+        .as_ref()
+        .or_trap("lunatic::message::get_exit_reason")?;
+    match message {
+        Message::Signal(_, reason) => Ok(reason.code()),
+        Message::Data(_) => Err(Trap::new("Unexpected `Message::Data` in scratch area")),
+        Message::Upgrade(_, _) => Err(Trap::new("Unexpected `Message::Upgrade` in scratch area")),
+        Message::Suspend(_) => Err(Trap::new("Unexpected `Message::Suspend` in scratch area")),
+    }
+}
+
 //% lunatic::message::data_size() -> u64
 //%
 //% Returns the size in bytes of the message buffer.
@@ -328,9 +385,15 @@ fn data_size(mut caller: Caller<ProcessState>) -> Result<u64, Trap> {
         .or_trap("lunatic::message::data_size")?;
     let bytes = match message {
         Message::Data(data) => data.size(),
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
 
     Ok(bytes as u64)
@@ -359,9 +422,15 @@ fn push_process(mut caller: Caller<ProcessState>, process_id: u64) -> Result<u64
         .or_trap("lunatic::message::push_process")?;
     let index = match message {
         Message::Data(data) => data.add_process(process) as u64,
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
     Ok(index)
 }
@@ -384,9 +453,15 @@ fn take_process(mut caller: Caller<ProcessState>, index: u64) -> Result<u64, Tra
         Message::Data(data) => data
             .take_process(index as usize)
             .or_trap("lunatic::message::take_process")?,
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
     Ok(caller.data_mut().resources.processes.add(process))
 }
@@ -406,6 +481,7 @@ fn push_tcp_stream(mut caller: Caller<ProcessState>, stream_id: u64) -> Result<u
         .tcp_streams
         .remove(stream_id)
         .or_trap("lunatic::message::push_tcp_stream")?;
+    caller.data_mut().quota.remove_tcp_stream();
     let message = caller
         .data_mut()
         .message
@@ -413,9 +489,15 @@ fn push_tcp_stream(mut caller: Caller<ProcessState>, stream_id: u64) -> Result<u
         .or_trap("lunatic::message::push_tcp_stream")?;
     let index = match message {
         Message::Data(data) => data.add_tcp_stream(stream) as u64,
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
     Ok(index)
 }
@@ -438,10 +520,17 @@ fn take_tcp_stream(mut caller: Caller<ProcessState>, index: u64) -> Result<u64,
         Message::Data(data) => data
             .take_tcp_stream(index as usize)
             .or_trap("lunatic::message::take_tcp_stream")?,
-        Message::Signal(_) => {
+        Message::Signal(..) => {
             return Err(Trap::new("Unexpected `Message::Signal` in scratch area"))
         }
+        Message::Upgrade(_, _) => {
+            return Err(Trap::new("Unexpected `Message::Upgrade` in scratch area"))
+        }
+        Message::Suspend(_) => {
+            return Err(Trap::new("Unexpected `Message::Suspend` in scratch area"))
+        }
     };
+    caller.data_mut().quota.add_tcp_stream();
     Ok(caller.data_mut().resources.tcp_streams.add(tcp_stream))
 }
 
@@ -510,10 +599,9 @@ fn send_receive_skip_search(
             .get(process_id)
             .or_trap("lunatic::message::send_receive_skip_search")?;
         process.send(Signal::Message(message));
-        if let Some(message) = tokio::select! {
-            _ = async_std::task::sleep(Duration::from_millis(timeout as u64)), if timeout != 0 => None,
-            message = caller.data_mut().message_mailbox.pop_skip_search(tag) => Some(message)
-        } {
+        let clock = caller.data().module.environment().clock().clone();
+        let pop = caller.data_mut().message_mailbox.pop_skip_search(tag);
+        if let Some(message) = clock.timeout(timeout_duration(timeout), pop).await {
             // Put the message into the scratch area
             caller.data_mut().message = Some(message);
             Ok(0)
@@ -527,7 +615,12 @@ fn send_receive_skip_search(
 //%
 //% Returns:
 //% * 0    if it's a data message.
-//% * 1    if it's a signal turned into a message.
+//% * 1    if it's a signal turned into a message, e.g. a linked process died or a monitored
+//%        process failed.
+//% * 2    if it's a request to upgrade the process to a new version of its module. The process
+//%        should call `lunatic::process::code_change` once it reaches a safe point.
+//% * 3    if it's a request to suspend the process. The process should call
+//%        `lunatic::process::suspend` once it reaches a safe point.
 //% * 9027 if call timed out.
 //%
 //% Takes the next message out of the queue or blocks until the next message is received if queue
@@ -535,7 +628,8 @@ fn send_receive_skip_search(
 //% is received and return it.
 //%
 //% If timeout is specified (value different from 0), the function will return on timeout
-//% expiration with value 9027.
+//% expiration with value 9027. In deterministic environments timeouts are driven by the virtual
+//% time instead.
 //%
 //% Once the message is received, functions like `lunatic::message::read_data()` can be used to
 //% extract data out of it.
@@ -549,13 +643,14 @@ fn receive(
             0 => None,
             tag => Some(tag),
         };
-        if let Some(message) = tokio::select! {
-            _ = async_std::task::sleep(Duration::from_millis(timeout as u64)), if timeout != 0 => None,
-            message = caller.data_mut().message_mailbox.pop(tag) => Some(message)
-        } {
+        let clock = caller.data().module.environment().clock().clone();
+        let pop = caller.data_mut().message_mailbox.pop(tag);
+        if let Some(message) = clock.timeout(timeout_duration(timeout), pop).await {
             let result = match message {
                 Message::Data(_) => 0,
-                Message::Signal(_) => 1,
+                Message::Signal(..) => 1,
+                Message::Upgrade(_, _) => 2,
+                Message::Suspend(_) => 3,
             };
             // Put the message into the scratch area
             caller.data_mut().message = Some(message);
@@ -565,3 +660,11 @@ fn receive(
         }
     })
 }
+
+// A timeout of 0 means waiting forever.
+fn timeout_duration(timeout: u32) -> Option<Duration> {
+    match timeout {
+        0 => None,
+        timeout => Some(Duration::from_millis(timeout as u64)),
+    }
+}
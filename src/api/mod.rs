@@ -13,18 +13,18 @@ use anyhow::Result;
 use wasmtime::{Caller, FuncType, IntoFunc, Linker, Memory, Trap, WasmRet, WasmTy};
 
 use self::error::IntoTrap;
-use crate::state::ProcessState;
+use crate::{capability::Capabilities, state::ProcessState};
 
 // Registers all sub-APIs to the `Linker`
 pub(crate) fn register(
     linker: &mut Linker<ProcessState>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
-    error::register(linker, namespace_filter)?;
-    process::register(linker, namespace_filter)?;
-    mailbox::register(linker, namespace_filter)?;
-    networking::register(linker, namespace_filter)?;
-    wasi::register(linker, namespace_filter)?;
+    error::register(linker, capabilities)?;
+    process::register(linker, capabilities)?;
+    mailbox::register(linker, capabilities)?;
+    networking::register(linker, capabilities)?;
+    wasi::register(linker, capabilities)?;
     Ok(())
 }
 
@@ -37,16 +37,16 @@ pub(crate) fn get_memory<T>(caller: &mut Caller<T>) -> std::result::Result<Memor
         .or_trap("Export `memory` is not a memory")
 }
 
-// Adds function to linker if the namespace matches the allowed list.
+// Adds function to linker if it's allowed by the capabilities.
 pub(crate) fn link_if_match<T, Params, Results>(
     linker: &mut Linker<T>,
     namespace: &str,
     name: &str,
     func_ty: FuncType,
     func: impl IntoFunc<T, Params, Results>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
-    if namespace_matches_filter(namespace, name, namespace_filter) {
+    if capabilities.is_allowed(namespace, name) {
         linker.func_wrap(namespace, name, func)?;
     } else {
         // If the host function is forbidden, we still want to add a fake function that always
@@ -66,14 +66,6 @@ pub(crate) fn link_if_match<T, Params, Results>(
 // Adds link_async1_if_match, link_async2_if_match, ...
 for_each_function_signature!(generate_wrap_async_func);
 
-fn namespace_matches_filter(namespace: &str, name: &str, namespace_filter: &[String]) -> bool {
-    let full_name = format!("{}::{}", namespace, name);
-    // Allow if any of the allowed namespaces matches the beginning of the full name.
-    namespace_filter
-        .iter()
-        .any(|allowed| full_name.starts_with(allowed))
-}
-
 mod tests {
     #[async_std::test]
     async fn import_filter_signature_matches() {
@@ -86,6 +78,17 @@ mod tests {
         let module = environment.create_module(raw_module).await.unwrap();
         module.spawn("hello", Vec::new(), None).await.unwrap();
 
+        // Denying single functions inside of an allowed namespace replaces only them with traps.
+        let mut config = EnvConfig::default();
+        config.deny_namespace("lunatic::process::spawn").unwrap();
+        config
+            .deny_namespace("wasi_snapshot_preview1::fd_write")
+            .unwrap();
+        let environment = Environment::new(config).unwrap();
+        let raw_module = std::fs::read("./target/wasm/all_imports.wasm").unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        module.spawn("hello", Vec::new(), None).await.unwrap();
+
         // This configuration should still compile, even all host calls will trap.
         let config = EnvConfig::new(0, None);
         let environment = Environment::new(config).unwrap();
@@ -7,12 +7,14 @@ use std::time::Duration;
 use anyhow::Result;
 use async_std::io::{ReadExt, WriteExt};
 use async_std::net::{TcpListener, TcpStream};
+use wasi_common::file::FdFlags;
 use wasmtime::{Caller, FuncType, Linker, ValType};
 use wasmtime::{Memory, Trap};
 
 use crate::api::error::IntoTrap;
 use crate::state::DnsIterator;
-use crate::{api::get_memory, state::ProcessState};
+use crate::wasi_socket::SocketKind;
+use crate::{api::get_memory, capability::Capabilities, state::ProcessState};
 
 use super::{
     link_async2_if_match, link_async3_if_match, link_async4_if_match, link_async5_if_match,
@@ -22,7 +24,7 @@ use super::{
 // Register the error APIs to the linker
 pub(crate) fn register(
     linker: &mut Linker<ProcessState>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
     link_async4_if_match(
         linker,
@@ -33,7 +35,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         resolve,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -41,7 +43,7 @@ pub(crate) fn register(
         "drop_dns_iterator",
         FuncType::new([ValType::I64], []),
         drop_dns_iterator,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -59,7 +61,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         resolve_next,
-        namespace_filter,
+        capabilities,
     )?;
     link_async6_if_match(
         linker,
@@ -77,7 +79,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         tcp_bind,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -85,7 +87,7 @@ pub(crate) fn register(
         "drop_tcp_listener",
         FuncType::new([ValType::I64], []),
         drop_tcp_listener,
-        namespace_filter,
+        capabilities,
     )?;
     link_async3_if_match(
         linker,
@@ -93,7 +95,7 @@ pub(crate) fn register(
         "tcp_accept",
         FuncType::new([ValType::I64, ValType::I32, ValType::I32], [ValType::I32]),
         tcp_accept,
-        namespace_filter,
+        capabilities,
     )?;
     link_async7_if_match(
         linker,
@@ -112,7 +114,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         tcp_connect,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -120,7 +122,7 @@ pub(crate) fn register(
         "drop_tcp_stream",
         FuncType::new([ValType::I64], []),
         drop_tcp_stream,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -128,7 +130,23 @@ pub(crate) fn register(
         "clone_tcp_stream",
         FuncType::new([ValType::I64], [ValType::I64]),
         clone_tcp_stream,
-        namespace_filter,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::networking",
+        "tcp_stream_into_wasi_fd",
+        FuncType::new([ValType::I64], [ValType::I32]),
+        tcp_stream_into_wasi_fd,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::networking",
+        "tcp_listener_into_wasi_fd",
+        FuncType::new([ValType::I64], [ValType::I32]),
+        tcp_listener_into_wasi_fd,
+        capabilities,
     )?;
     link_async5_if_match(
         linker,
@@ -145,7 +163,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         tcp_write_vectored,
-        namespace_filter,
+        capabilities,
     )?;
     link_async5_if_match(
         linker,
@@ -162,7 +180,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         tcp_read,
-        namespace_filter,
+        capabilities,
     )?;
     link_async2_if_match(
         linker,
@@ -170,7 +188,7 @@ pub(crate) fn register(
         "tcp_flush",
         FuncType::new([ValType::I64, ValType::I32], [ValType::I32]),
         tcp_flush,
-        namespace_filter,
+        capabilities,
     )?;
     Ok(())
 }
@@ -451,13 +469,22 @@ fn tcp_accept(
     socket_addr_id_ptr: u32,
 ) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
     Box::new(async move {
+        // Reserve space for the new stream before accepting the connection
+        if let Err(error) = caller.data_mut().try_add_tcp_stream() {
+            let error_id = caller.data_mut().errors.add(error);
+            let memory = get_memory(&mut caller)?;
+            memory
+                .write(&mut caller, id_ptr as usize, &error_id.to_le_bytes())
+                .or_trap("lunatic::networking::tcp_accept")?;
+            return Ok(1);
+        }
+
         let tcp_listener = caller
             .data()
             .resources
             .tcp_listeners
             .get(listener_id)
             .or_trap("lunatic::network::tcp_accept")?;
-
         let (tcp_stream_or_error_id, peer_addr_iter, result) = match tcp_listener.accept().await {
             Ok((stream, socket_addr)) => {
                 let stream_id = caller.data_mut().resources.tcp_streams.add(stream);
@@ -468,7 +495,10 @@ fn tcp_accept(
                     .add(DnsIterator::new(vec![socket_addr].into_iter()));
                 (stream_id, dns_iter_id, 0)
             }
-            Err(error) => (caller.data_mut().errors.add(error.into()), 0, 1),
+            Err(error) => {
+                caller.data_mut().quota.remove_tcp_stream();
+                (caller.data_mut().errors.add(error.into()), 0, 1)
+            }
         };
 
         let memory = get_memory(&mut caller)?;
@@ -531,13 +561,25 @@ fn tcp_connect(
             scope_id,
         )?;
 
+        // Reserve space for the new stream before connecting
+        if let Err(error) = caller.data_mut().try_add_tcp_stream() {
+            let error_id = caller.data_mut().errors.add(error);
+            memory
+                .write(&mut caller, id_u64_ptr as usize, &error_id.to_le_bytes())
+                .or_trap("lunatic::networking::tcp_connect")?;
+            return Ok(1);
+        }
+
         if let Some(result) = tokio::select! {
             _ = async_std::task::sleep(Duration::from_millis(timeout as u64)), if timeout != 0 => None,
             result = TcpStream::connect(socket_addr) => Some(result)
         } {
             let (stream_or_error_id, result) = match result {
                 Ok(stream) => (caller.data_mut().resources.tcp_streams.add(stream), 0),
-                Err(error) => (caller.data_mut().errors.add(error.into()), 1),
+                Err(error) => {
+                    caller.data_mut().quota.remove_tcp_stream();
+                    (caller.data_mut().errors.add(error.into()), 1)
+                }
             };
 
             memory
@@ -550,6 +592,7 @@ fn tcp_connect(
             Ok(result)
         } else {
             // Call timed out
+            caller.data_mut().quota.remove_tcp_stream();
             let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "Connect timed out");
             let error_id = caller.data_mut().errors.add(error.into());
             memory
@@ -573,6 +616,7 @@ fn drop_tcp_stream(mut caller: Caller<ProcessState>, tcp_stream_id: u64) -> Resu
         .tcp_streams
         .remove(tcp_stream_id)
         .or_trap("lunatic::networking::drop_tcp_stream")?;
+    caller.data_mut().quota.remove_tcp_stream();
     Ok(())
 }
 
@@ -590,10 +634,58 @@ fn clone_tcp_stream(mut caller: Caller<ProcessState>, tcp_stream_id: u64) -> Res
         .get(tcp_stream_id)
         .or_trap("lunatic::networking::clone_process")?
         .clone();
+    caller.data_mut().quota.add_tcp_stream();
     let id = caller.data_mut().resources.tcp_streams.add(stream);
     Ok(id)
 }
 
+//% lunatic::networking::tcp_stream_into_wasi_fd(tcp_stream_id: u64) -> u32
+//%
+//% Moves the TCP stream into the WASI file descriptor table and returns its file descriptor. The
+//% stream can then be used with `fd_read`, `fd_write` and the WASI `sock_*` functions, and is
+//% closed with `fd_close`. The ID is no longer valid afterwards.
+//%
+//% Traps:
+//% * If the stream ID doesn't exist.
+fn tcp_stream_into_wasi_fd(
+    mut caller: Caller<ProcessState>,
+    tcp_stream_id: u64,
+) -> Result<u32, Trap> {
+    let stream = caller
+        .data_mut()
+        .resources
+        .tcp_streams
+        .remove(tcp_stream_id)
+        .or_trap("lunatic::networking::tcp_stream_into_wasi_fd")?;
+    // The stream keeps counting against the quota until the guest closes the file descriptor
+    Ok(caller
+        .data_mut()
+        .insert_wasi_socket(SocketKind::Stream(stream), FdFlags::empty()))
+}
+
+//% lunatic::networking::tcp_listener_into_wasi_fd(tcp_listener_id: u64) -> u32
+//%
+//% Moves the TCP listener into the WASI file descriptor table and returns its file descriptor.
+//% Connections can then be accepted with the WASI `sock_accept` function. The ID is no longer
+//% valid afterwards.
+//%
+//% Traps:
+//% * If the listener ID doesn't exist.
+fn tcp_listener_into_wasi_fd(
+    mut caller: Caller<ProcessState>,
+    tcp_listener_id: u64,
+) -> Result<u32, Trap> {
+    let listener = caller
+        .data_mut()
+        .resources
+        .tcp_listeners
+        .remove(tcp_listener_id)
+        .or_trap("lunatic::networking::tcp_listener_into_wasi_fd")?;
+    Ok(caller
+        .data_mut()
+        .insert_wasi_socket(SocketKind::Listener(listener), FdFlags::empty()))
+}
+
 //% lunatic::networking::tcp_write_vectored(
 //%     stream_id: u64,
 //%     ciovec_array_ptr: u32,
@@ -1,24 +1,29 @@
 use std::{convert::TryInto, future::Future, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
+use semver::Version;
 use wasmtime::{Caller, FuncType, Linker, Trap, Val, ValType};
 
 use super::{
     get_memory, link_async1_if_match, link_async2_if_match, link_async4_if_match,
-    link_async6_if_match, link_async7_if_match, link_if_match,
+    link_async6_if_match, link_async7_if_match, link_async8_if_match, link_if_match,
 };
 use crate::{
     api::error::IntoTrap,
-    module::Module,
+    capability::Capabilities,
+    message::Message,
+    module::{Module, SpawnOptions},
     process::{Signal, WasmProcess},
-    state::ProcessState,
+    snapshot::Snapshot,
+    state::{CodeChange, ProcessState, Suspend},
+    stdio::{WasiOutput, WasiStdin},
     EnvConfig, Environment,
 };
 
 // Register the process APIs to the linker
 pub(crate) fn register(
     linker: &mut Linker<ProcessState>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
     link_if_match(
         linker,
@@ -26,7 +31,7 @@ pub(crate) fn register(
         "create_config",
         FuncType::new([ValType::I64, ValType::I64], [ValType::I64]),
         create_config,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -34,7 +39,7 @@ pub(crate) fn register(
         "drop_config",
         FuncType::new([ValType::I64], []),
         drop_config,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -42,7 +47,32 @@ pub(crate) fn register(
         "allow_namespace",
         FuncType::new([ValType::I64, ValType::I32, ValType::I32], []),
         allow_namespace,
-        namespace_filter,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "deny_namespace",
+        FuncType::new([ValType::I64, ValType::I32, ValType::I32], []),
+        deny_namespace,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "set_resource_limits",
+        FuncType::new(
+            [
+                ValType::I64,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+            ],
+            [],
+        ),
+        set_resource_limits,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -50,7 +80,7 @@ pub(crate) fn register(
         "create_environment",
         FuncType::new([ValType::I64, ValType::I32], [ValType::I32]),
         create_environment,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -58,7 +88,7 @@ pub(crate) fn register(
         "drop_environment",
         FuncType::new([ValType::I64], []),
         drop_environment,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -69,7 +99,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         add_plugin,
-        namespace_filter,
+        capabilities,
     )?;
     link_async4_if_match(
         linker,
@@ -80,7 +110,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         add_module,
-        namespace_filter,
+        capabilities,
     )?;
     link_async2_if_match(
         linker,
@@ -88,7 +118,7 @@ pub(crate) fn register(
         "add_this_module",
         FuncType::new([ValType::I64, ValType::I32], [ValType::I32]),
         add_this_module,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -96,14 +126,15 @@ pub(crate) fn register(
         "drop_module",
         FuncType::new([ValType::I64], []),
         drop_module,
-        namespace_filter,
+        capabilities,
     )?;
-    link_async7_if_match(
+    link_async8_if_match(
         linker,
         "lunatic::process",
         "spawn",
         FuncType::new(
             [
+                ValType::I64,
                 ValType::I64,
                 ValType::I64,
                 ValType::I32,
@@ -115,7 +146,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         spawn,
-        namespace_filter,
+        capabilities,
     )?;
     link_async6_if_match(
         linker,
@@ -133,7 +164,150 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         inherit_spawn,
-        namespace_filter,
+        capabilities,
+    )?;
+    link_async6_if_match(
+        linker,
+        "lunatic::process",
+        "fork",
+        FuncType::new(
+            [
+                ValType::I64,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+            ],
+            [ValType::I32],
+        ),
+        fork,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "create_spawn_options",
+        FuncType::new([], [ValType::I64]),
+        create_spawn_options,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "drop_spawn_options",
+        FuncType::new([ValType::I64], []),
+        drop_spawn_options,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_set_message",
+        FuncType::new([ValType::I64], []),
+        spawn_options_set_message,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_set_link",
+        FuncType::new([ValType::I64, ValType::I64], []),
+        spawn_options_set_link,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_set_monitor",
+        FuncType::new([ValType::I64, ValType::I64], []),
+        spawn_options_set_monitor,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_register",
+        FuncType::new(
+            [
+                ValType::I64,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+            ],
+            [ValType::I32],
+        ),
+        spawn_options_register,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_add_arg",
+        FuncType::new([ValType::I64, ValType::I32, ValType::I32], []),
+        spawn_options_add_arg,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_add_env",
+        FuncType::new(
+            [
+                ValType::I64,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+            ],
+            [],
+        ),
+        spawn_options_add_env,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_set_stdin",
+        FuncType::new([ValType::I64, ValType::I32, ValType::I32], []),
+        spawn_options_set_stdin,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_set_stdout",
+        FuncType::new([ValType::I64, ValType::I64, ValType::I64], []),
+        spawn_options_set_stdout,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_options_set_stderr",
+        FuncType::new([ValType::I64, ValType::I64, ValType::I64], []),
+        spawn_options_set_stderr,
+        capabilities,
+    )?;
+    link_async7_if_match(
+        linker,
+        "lunatic::process",
+        "spawn_with",
+        FuncType::new(
+            [
+                ValType::I64,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I32,
+                ValType::I64,
+                ValType::I32,
+            ],
+            [ValType::I32],
+        ),
+        spawn_with,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -141,7 +315,7 @@ pub(crate) fn register(
         "drop_process",
         FuncType::new([ValType::I64], []),
         drop_process,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -149,7 +323,15 @@ pub(crate) fn register(
         "clone_process",
         FuncType::new([ValType::I64], [ValType::I64]),
         clone_process,
-        namespace_filter,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "fuel_used",
+        FuncType::new([], [ValType::I64]),
+        fuel_used,
+        capabilities,
     )?;
     link_async1_if_match(
         linker,
@@ -157,7 +339,7 @@ pub(crate) fn register(
         "sleep_ms",
         FuncType::new([ValType::I64], []),
         sleep_ms,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -165,7 +347,7 @@ pub(crate) fn register(
         "die_when_link_dies",
         FuncType::new([ValType::I32], []),
         die_when_link_dies,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -173,7 +355,7 @@ pub(crate) fn register(
         "this",
         FuncType::new([], [ValType::I64]),
         this,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -181,7 +363,7 @@ pub(crate) fn register(
         "id",
         FuncType::new([ValType::I64, ValType::I32], []),
         id,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -189,7 +371,7 @@ pub(crate) fn register(
         "this_env",
         FuncType::new([], [ValType::I64]),
         this_env,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -197,7 +379,7 @@ pub(crate) fn register(
         "link",
         FuncType::new([ValType::I64, ValType::I64], []),
         link,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -205,7 +387,34 @@ pub(crate) fn register(
         "unlink",
         FuncType::new([ValType::I64], []),
         unlink,
-        namespace_filter,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "upgrade",
+        FuncType::new(
+            [ValType::I64, ValType::I64, ValType::I32, ValType::I32],
+            [ValType::I32],
+        ),
+        upgrade,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "code_change",
+        FuncType::new([ValType::I32, ValType::I32], [ValType::I32]),
+        code_change,
+        capabilities,
+    )?;
+    link_if_match(
+        linker,
+        "lunatic::process",
+        "suspend",
+        FuncType::new([], [ValType::I32]),
+        suspend,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -223,7 +432,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         register_proc,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -240,7 +449,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         unregister,
-        namespace_filter,
+        capabilities,
     )?;
     link_if_match(
         linker,
@@ -257,7 +466,7 @@ pub(crate) fn register(
             [ValType::I32],
         ),
         lookup,
-        namespace_filter,
+        capabilities,
     )?;
     Ok(())
 }
@@ -265,16 +474,33 @@ pub(crate) fn register(
 //% lunatic::process::create_config(max_memory: u64, max_fuel: u64) -> u64
 //%
 //% * **max_memory** - Maximum amount of memory in bytes.
-//% * **max_fuel**   - Maximum amount of instructions in gallons that processes will be able to run
-//%                    before it traps. 1 gallon ~= 10k instructions. The special value of `0` means
-//%                    unlimited instructions.
+//% * **max_fuel**   - Maximum amount of instructions in units of 100k that processes will be able
+//%                    to run before they are terminated. The special value of `0` means the same
+//%                    limit as the environment of the calling process.
 //% * Returns ID of newly created configuration.
 //%
 //% Create a new configuration for an environment.
-fn create_config(mut caller: Caller<ProcessState>, max_memory: u64, max_fuel: u64) -> u64 {
+//%
+//% Processes running out of fuel trap and their links are notified with the "out of fuel" exit
+//% reason (see `lunatic::message::get_exit_reason`). `lunatic::process::fuel_used` can be used to
+//% check how much fuel a process used.
+//%
+//% The configuration is bounded by the configuration of the calling process' environment. It can't
+//% allow more memory, fuel or host functions than the caller has access to and it will always
+//% include the caller's plugins.
+//%
+//% Traps:
+//% * If **max_memory** or **max_fuel** exceed the limits of the caller's environment.
+fn create_config(
+    mut caller: Caller<ProcessState>,
+    max_memory: u64,
+    max_fuel: u64,
+) -> Result<u64, Trap> {
     let max_fuel = if max_fuel != 0 { Some(max_fuel) } else { None };
-    let config = EnvConfig::new(max_memory as usize, max_fuel);
-    caller.data_mut().resources.configs.add(config)
+    let parent = caller.data().module.environment().config();
+    let config = EnvConfig::new_bounded(parent, max_memory as usize, max_fuel)
+        .or_trap("lunatic::process::create_config")?;
+    Ok(caller.data_mut().resources.configs.add(config))
 }
 
 //% lunatic::process::drop_config(config_id: u64)
@@ -295,11 +521,14 @@ fn drop_config(mut caller: Caller<ProcessState>, config_id: u64) -> Result<(), T
 
 //% lunatic::process::allow_namespace(config_id: u64, namespace_str_ptr: u32, namespace_str_len: u32)
 //%
-//% Allow using host functions under this namespace with this configuration. Namespaces are strings,
-//% e.g. `lunatic::` or `lunatic::process::`.
+//% Allow using host functions matching this rule with this configuration. Rules can match a single
+//% function, e.g. `lunatic::process::spawn`, or a whole namespace, e.g. `lunatic::process::*`. The
+//% trailing `::` form (`lunatic::process::`) is equivalent to `lunatic::process::*`.
 //%
 //% Traps:
+//% * If the config ID doesn't exist.
 //% * If the namespace string is not a valid utf8 string.
+//% * If the namespace string is not a valid rule.
 //% * If **namespace_str_ptr + namespace_str_len** is outside the memory.
 fn allow_namespace(
     mut caller: Caller<ProcessState>,
@@ -320,7 +549,95 @@ fn allow_namespace(
         .configs
         .get_mut(config_id)
         .or_trap("lunatic::process::allow_namespace")?;
-    config.allow_namespace(namespace);
+    config
+        .allow_namespace(namespace)
+        .or_trap("lunatic::process::allow_namespace")?;
+    Ok(())
+}
+
+//% lunatic::process::deny_namespace(config_id: u64, namespace_str_ptr: u32, namespace_str_len: u32)
+//%
+//% Deny using host functions matching this rule with this configuration, even if they are allowed
+//% by an `allow_namespace` rule. Rules follow the same format as in `allow_namespace`.
+//%
+//% Traps:
+//% * If the config ID doesn't exist.
+//% * If the namespace string is not a valid utf8 string.
+//% * If the namespace string is not a valid rule.
+//% * If **namespace_str_ptr + namespace_str_len** is outside the memory.
+fn deny_namespace(
+    mut caller: Caller<ProcessState>,
+    config_id: u64,
+    namespace_str_ptr: u32,
+    namespace_str_len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let mut buffer = vec![0; namespace_str_len as usize];
+    memory
+        .read(&caller, namespace_str_ptr as usize, &mut buffer)
+        .or_trap("lunatic::process::deny_namespace")?;
+    let namespace =
+        std::str::from_utf8(buffer.as_slice()).or_trap("lunatic::process::deny_namespace")?;
+    let config = caller
+        .data_mut()
+        .resources
+        .configs
+        .get_mut(config_id)
+        .or_trap("lunatic::process::deny_namespace")?;
+    config
+        .deny_namespace(namespace)
+        .or_trap("lunatic::process::deny_namespace")?;
+    Ok(())
+}
+
+//% lunatic::process::set_resource_limits(
+//%     config_id: u64,
+//%     max_table_elements: u32,
+//%     max_instances: u32,
+//%     max_tables: u32,
+//%     max_memories: u32
+//% )
+//%
+//% Set the limits of Wasm instances belonging to processes spawned with this configuration.
+//%
+//% * **max_table_elements** - Maximum number of elements a single table can grow to (inclusive).
+//% * **max_instances**      - Maximum number of instances.
+//% * **max_tables**         - Maximum number of tables.
+//% * **max_memories**       - Maximum number of memories. The **max_memory** limit of the config
+//%                            applies to all memories together.
+//%
+//% Traps:
+//% * If the config ID doesn't exist.
+//% * If any of the limits exceeds the limit of the caller's environment.
+fn set_resource_limits(
+    mut caller: Caller<ProcessState>,
+    config_id: u64,
+    max_table_elements: u32,
+    max_instances: u32,
+    max_tables: u32,
+    max_memories: u32,
+) -> Result<(), Trap> {
+    let parent = caller.data().module.environment().config();
+    if max_table_elements > parent.max_table_elements()
+        || max_instances as usize > parent.max_instances()
+        || max_tables as usize > parent.max_tables()
+        || max_memories as usize > parent.max_memories()
+    {
+        return Err(anyhow!(
+            "Resource limits exceed the limits of the caller's environment"
+        ))
+        .or_trap("lunatic::process::set_resource_limits");
+    }
+    let config = caller
+        .data_mut()
+        .resources
+        .configs
+        .get_mut(config_id)
+        .or_trap("lunatic::process::set_resource_limits")?;
+    config.set_max_table_elements(max_table_elements);
+    config.set_max_instances(max_instances as usize);
+    config.set_max_tables(max_tables as usize);
+    config.set_max_memories(max_memories as usize);
     Ok(())
 }
 
@@ -398,7 +715,8 @@ fn create_environment(
         .or_trap("lunatic::process::create_environment")?
         .clone();
 
-    let (env_or_error_id, result) = match Environment::new(config) {
+    let parent = caller.data().module.environment();
+    let (env_or_error_id, result) = match Environment::new_child(config, parent) {
         Ok(env) => (caller.data_mut().resources.environments.add(env), 0),
         Err(error) => (caller.data_mut().errors.add(error), 1),
     };
@@ -532,6 +850,7 @@ fn drop_module(mut caller: Caller<ProcessState>, mod_id: u64) -> Result<(), Trap
 
 //% lunatic::process::spawn(
 //%     link: i64,
+//%     deadline_ms: u64,
 //%     module_id: u64,
 //%     func_str_ptr: u32,
 //%     func_str_len: u32,
@@ -548,19 +867,26 @@ fn drop_module(mut caller: Caller<ProcessState>, mod_id: u64) -> Result<(), Trap
 //% If **link** is not 0, it will link the child and parent processes. The value
 //% of the **link** argument will be used as the link-tag for the child.
 //%
+//% If **deadline_ms** is not 0, the process is killed if it's still running after
+//% **deadline_ms** milliseconds. The deadline can't extend the environment's maximum runtime.
 //%
 //% The function arguments are passed as an array with the following structure:
 //% [0 byte = type ID; 1..17 bytes = value as u128, ...]
 //% The type ID follows the WebAssembly binary convention:
 //%  - 0x7F => i32
 //%  - 0x7E => i64
+//%  - 0x7D => f32 (bits of the float)
+//%  - 0x7C => f64 (bits of the float)
 //%  - 0x7B => v128
-//% If any other value is used as type ID, this function will trap.
+//%  - 0x70 => funcref
+//%  - 0x6F => externref
+//% References can't be shared between processes, so only null references (value 0) can be passed.
+//% If **params_len** is not a multiple of 17, a type ID is unknown or a reference is not null,
+//% the process is not spawned and an error is returned.
 //%
 //% Traps:
 //% * If the module ID doesn't exist.
 //% * If the function string is not a valid utf8 string.
-//% * If the params array is in a wrong format.
 //% * If **func_str_ptr + func_str_len** is outside the memory.
 //% * If **params_ptr + params_len** is outside the memory.
 //% * If **id_ptr** is outside the memory.
@@ -568,6 +894,7 @@ fn drop_module(mut caller: Caller<ProcessState>, mod_id: u64) -> Result<(), Trap
 fn spawn(
     mut caller: Caller<ProcessState>,
     link: i64,
+    deadline_ms: u64,
     module_id: u64,
     func_str_ptr: u32,
     func_str_len: u32,
@@ -583,9 +910,19 @@ fn spawn(
             .get(module_id)
             .or_trap("lunatic::process::spawn")?
             .clone();
+        let deadline = match deadline_ms {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        };
+        let options = SpawnOptions {
+            link: link_to_caller(&caller, link),
+            deadline,
+            ..SpawnOptions::default()
+        };
         spawn_from_module(
             &mut caller,
-            link,
+            options,
+            false,
             module,
             func_str_ptr,
             func_str_len,
@@ -619,12 +956,17 @@ fn spawn(
 //% The type ID follows the WebAssembly binary convention:
 //%  - 0x7F => i32
 //%  - 0x7E => i64
+//%  - 0x7D => f32 (bits of the float)
+//%  - 0x7C => f64 (bits of the float)
 //%  - 0x7B => v128
-//% If any other value is used as type ID, this function will trap.
+//%  - 0x70 => funcref
+//%  - 0x6F => externref
+//% References can't be shared between processes, so only null references (value 0) can be passed.
+//% If **params_len** is not a multiple of 17, a type ID is unknown or a reference is not null,
+//% the process is not spawned and an error is returned.
 //%
 //% Traps:
 //% * If the function string is not a valid utf8 string.
-//% * If the params array is in a wrong format.
 //% * If **func_str_ptr + func_str_len** is outside the memory.
 //% * If **params_ptr + params_len** is outside the memory.
 //% * If **id_ptr** is outside the memory.
@@ -639,9 +981,14 @@ fn inherit_spawn(
 ) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
     Box::new(async move {
         let module = caller.data().module.clone();
+        let options = SpawnOptions {
+            link: link_to_caller(&caller, link),
+            ..SpawnOptions::default()
+        };
         spawn_from_module(
             &mut caller,
-            link,
+            options,
+            false,
             module,
             func_str_ptr,
             func_str_len,
@@ -653,67 +1000,589 @@ fn inherit_spawn(
     })
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn spawn_from_module(
-    mut caller: &mut Caller<'_, ProcessState>,
+//% lunatic::process::fork(
+//%     link: i64,
+//%     func_str_ptr: u32,
+//%     func_str_len: u32,
+//%     params_ptr: u32,
+//%     params_len: u32,
+//%     id_ptr: u32
+//% ) -> u32
+//%
+//% Returns:
+//% * 0 on success - The ID of the newly created process is written to **id_ptr**
+//% * 1 on error   - The error ID is written to **id_ptr**
+//%
+//% Spawns a new process using the same module as the parent, that starts with a copy of the
+//% parent's exported memories and exported mutable globals instead of the module's initial state.
+//% This allows children to skip expensive initialization that the parent already did. The memory
+//% is copied when the child is spawned, later writes of either process are not visible to the
+//% other one. Fails if the module has memories or mutable globals that are not exported.
+//%
+//% If **link** is not 0, it will link the child and parent processes. The value of the **link**
+//% argument will be used as the link-tag for the child. The params array has the same format as in
+//% `lunatic::process::inherit_spawn`.
+//%
+//% Traps:
+//% * If the function string is not a valid utf8 string.
+//% * If **func_str_ptr + func_str_len** is outside the memory.
+//% * If **params_ptr + params_len** is outside the memory.
+//% * If **id_ptr** is outside the memory.
+fn fork(
+    mut caller: Caller<ProcessState>,
     link: i64,
-    module: Module,
     func_str_ptr: u32,
     func_str_len: u32,
     params_ptr: u32,
     params_len: u32,
     id_ptr: u32,
-) -> Result<u32, Trap> {
-    let memory = get_memory(&mut caller)?;
-    let func_str = memory
-        .data(&caller)
-        .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
-        .or_trap("lunatic::process::(inherit_)spawn")?;
-    let function = std::str::from_utf8(func_str).or_trap("lunatic::process::(inherit_)spawn")?;
-    let params = memory
-        .data(&caller)
-        .get(params_ptr as usize..(params_ptr + params_len) as usize)
-        .or_trap("lunatic::process::(inherit_)spawn")?;
-    let params = params
-        .chunks_exact(17)
-        .map(|chunk| {
-            let value = u128::from_le_bytes(chunk[1..].try_into()?);
-            let result = match chunk[0] {
-                0x7F => Val::I32(value as i32),
-                0x7E => Val::I64(value as i64),
-                0x7B => Val::V128(value),
-                _ => return Err(anyhow!("Unsupported type ID")),
-            };
-            Ok(result)
-        })
-        .collect::<Result<Vec<_>>>()?;
-    // Should processes be linked together?
-    let link = match link {
-        0 => None,
-        tag => {
-            let id = caller.data().id;
-            let signal_mailbox = caller.data().signal_mailbox.clone();
-            let process = WasmProcess::new(id, signal_mailbox);
-            Some((Some(tag), process))
-        }
-    };
-    let (proc_or_error_id, result) = match module.spawn(function, params, link).await {
-        Ok((_, process)) => (
-            caller.data_mut().resources.processes.add(Arc::new(process)),
-            0,
-        ),
-        Err(error) => (caller.data_mut().errors.add(error), 1),
-    };
-    memory
-        .write(
-            &mut caller,
-            id_ptr as usize,
-            &proc_or_error_id.to_le_bytes(),
-        )
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let module = caller.data().module.clone();
+        let options = SpawnOptions {
+            link: link_to_caller(&caller, link),
+            ..SpawnOptions::default()
+        };
+        spawn_from_module(
+            &mut caller,
+            options,
+            true,
+            module,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+            id_ptr,
+        )
+        .await
+    })
+}
+
+//% lunatic::process::create_spawn_options() -> u64
+//%
+//% Creates a new set of options for `lunatic::process::spawn_with` and returns its ID. Without
+//% any changes, processes are spawned the same way as with `lunatic::process::spawn`.
+fn create_spawn_options(mut caller: Caller<ProcessState>) -> u64 {
+    caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .add(SpawnOptions::default())
+}
+
+//% lunatic::process::drop_spawn_options(options_id: u64)
+//%
+//% Drops the spawn options resource.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+fn drop_spawn_options(mut caller: Caller<ProcessState>, options_id: u64) -> Result<(), Trap> {
+    caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .remove(options_id)
+        .or_trap("lunatic::process::drop_spawn_options")?;
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_set_message(options_id: u64)
+//%
+//% Moves the message from the scratch area (see `lunatic::message::create_data`) into the spawn
+//% options. The message is put into the mailbox of the new process before it starts running.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If there is no message in the scratch area.
+fn spawn_options_set_message(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+) -> Result<(), Trap> {
+    let message = caller
+        .data_mut()
+        .message
+        .take()
+        .or_trap("lunatic::process::spawn_options_set_message")?;
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_set_message")?;
+    options.message = Some(message);
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_set_link(options_id: u64, tag: i64)
+//%
+//% Links the new process to the calling process. The value of **tag** will be used as the
+//% link-tag for the child, 0 means no tag.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+fn spawn_options_set_link(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    tag: i64,
+) -> Result<(), Trap> {
+    let id = caller.data().id;
+    let signal_mailbox = caller.data().signal_mailbox.clone();
+    let this_process = WasmProcess::new(id, signal_mailbox);
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_set_link")?;
+    let tag = match tag {
+        0 => None,
+        tag => Some(tag),
+    };
+    options.link = Some((tag, this_process));
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_set_monitor(options_id: u64, tag: i64)
+//%
+//% Makes the calling process monitor the new process. Once the new process finishes, normally or
+//% not, the calling process receives a signal message with **tag** (0 means no tag) and the exit
+//% reason (see `lunatic::message::get_exit_reason`). Unlike links, a failing child never kills the
+//% monitoring process.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+fn spawn_options_set_monitor(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    tag: i64,
+) -> Result<(), Trap> {
+    let id = caller.data().id;
+    let signal_mailbox = caller.data().signal_mailbox.clone();
+    let this_process = WasmProcess::new(id, signal_mailbox);
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_set_monitor")?;
+    let tag = match tag {
+        0 => None,
+        tag => Some(tag),
+    };
+    options.monitor = Some((tag, Arc::new(this_process)));
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_register(
+//%     options_id: u64,
+//%     name_ptr: u32,
+//%     name_len: u32,
+//%     version_ptr: u32,
+//%     version_len: u32
+//% ) -> u32
+//%
+//% Returns 0 in case of success or 1 if the version string didn't have a correct semver format.
+//%
+//% Registers the new process under **name** and **version** in the registry of the environment
+//% it's spawned into, before it starts running (see `lunatic::process::register`).
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If the name or version string is not a valid utf8 string.
+//% * If **name_ptr + name_len** is outside the memory.
+//% * If **version_ptr + version_len** is outside the memory.
+fn spawn_options_register(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    name_ptr: u32,
+    name_len: u32,
+    version_ptr: u32,
+    version_len: u32,
+) -> Result<u32, Trap> {
+    let memory = get_memory(&mut caller)?;
+    let buffer = memory
+        .data(&caller)
+        .get(name_ptr as usize..(name_ptr as usize + name_len as usize))
+        .or_trap("lunatic::process::spawn_options_register")?;
+    let name = std::str::from_utf8(buffer)
+        .or_trap("lunatic::process::spawn_options_register")?
+        .to_string();
+    let buffer = memory
+        .data(&caller)
+        .get(version_ptr as usize..(version_ptr as usize + version_len as usize))
+        .or_trap("lunatic::process::spawn_options_register")?;
+    let version = std::str::from_utf8(buffer)
+        .or_trap("lunatic::process::spawn_options_register")?
+        .to_string();
+    if Version::parse(&version).is_err() {
+        return Ok(1);
+    }
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_register")?;
+    options.register = Some((name, version));
+    Ok(0)
+}
+
+//% lunatic::process::spawn_options_add_arg(options_id: u64, arg_ptr: u32, arg_len: u32)
+//%
+//% Adds a WASI command line argument for the new process. Once an argument is added, the
+//% arguments of the environment are not used anymore.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If the argument is not a valid utf8 string.
+//% * If **arg_ptr + arg_len** is outside the memory.
+fn spawn_options_add_arg(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    arg_ptr: u32,
+    arg_len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let buffer = memory
+        .data(&caller)
+        .get(arg_ptr as usize..(arg_ptr as usize + arg_len as usize))
+        .or_trap("lunatic::process::spawn_options_add_arg")?;
+    let arg = std::str::from_utf8(buffer)
+        .or_trap("lunatic::process::spawn_options_add_arg")?
+        .to_string();
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_add_arg")?;
+    options.wasi_args.get_or_insert_with(Vec::new).push(arg);
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_add_env(
+//%     options_id: u64,
+//%     key_ptr: u32,
+//%     key_len: u32,
+//%     value_ptr: u32,
+//%     value_len: u32
+//% )
+//%
+//% Adds a WASI environment variable for the new process. Once a variable is added, the
+//% environment variables of the environment are not used anymore.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If the key or value is not a valid utf8 string.
+//% * If **key_ptr + key_len** is outside the memory.
+//% * If **value_ptr + value_len** is outside the memory.
+fn spawn_options_add_env(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let buffer = memory
+        .data(&caller)
+        .get(key_ptr as usize..(key_ptr as usize + key_len as usize))
+        .or_trap("lunatic::process::spawn_options_add_env")?;
+    let key = std::str::from_utf8(buffer)
+        .or_trap("lunatic::process::spawn_options_add_env")?
+        .to_string();
+    let buffer = memory
+        .data(&caller)
+        .get(value_ptr as usize..(value_ptr as usize + value_len as usize))
+        .or_trap("lunatic::process::spawn_options_add_env")?;
+    let value = std::str::from_utf8(buffer)
+        .or_trap("lunatic::process::spawn_options_add_env")?
+        .to_string();
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_add_env")?;
+    options
+        .wasi_envs
+        .get_or_insert_with(Vec::new)
+        .push((key, value));
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_set_stdin(options_id: u64, data_ptr: u32, data_len: u32)
+//%
+//% The new process reads **data** from its WASI standard input, instead of the standard input of
+//% the environment.
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If **data_ptr + data_len** is outside the memory.
+fn spawn_options_set_stdin(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    data_ptr: u32,
+    data_len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let data = memory
+        .data(&caller)
+        .get(data_ptr as usize..(data_ptr as usize + data_len as usize))
+        .or_trap("lunatic::process::spawn_options_set_stdin")?
+        .to_vec();
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_set_stdin")?;
+    options.wasi_stdin = Some(WasiStdin::Bytes(data));
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_set_stdout(options_id: u64, process_id: u64, tag: i64)
+//%
+//% Redirects the WASI standard output of the new process to the process **process_id**. Each
+//% write is sent as a data message with **tag** (0 means no tag).
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If the process ID doesn't exist.
+fn spawn_options_set_stdout(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    process_id: u64,
+    tag: i64,
+) -> Result<(), Trap> {
+    let output = output_to_process(&caller, process_id, tag)
+        .or_trap("lunatic::process::spawn_options_set_stdout")?;
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_set_stdout")?;
+    options.wasi_stdout = Some(output);
+    Ok(())
+}
+
+//% lunatic::process::spawn_options_set_stderr(options_id: u64, process_id: u64, tag: i64)
+//%
+//% Redirects the WASI standard error of the new process to the process **process_id**. Each
+//% write is sent as a data message with **tag** (0 means no tag).
+//%
+//% Traps:
+//% * If the spawn options ID doesn't exist.
+//% * If the process ID doesn't exist.
+fn spawn_options_set_stderr(
+    mut caller: Caller<ProcessState>,
+    options_id: u64,
+    process_id: u64,
+    tag: i64,
+) -> Result<(), Trap> {
+    let output = output_to_process(&caller, process_id, tag)
+        .or_trap("lunatic::process::spawn_options_set_stderr")?;
+    let options = caller
+        .data_mut()
+        .resources
+        .spawn_options
+        .get_mut(options_id)
+        .or_trap("lunatic::process::spawn_options_set_stderr")?;
+    options.wasi_stderr = Some(output);
+    Ok(())
+}
+
+fn output_to_process(
+    caller: &Caller<ProcessState>,
+    process_id: u64,
+    tag: i64,
+) -> Option<WasiOutput> {
+    let process = caller.data().resources.processes.get(process_id)?.clone();
+    let tag = match tag {
+        0 => None,
+        tag => Some(tag),
+    };
+    Some(WasiOutput::Process(process, tag))
+}
+
+//% lunatic::process::spawn_with(
+//%     module_id: u64,
+//%     func_str_ptr: u32,
+//%     func_str_len: u32,
+//%     params_ptr: u32,
+//%     params_len: u32,
+//%     options_id: u64,
+//%     id_ptr: u32
+//% ) -> u32
+//%
+//% Returns:
+//% * 0 on success - The ID of the newly created process is written to **id_ptr**
+//% * 1 on error   - The error ID is written to **id_ptr**
+//%
+//% Spawns a new process like `lunatic::process::spawn`, configured with the spawn options
+//% **options_id** (see `lunatic::process::create_spawn_options`). The initial message, links,
+//% monitors and registry entry are all set up before the new process starts running. The spawn
+//% options resource is consumed by this call. The params array has the same format as in
+//% `lunatic::process::spawn`.
+//%
+//% Traps:
+//% * If the module ID doesn't exist.
+//% * If the spawn options ID doesn't exist.
+//% * If the function string is not a valid utf8 string.
+//% * If **func_str_ptr + func_str_len** is outside the memory.
+//% * If **params_ptr + params_len** is outside the memory.
+//% * If **id_ptr** is outside the memory.
+#[allow(clippy::too_many_arguments)]
+fn spawn_with(
+    mut caller: Caller<ProcessState>,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    options_id: u64,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let module = caller
+            .data()
+            .resources
+            .modules
+            .get(module_id)
+            .or_trap("lunatic::process::spawn_with")?
+            .clone();
+        let options = caller
+            .data_mut()
+            .resources
+            .spawn_options
+            .remove(options_id)
+            .or_trap("lunatic::process::spawn_with")?;
+        spawn_from_module(
+            &mut caller,
+            options,
+            false,
+            module,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+            id_ptr,
+        )
+        .await
+    })
+}
+
+// Links a child to the calling process if **link** is not 0, it's used as the child's link-tag.
+fn link_to_caller(caller: &Caller<ProcessState>, link: i64) -> Option<(Option<i64>, WasmProcess)> {
+    match link {
+        0 => None,
+        tag => {
+            let id = caller.data().id;
+            let signal_mailbox = caller.data().signal_mailbox.clone();
+            let process = WasmProcess::new(id, signal_mailbox);
+            Some((Some(tag), process))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn spawn_from_module(
+    mut caller: &mut Caller<'_, ProcessState>,
+    options: SpawnOptions,
+    fork: bool,
+    module: Module,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Result<u32, Trap> {
+    let memory = get_memory(&mut caller)?;
+    let func_str = memory
+        .data(&caller)
+        .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
+        .or_trap("lunatic::process::(inherit_)spawn")?;
+    let function = std::str::from_utf8(func_str)
+        .or_trap("lunatic::process::(inherit_)spawn")?
+        .to_string();
+    let params = memory
+        .data(&caller)
+        .get(params_ptr as usize..(params_ptr + params_len) as usize)
+        .or_trap("lunatic::process::(inherit_)spawn")?;
+    let params = decode_params(params);
+    let spawned = match params {
+        // The child continues from the current memories and globals of the parent
+        Ok(params) if fork => match Snapshot::capture_caller(caller) {
+            Ok(snapshot) => {
+                module
+                    .fork(&snapshot, &function, params, options.link)
+                    .await
+            }
+            Err(error) => Err(error),
+        },
+        Ok(params) => module.spawn_with(&function, params, options).await,
+        Err(error) => Err(error),
+    };
+    let (proc_or_error_id, result) = match spawned {
+        Ok((_, process)) => (
+            caller.data_mut().resources.processes.add(Arc::new(process)),
+            0,
+        ),
+        Err(error) => (caller.data_mut().errors.add(error), 1),
+    };
+    memory
+        .write(
+            &mut caller,
+            id_ptr as usize,
+            &proc_or_error_id.to_le_bytes(),
+        )
         .or_trap("lunatic::process::(inherit_)spawn")?;
     Ok(result)
 }
 
+// Decodes the params array of `spawn`, `inherit_spawn` and `fork`.
+fn decode_params(params: &[u8]) -> Result<Vec<Val>> {
+    if params.len() % 17 != 0 {
+        return Err(anyhow!(
+            "Params array length ({}) is not a multiple of 17 bytes",
+            params.len()
+        ));
+    }
+    params
+        .chunks_exact(17)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let value = u128::from_le_bytes(chunk[1..].try_into()?);
+            let result = match chunk[0] {
+                0x7F => Val::I32(value as i32),
+                0x7E => Val::I64(value as i64),
+                0x7D => Val::F32(value as u32),
+                0x7C => Val::F64(value as u64),
+                0x7B => Val::V128(value),
+                0x70 | 0x6F if value != 0 => {
+                    return Err(anyhow!(
+                        "Param {} is a reference that is not null, only null references can be \
+                         passed to a new process",
+                        index
+                    ))
+                }
+                0x70 => Val::FuncRef(None),
+                0x6F => Val::ExternRef(None),
+                type_id => {
+                    return Err(anyhow!(
+                        "Param {} has an unsupported type ID: {:#x}",
+                        index,
+                        type_id
+                    ))
+                }
+            };
+            Ok(result)
+        })
+        .collect()
+}
+
 //% lunatic::process::drop_process(process_id: u64)
 //%
 //% Drops the process handle. This will not kill the process, it just removes the handle that
@@ -749,12 +1618,25 @@ fn clone_process(mut caller: Caller<ProcessState>, process_id: u64) -> Result<u6
     Ok(id)
 }
 
+//% lunatic::process::fuel_used() -> u64
+//%
+//% Returns the amount of fuel consumed by the current process. Most WebAssembly instructions
+//% consume 1 unit of fuel, so this roughly corresponds to the number of executed instructions.
+//% The process is terminated once it consumes **max_fuel** * 100k units.
+fn fuel_used(caller: Caller<ProcessState>) -> u64 {
+    // Fuel consumption is always enabled in the engine configuration
+    caller.fuel_consumed().unwrap_or(0)
+}
+
 //% lunatic::process::sleep_ms(millis: u64)
 //%
 //% Suspend process for `millis`.
-fn sleep_ms(_: Caller<ProcessState>, millis: u64) -> Box<dyn Future<Output = ()> + Send + '_> {
+//%
+//% In deterministic environments the virtual time is advanced by `millis` without waiting.
+fn sleep_ms(caller: Caller<ProcessState>, millis: u64) -> Box<dyn Future<Output = ()> + Send + '_> {
     Box::new(async move {
-        async_std::task::sleep(Duration::from_millis(millis)).await;
+        let clock = caller.data().module.environment().clock().clone();
+        clock.sleep(Duration::from_millis(millis)).await;
     })
 }
 
@@ -883,6 +1765,150 @@ fn unlink(mut caller: Caller<ProcessState>, process_id: u64) -> Result<(), Trap>
     Ok(())
 }
 
+//% lunatic::process::upgrade(
+//%     process_id: u64,
+//%     mod_id: u64,
+//%     version_ptr: u32,
+//%     version_len: u32
+//% ) -> u32
+//%
+//% Returns:
+//% * 0 if the upgrade was requested
+//% * 1 if version string is not a correct semver string
+//%
+//% Asks the process **process_id** to continue running the module **mod_id**. The process receives
+//% an upgrade message (see `lunatic::message::receive`) and decides itself when it's safe to
+//% upgrade by calling `lunatic::process::code_change`.
+//%
+//% If **version_len** is not 0, all registry entries of the process in the registry of its
+//% environment are moved to the version at **version_ptr** once the upgrade succeeds, so that
+//% other processes can find it under the new version.
+//%
+//% Traps:
+//% * If the process ID doesn't exist.
+//% * If the module ID doesn't exist.
+//% * If the version string is not valid UTF-8.
+//% * If any memory outside the guest heap space is referenced.
+fn upgrade(
+    mut caller: Caller<ProcessState>,
+    process_id: u64,
+    mod_id: u64,
+    version_ptr: u32,
+    version_len: u32,
+) -> Result<u32, Trap> {
+    let version = match version_len {
+        0 => None,
+        _ => {
+            let memory = get_memory(&mut caller)?;
+            let buffer = memory
+                .data(&caller)
+                .get(version_ptr as usize..(version_ptr as usize + version_len as usize))
+                .or_trap("lunatic::process::upgrade")?;
+            let version = std::str::from_utf8(buffer).or_trap("lunatic::process::upgrade")?;
+            if Version::parse(version).is_err() {
+                return Ok(1);
+            }
+            Some(String::from(version))
+        }
+    };
+    let module = caller
+        .data()
+        .resources
+        .modules
+        .get(mod_id)
+        .or_trap("lunatic::process::upgrade")?
+        .clone();
+    caller
+        .data()
+        .resources
+        .processes
+        .get(process_id)
+        .or_trap("lunatic::process::upgrade")?
+        .send(Signal::Upgrade(module, version));
+    Ok(0)
+}
+
+//% lunatic::process::code_change(state_ptr: u32, state_len: u32) -> u32
+//%
+//% Returns:
+//% * 1 if the new module belongs to a different environment and the process can't be upgraded.
+//%
+//% Upgrades the process to the module of the last received upgrade message, similar to Erlang's
+//% hot code upgrade. Should only be called at a point where the process is safe to stop, e.g.
+//% between handling two messages.
+//%
+//% On success this function doesn't return. The current instance is stopped and the new module is
+//% instantiated in its place. Its exported function `lunatic_code_change` is called with the
+//% buffer at **state_ptr** as the current message, so that it can be read with
+//% `lunatic::message::read_data`. The process keeps its ID, mailbox, links and resources. If the
+//% upgrade message carries a version, the process is moved to it in the registry.
+//%
+//% Traps:
+//% * If the last received message is not an upgrade message.
+//% * If any memory outside the guest heap space is referenced.
+fn code_change(
+    mut caller: Caller<ProcessState>,
+    state_ptr: u32,
+    state_len: u32,
+) -> Result<u32, Trap> {
+    let (module, version) = match caller.data_mut().message.take() {
+        Some(Message::Upgrade(module, version)) => (module, version),
+        _ => return Err(Trap::new("No upgrade message in scratch area")),
+    };
+    if !module
+        .environment()
+        .same_as(caller.data().module.environment())
+    {
+        return Ok(1);
+    }
+    let memory = get_memory(&mut caller)?;
+    let state = memory
+        .data(&caller)
+        .get(state_ptr as usize..(state_ptr as usize + state_len as usize))
+        .or_trap("lunatic::process::code_change")?
+        .to_vec();
+    caller.data_mut().code_change = Some(CodeChange {
+        module,
+        state,
+        version,
+    });
+    // Stop the current instance, the process continues with the new module
+    Err(Trap::new("lunatic::process::code_change"))
+}
+
+//% lunatic::process::suspend() -> u32
+//%
+//% Returns:
+//% * 1 if messages carrying resources are waiting in the mailbox and the process can't be
+//%     suspended.
+//%
+//% Suspends the process after a suspend message was received, so that it can be restored later
+//% from a snapshot. Should only be called at a point where all state that needs to survive is
+//% kept in exported memories and exported globals, e.g. at the top of the receive loop.
+//%
+//% On success this function doesn't return. The process is stopped and a snapshot of it is sent to
+//% the requester. A restored process continues by calling the exported `lunatic_resume` function.
+//%
+//% Traps:
+//% * If the last received message is not a suspend message.
+fn suspend(mut caller: Caller<ProcessState>) -> Result<u32, Trap> {
+    let sender = match caller.data_mut().message.take() {
+        Some(Message::Suspend(sender)) => sender,
+        _ => return Err(Trap::new("No suspend message in scratch area")),
+    };
+    let messages = match caller
+        .data()
+        .message_mailbox
+        .take_all_if(Snapshot::can_capture)
+    {
+        Some(messages) => messages,
+        None => return Ok(1),
+    };
+    caller.data_mut().suspend = Some(Suspend { sender, messages });
+    // Stop the process, the snapshot is captured once the instance isn't running anymore
+    Err(Trap::new("lunatic::process::suspend"))
+}
+
 //% lunatic::process::register(
 //%     name_ptr: u32,
 //%     name_len: u32,
@@ -1051,3 +2077,144 @@ fn lookup(
         None => Ok(2),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_std::channel::unbounded;
+    use uuid::Uuid;
+
+    use crate::{EnvConfig, Environment, Signal, WasmProcess};
+
+    #[async_std::test]
+    async fn spawn_params() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.allow_namespace("lunatic::process::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (import "lunatic::process" "inherit_spawn" (func $spawn (param i64 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                ;; f32 1.5, f64 2.5 and a null externref
+                (data (i32.const 16)
+                    "\7d\00\00\c0\3f\00\00\00\00\00\00\00\00\00\00\00\00"
+                    "\7c\00\00\00\00\00\00\04\40\00\00\00\00\00\00\00\00"
+                    "\6f\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+                ;; Unknown type ID
+                (data (i32.const 80) "\01\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00")
+                (func (export "run")
+                    (if (i32.ne (call $spawn (i64.const 1) (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 51) (i32.const 120))
+                                (i32.const 0))
+                        (then unreachable))
+                    ;; Truncated params array
+                    (if (i32.ne (call $spawn (i64.const 1) (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 50) (i32.const 120))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (i32.ne (call $spawn (i64.const 1) (i32.const 0) (i32.const 5) (i32.const 80) (i32.const 17) (i32.const 120))
+                                (i32.const 1))
+                        (then unreachable))
+                    ;; Give the child time to finish, it kills the parent if it fails
+                    (drop (call $receive (i64.const 0) (i32.const 200))))
+                (func (export "child") (param f32 f64 externref)
+                    (if (f32.ne (local.get 0) (f32.const 1.5))
+                        (then unreachable))
+                    (if (f64.ne (local.get 1) (f64.const 2.5))
+                        (then unreachable))
+                    (if (i32.eqz (ref.is_null (local.get 2)))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("run", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        // Finished without failing
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[async_std::test]
+    async fn spawn_with_options() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.allow_namespace("lunatic::process::*").unwrap();
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (import "lunatic::message" "get_exit_reason" (func $get_exit_reason (result i32)))
+                (import "lunatic::process" "this_env" (func $this_env (result i64)))
+                (import "lunatic::process" "add_this_module" (func $add_this_module (param i64 i32) (result i32)))
+                (import "lunatic::process" "create_spawn_options" (func $create_spawn_options (result i64)))
+                (import "lunatic::process" "spawn_options_set_message" (func $set_message (param i64)))
+                (import "lunatic::process" "spawn_options_set_monitor" (func $set_monitor (param i64 i64)))
+                (import "lunatic::process" "spawn_options_register" (func $register (param i64 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "spawn_options_add_arg" (func $add_arg (param i64 i32 i32)))
+                (import "lunatic::process" "spawn_with" (func $spawn_with (param i64 i32 i32 i32 i32 i64 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (data (i32.const 8) "1.0.0")
+                (data (i32.const 16) "arg")
+                (data (i32.const 24) "bad")
+                (func (export "run")
+                    (local $options i64)
+                    (if (i32.ne (call $add_this_module (call $this_env) (i32.const 32)) (i32.const 0))
+                        (then unreachable))
+                    (local.set $options (call $create_spawn_options))
+                    (call $create_data (i64.const 5) (i64.const 0))
+                    (call $set_message (local.get $options))
+                    (call $set_monitor (local.get $options) (i64.const 9))
+                    (if (i32.ne (call $register (local.get $options) (i32.const 0) (i32.const 5) (i32.const 24) (i32.const 3))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (i32.ne (call $register (local.get $options) (i32.const 0) (i32.const 5) (i32.const 8) (i32.const 5))
+                                (i32.const 0))
+                        (then unreachable))
+                    (call $add_arg (local.get $options) (i32.const 16) (i32.const 3))
+                    (if (i32.ne (call $spawn_with (i64.load (i32.const 32)) (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (local.get $options) (i32.const 40))
+                                (i32.const 0))
+                        (then unreachable))
+                    ;; The monitored child returns normally once all checks passed
+                    (if (i32.ne (call $receive (i64.const 9) (i32.const 1000)) (i32.const 1))
+                        (then unreachable))
+                    (if (i32.ne (call $get_exit_reason) (i32.const 0))
+                        (then unreachable)))
+                (func (export "child")
+                    ;; Failing is reported to the monitoring parent, without killing it
+                    ;; The initial message is already in the mailbox
+                    (if (i32.ne (call $receive (i64.const 5) (i32.const 1)) (i32.const 0))
+                        (then unreachable))
+                    (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+                    (if (i32.ne (i32.load (i32.const 0)) (i32.const 1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("run", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        // Finished without failing
+        assert!(receiver.try_recv().is_err());
+        // The child was registered before it started
+        let child = environment.registry().get("child", "^1.0").unwrap();
+        assert!(child.is_some());
+    }
+}
@@ -1,13 +1,47 @@
+use std::{convert::TryInto, future::Future, net::Shutdown, pin::Pin, sync::Arc};
+
 use anyhow::Result;
-use wasmtime::{Linker, Trap};
+use async_std::io::{ReadExt, WriteExt};
+use lazy_static::lazy_static;
+use wasi_common::{
+    file::FdFlags,
+    snapshots::preview_1::{
+        types::{Errno, Riflags, Sdflags},
+        wasi_snapshot_preview1,
+    },
+};
+use wasmtime::{Caller, Engine, Extern, FuncType, Linker, Store, Trap, ValType};
+use wasmtime_wasi::{WasiCtx, WasiCtxBuilder};
+use wiggle::{wasmtime::WasmtimeGuestMemory, GuestMemory};
+
+use crate::{
+    api::get_memory,
+    capability::Capabilities,
+    state::ProcessState,
+    wasi_socket::{errno, SocketKind, WasiSocket},
+};
 
-use super::namespace_matches_filter;
-use crate::state::ProcessState;
+use super::{
+    link_async2_if_match, link_async3_if_match, link_async4_if_match, link_async5_if_match,
+    link_async6_if_match,
+};
+
+const NAMESPACE: &str = "wasi_snapshot_preview1";
+// Upper bound of bytes sent or received over a socket per call.
+const MAX_TRANSFER: usize = 64 * 1024;
+// Size of a `poll_oneoff` subscription in the guest memory.
+const SUBSCRIPTION_SIZE: usize = 48;
+
+lazy_static! {
+    // Names and types of all WASI functions, taken from a linker that only contains them.
+    static ref WASI_FUNCTIONS: Vec<(String, FuncType)> =
+        wasi_functions().expect("WASI functions must be inspectable");
+}
 
 // Register WASI APIs to the linker
 pub(crate) fn register(
     linker: &mut Linker<ProcessState>,
-    namespace_filter: &[String],
+    capabilities: &Capabilities,
 ) -> Result<()> {
     // Add all WASI functions at first
     wasmtime_wasi::sync::snapshots::preview_1::add_wasi_snapshot_preview1_to_linker(
@@ -15,313 +49,613 @@ pub(crate) fn register(
         |ctx| &mut ctx.wasi,
     )?;
 
+    // Replace the socket functions, that are not implemented by WASI, with ones working on the
+    // TCP sockets moved into the file descriptor table. Reads and writes of sockets are also
+    // handled here, so that they don't block the thread, as well as renumbering and polling them.
+    // See the `wasi_socket` module for details.
+    link_async4_if_match(
+        linker,
+        NAMESPACE,
+        "fd_read",
+        FuncType::new(vec![ValType::I32; 4], [ValType::I32]),
+        fd_read,
+        capabilities,
+    )?;
+    link_async4_if_match(
+        linker,
+        NAMESPACE,
+        "fd_write",
+        FuncType::new(vec![ValType::I32; 4], [ValType::I32]),
+        fd_write,
+        capabilities,
+    )?;
+    link_async2_if_match(
+        linker,
+        NAMESPACE,
+        "fd_renumber",
+        FuncType::new(vec![ValType::I32; 2], [ValType::I32]),
+        fd_renumber,
+        capabilities,
+    )?;
+    link_async4_if_match(
+        linker,
+        NAMESPACE,
+        "poll_oneoff",
+        FuncType::new(vec![ValType::I32; 4], [ValType::I32]),
+        poll_oneoff,
+        capabilities,
+    )?;
+    link_async6_if_match(
+        linker,
+        NAMESPACE,
+        "sock_recv",
+        FuncType::new(vec![ValType::I32; 6], [ValType::I32]),
+        sock_recv,
+        capabilities,
+    )?;
+    link_async5_if_match(
+        linker,
+        NAMESPACE,
+        "sock_send",
+        FuncType::new(vec![ValType::I32; 5], [ValType::I32]),
+        sock_send,
+        capabilities,
+    )?;
+    link_async2_if_match(
+        linker,
+        NAMESPACE,
+        "sock_shutdown",
+        FuncType::new(vec![ValType::I32; 2], [ValType::I32]),
+        sock_shutdown,
+        capabilities,
+    )?;
+    link_async3_if_match(
+        linker,
+        NAMESPACE,
+        "sock_accept",
+        FuncType::new(vec![ValType::I32; 3], [ValType::I32]),
+        sock_accept,
+        capabilities,
+    )?;
+
     // Override all functions not matched with a trap implementation.
-    if !namespace_matches_filter("wasi_snapshot_preview1", "args_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "args_get", |_: i32, _: i32| -> Result<i32, Trap> {
-            Err(Trap::new("Host function `wasi_snapshot_preview1::args_get` unavailable in this environment."))
-        })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "args_sizes_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "args_sizes_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::args_sizes_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "clock_res_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "clock_res_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::clock_res_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "clock_time_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "clock_time_get", |_: i32, _:i64, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::clock_time_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "environ_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "environ_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::environ_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_advise", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_advise", |_: i32, _:i64, _:i64, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_advise` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_allocate", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_allocate", |_: i32, _:i64, _:i64| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_allocate` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_close", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_close",
-            |_: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_close` unavailable in this environment.",
-            ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_datasync", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_datasync", |_: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_datasync` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_fdstat_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_fdstat_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_fdstat_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fd_fdstat_set_flags",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_fdstat_set_flags", |_: i32, _: i32| -> Result<i32, Trap> {
-                Err(Trap::new("Host function `wasi_snapshot_preview1::fd_fdstat_set_flags` unavailable in this environment."))
+    for (name, func_ty) in WASI_FUNCTIONS.iter() {
+        if !capabilities.is_allowed(NAMESPACE, name) {
+            let error = format!(
+                "Host function `{}::{}` unavailable in this environment.",
+                NAMESPACE, name
+            );
+            linker.func_new(NAMESPACE, name, func_ty.clone(), move |_, _, _| {
+                Err(Trap::new(error.clone()))
             })?;
+        }
     }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fd_fdstat_set_rights",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_fdstat_set_rights", |_: i32, _:i64, _:i64| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_fdstat_set_rights` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fd_filestat_get",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_filestat_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_filestat_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fd_filestat_set_size",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_filestat_set_size", |_: i32, _: i64| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_filestat_set_size` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fd_filestat_set_times",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_filestat_set_times", |_: i32, _:i64, _:i64, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_filestat_set_times` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_pread", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_pread",
-            |_: i32, _: i32, _: i32, _: i64, _: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_pread` unavailable in this environment.",
-            ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fd_prestat_dir_name",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_prestat_dir_name", |_: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_prestat_dir_name` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_prestat_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_prestat_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_prestat_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_pwrite", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_pwrite", |_: i32, _: i32, _: i32, _: i64, _:i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_pwrite` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_read", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_read",
-            |_: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_read` unavailable in this environment.",
-            ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_readdir", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_readdir", |_: i32, _: i32, _: i32, _: i64, _:i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_readdir` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_renumber", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "fd_renumber", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::fd_renumber` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_seek", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_seek",
-            |_: i32, _: i64, _: i32, _: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_seek` unavailable in this environment.",
-            ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_sync", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_sync",
-            |_: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_sync` unavailable in this environment.",
-            ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_tell", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_tell",
-            |_: i32, _: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_tell` unavailable in this environment.",
+
+    Ok(())
+}
+
+//% wasi_snapshot_preview1::fd_read(fd: u32, iovs_ptr: u32, iovs_len: u32, nread_ptr: u32) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Reads from TCP streams moved into the file descriptor table without blocking the thread, like
+//% `sock_recv`. Other file descriptors are handled by WASI.
+fn fd_read(
+    mut caller: Caller<ProcessState>,
+    fd: u32,
+    iovs_ptr: u32,
+    iovs_len: u32,
+    nread_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let socket = match caller.data().resources.wasi_sockets.get(fd) {
+            Some(socket) => socket,
+            None => {
+                return wasi_call(&mut caller, |ctx, memory| {
+                    Box::pin(wasi_snapshot_preview1::fd_read(
+                        ctx,
+                        memory,
+                        fd as i32,
+                        iovs_ptr as i32,
+                        iovs_len as i32,
+                        nread_ptr as i32,
+                    ))
+                })
+            }
+        };
+        let read = match receive(&mut caller, &socket, iovs_ptr, iovs_len, Riflags::empty()).await?
+        {
+            Ok(read) => read,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let memory = get_memory(&mut caller)?;
+        Ok(
+            write_u32(memory.data_mut(&mut caller), nread_ptr, read as u32)
+                .err()
+                .unwrap_or(0),
+        )
+    })
+}
+
+//% wasi_snapshot_preview1::fd_write(
+//%     fd: u32,
+//%     ciovs_ptr: u32,
+//%     ciovs_len: u32,
+//%     nwritten_ptr: u32,
+//% ) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Writes to TCP streams moved into the file descriptor table without blocking the thread, like
+//% `sock_send`. Other file descriptors are handled by WASI.
+fn fd_write(
+    mut caller: Caller<ProcessState>,
+    fd: u32,
+    ciovs_ptr: u32,
+    ciovs_len: u32,
+    nwritten_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let socket = match caller.data().resources.wasi_sockets.get(fd) {
+            Some(socket) => socket,
+            None => {
+                return wasi_call(&mut caller, |ctx, memory| {
+                    Box::pin(wasi_snapshot_preview1::fd_write(
+                        ctx,
+                        memory,
+                        fd as i32,
+                        ciovs_ptr as i32,
+                        ciovs_len as i32,
+                        nwritten_ptr as i32,
+                    ))
+                })
+            }
+        };
+        let written = match send(&mut caller, &socket, ciovs_ptr, ciovs_len).await? {
+            Ok(written) => written,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let memory = get_memory(&mut caller)?;
+        Ok(
+            write_u32(memory.data_mut(&mut caller), nwritten_ptr, written as u32)
+                .err()
+                .unwrap_or(0),
+        )
+    })
+}
+
+//% wasi_snapshot_preview1::fd_renumber(from: u32, to: u32) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Renumbers the file descriptor **from** to **to** like WASI does. TCP streams and listeners
+//% moved into the file descriptor table stay usable as sockets under their new number.
+fn fd_renumber(
+    mut caller: Caller<ProcessState>,
+    from: u32,
+    to: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let result = wasi_call(&mut caller, |ctx, memory| {
+            Box::pin(wasi_snapshot_preview1::fd_renumber(
+                ctx,
+                memory,
+                from as i32,
+                to as i32,
             ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "fd_write", namespace_filter) {
-        linker.func_wrap(
-            "wasi_snapshot_preview1",
-            "fd_write",
-            |_: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-                Err(Trap::new(
-                "Host function `wasi_snapshot_preview1::fd_write` unavailable in this environment.",
+        })?;
+        if result == 0 {
+            caller.data_mut().renumber_wasi_socket(from, to);
+        }
+        Ok(result)
+    })
+}
+
+//% wasi_snapshot_preview1::poll_oneoff(
+//%     in_ptr: u32,
+//%     out_ptr: u32,
+//%     nsubscriptions: u32,
+//%     nevents_ptr: u32,
+//% ) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Waits on the subscriptions like WASI does. Returns `ENOTSUP` if any of them is a read or write
+//% subscription on a TCP stream or listener moved into the file descriptor table.
+fn poll_oneoff(
+    mut caller: Caller<ProcessState>,
+    in_ptr: u32,
+    out_ptr: u32,
+    nsubscriptions: u32,
+    nevents_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let start = in_ptr as usize;
+        let end = start.saturating_add(nsubscriptions as usize * SUBSCRIPTION_SIZE);
+        // Subscriptions outside the memory are reported by WASI
+        if let Some(subscriptions) = memory.data(&caller).get(start..end) {
+            let sockets = &caller.data().resources.wasi_sockets;
+            let polls_socket = subscriptions
+                .chunks_exact(SUBSCRIPTION_SIZE)
+                .any(|subscription| {
+                    // The event type is followed by the file descriptor of read (1) and write (2)
+                    // subscriptions
+                    let fd = u32::from_le_bytes(subscription[16..20].try_into().expect("works"));
+                    matches!(subscription[8], 1 | 2) && sockets.get(fd).is_some()
+                });
+            if polls_socket {
+                return Ok(Errno::Notsup as u32);
+            }
+        }
+        wasi_call(&mut caller, |ctx, memory| {
+            Box::pin(wasi_snapshot_preview1::poll_oneoff(
+                ctx,
+                memory,
+                in_ptr as i32,
+                out_ptr as i32,
+                nsubscriptions as i32,
+                nevents_ptr as i32,
             ))
-            },
-        )?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "fdpath_create_directory_tell",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "fdpath_create_directory_tell", |_: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_create_directory` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "path_filestat_get",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_filestat_get", |_: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_filestat_get` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "path_filestat_set_times",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_filestat_set_times", |_: i32, _: i32, _: i32, _: i32, _: i64, _: i64, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_filestat_set_times` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "path_link", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_link", |_: i32, _: i32, _: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_link` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "path_open", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_open", |_: i32, _: i32, _: i32, _: i32, _: i32, _: i64, _: i64, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_open` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "path_readlink", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_readlink", |_: i32, _: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_readlink` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "path_remove_directory",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_remove_directory", |_: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_remove_directory` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "path_rename", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_rename", |_: i32, _: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_rename` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "path_symlink", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_symlink", |_: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_symlink` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter(
-        "wasi_snapshot_preview1",
-        "path_unlink_file",
-        namespace_filter,
-    ) {
-        linker.func_wrap("wasi_snapshot_preview1", "path_unlink_file", |_: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::path_unlink_file` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "poll_oneoff", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "poll_oneoff", |_: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::poll_oneoff` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "proc_exit", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "proc_exit", |_: i32| -> Result<(), Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::proc_exit` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "proc_raise", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "proc_raise", |_: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::proc_raise` unavailable in this environment."))
-    })?;
-    }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "random_get", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "random_get", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::random_get` unavailable in this environment."))
-    })?;
+        })
+    })
+}
+
+//% wasi_snapshot_preview1::sock_recv(
+//%     fd: u32,
+//%     ri_data_ptr: u32,
+//%     ri_data_len: u32,
+//%     ri_flags: u32,
+//%     ro_datalen_ptr: u32,
+//%     ro_flags_ptr: u32,
+//% ) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Receives data from the TCP stream into the **ri_data** iovec array and writes the number of
+//% received bytes to **ro_datalen_ptr**. Supports the `RECV_PEEK` and `RECV_WAITALL` flags. At
+//% most 64 KiB are received per call.
+fn sock_recv(
+    mut caller: Caller<ProcessState>,
+    fd: u32,
+    ri_data_ptr: u32,
+    ri_data_len: u32,
+    ri_flags: u32,
+    ro_datalen_ptr: u32,
+    ro_flags_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let socket = match wasi_socket(&mut caller, fd) {
+            Ok(socket) => socket,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let flags = Riflags::from_bits_truncate(ri_flags as u16);
+        let received = match receive(&mut caller, &socket, ri_data_ptr, ri_data_len, flags).await? {
+            Ok(received) => received,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let memory = get_memory(&mut caller)?;
+        let memory = memory.data_mut(&mut caller);
+        let outputs = write_u32(memory, ro_datalen_ptr, received as u32)
+            .and_then(|_| write_u32(memory, ro_flags_ptr, 0));
+        Ok(outputs.err().unwrap_or(0))
+    })
+}
+
+//% wasi_snapshot_preview1::sock_send(
+//%     fd: u32,
+//%     si_data_ptr: u32,
+//%     si_data_len: u32,
+//%     si_flags: u32,
+//%     so_datalen_ptr: u32,
+//% ) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Sends the data of the **si_data** ciovec array over the TCP stream and writes the number of
+//% sent bytes to **so_datalen_ptr**. At most 64 KiB are sent per call.
+fn sock_send(
+    mut caller: Caller<ProcessState>,
+    fd: u32,
+    si_data_ptr: u32,
+    si_data_len: u32,
+    _si_flags: u32,
+    so_datalen_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let socket = match wasi_socket(&mut caller, fd) {
+            Ok(socket) => socket,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let sent = match send(&mut caller, &socket, si_data_ptr, si_data_len).await? {
+            Ok(sent) => sent,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let memory = get_memory(&mut caller)?;
+        Ok(
+            write_u32(memory.data_mut(&mut caller), so_datalen_ptr, sent as u32)
+                .err()
+                .unwrap_or(0),
+        )
+    })
+}
+
+// Receives data from the stream into the iovecs and returns the number of received bytes.
+async fn receive(
+    caller: &mut Caller<'_, ProcessState>,
+    socket: &WasiSocket,
+    iovs_ptr: u32,
+    iovs_len: u32,
+    flags: Riflags,
+) -> Result<Result<usize, Errno>, Trap> {
+    let mut stream = match socket.kind() {
+        SocketKind::Stream(stream) => stream,
+        SocketKind::Listener(_) => return Ok(Err(Errno::Notconn)),
+    };
+    let memory = get_memory(caller)?;
+    let iovecs = match iovecs(memory.data(&caller), iovs_ptr, iovs_len) {
+        Some(iovecs) => iovecs,
+        None => return Ok(Err(Errno::Fault)),
+    };
+
+    // Iovecs can overlap, so their total length is only bounded by the transfer limit
+    let total = iovecs
+        .iter()
+        .fold(0usize, |total, (_, len)| total.saturating_add(*len));
+    let mut buffer = vec![0; total.min(MAX_TRANSFER)];
+    let result = if flags.contains(Riflags::RECV_PEEK) {
+        socket.io(stream.peek(&mut buffer)).await
+    } else if flags.contains(Riflags::RECV_WAITALL) {
+        let mut received = 0;
+        loop {
+            match socket.io(stream.read(&mut buffer[received..])).await {
+                Ok(0) => break Ok(received),
+                Ok(read) if received + read == buffer.len() => break Ok(buffer.len()),
+                Ok(read) => received += read,
+                Err(error) => break Err(error),
+            }
+        }
+    } else {
+        socket.io(stream.read(&mut buffer)).await
+    };
+    let received = match result {
+        Ok(received) => received,
+        Err(error) => return Ok(Err(errno(error))),
+    };
+
+    // Scatter the received bytes over the iovecs
+    let memory = memory.data_mut(caller);
+    let mut data = &buffer[..received];
+    for (ptr, len) in iovecs {
+        let len = len.min(data.len());
+        memory[ptr..ptr + len].copy_from_slice(&data[..len]);
+        data = &data[len..];
+    }
+    Ok(Ok(received))
+}
+
+// Sends the data of the ciovecs over the stream and returns the number of sent bytes.
+async fn send(
+    caller: &mut Caller<'_, ProcessState>,
+    socket: &WasiSocket,
+    ciovs_ptr: u32,
+    ciovs_len: u32,
+) -> Result<Result<usize, Errno>, Trap> {
+    let mut stream = match socket.kind() {
+        SocketKind::Stream(stream) => stream,
+        SocketKind::Listener(_) => return Ok(Err(Errno::Notconn)),
+    };
+    let memory = get_memory(caller)?;
+    let data = memory.data(&caller);
+    let iovecs = match iovecs(data, ciovs_ptr, ciovs_len) {
+        Some(iovecs) => iovecs,
+        None => return Ok(Err(Errno::Fault)),
+    };
+    let mut buffer = Vec::new();
+    for (ptr, len) in iovecs {
+        let len = len.min(MAX_TRANSFER - buffer.len());
+        buffer.extend_from_slice(&data[ptr..ptr + len]);
+    }
+    match socket.io(stream.write(&buffer)).await {
+        Ok(sent) => Ok(Ok(sent)),
+        Err(error) => Ok(Err(errno(error))),
     }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "sched_yield", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "sched_yield", || -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::sched_yield` unavailable in this environment."))
-    })?;
+}
+
+// Calls the WASI implementation of a function. They don't wait on their own, so the future is
+// polled only once, the same way the WASI functions registered to the linker are.
+fn wasi_call<F>(caller: &mut Caller<ProcessState>, call: F) -> Result<u32, Trap>
+where
+    F: for<'a> FnOnce(&'a mut WasiCtx, &'a dyn GuestMemory) -> WasiFuture<'a>,
+{
+    let memory = get_memory(caller)?;
+    let (memory, state) = memory.data_and_store_mut(caller);
+    let memory = WasmtimeGuestMemory::new(memory);
+    match wiggle::run_in_dummy_executor(call(&mut state.wasi, &memory))? {
+        Ok(errno) => Ok(errno as u32),
+        Err(wiggle::Trap::String(error)) => Err(Trap::new(error)),
+        Err(wiggle::Trap::I32Exit(code)) => Err(Trap::i32_exit(code)),
     }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "sock_recv", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "sock_recv", |_: i32, _: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::sock_recv` unavailable in this environment."))
-    })?;
+}
+
+type WasiFuture<'a> = Pin<Box<dyn Future<Output = Result<i32, wiggle::Trap>> + 'a>>;
+
+//% wasi_snapshot_preview1::sock_shutdown(fd: u32, how: u32) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Shuts down the reading (`SHUT_RD`), writing (`SHUT_WR`) or both halves of the TCP stream.
+fn sock_shutdown(
+    mut caller: Caller<ProcessState>,
+    fd: u32,
+    how: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let socket = match wasi_socket(&mut caller, fd) {
+            Ok(socket) => socket,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let stream = match socket.kind() {
+            SocketKind::Stream(stream) => stream,
+            SocketKind::Listener(_) => return Ok(Errno::Notconn as u32),
+        };
+        let how = match Sdflags::from_bits(how as u8) {
+            Some(how) if how == Sdflags::RD => Shutdown::Read,
+            Some(how) if how == Sdflags::WR => Shutdown::Write,
+            Some(how) if how == Sdflags::RD | Sdflags::WR => Shutdown::Both,
+            _ => return Ok(Errno::Inval as u32),
+        };
+        match stream.shutdown(how) {
+            Ok(()) => Ok(0),
+            Err(error) => Ok(errno(error) as u32),
+        }
+    })
+}
+
+//% wasi_snapshot_preview1::sock_accept(fd: u32, flags: u32, ro_fd_ptr: u32) -> u32
+//%
+//% Returns the WASI error number, 0 on success.
+//%
+//% Accepts a new connection on the TCP listener and writes the file descriptor of the new stream
+//% to **ro_fd_ptr**. The `NONBLOCK` flag applies to the new stream. The stream counts against the
+//% environment's TCP stream quota, `ENFILE` is returned if it's exhausted.
+fn sock_accept(
+    mut caller: Caller<ProcessState>,
+    fd: u32,
+    flags: u32,
+    ro_fd_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let socket = match wasi_socket(&mut caller, fd) {
+            Ok(socket) => socket,
+            Err(errno) => return Ok(errno as u32),
+        };
+        let listener = match socket.kind() {
+            SocketKind::Listener(listener) => listener,
+            SocketKind::Stream(_) => return Ok(Errno::Inval as u32),
+        };
+        let flags = match FdFlags::from_bits(flags) {
+            Some(flags) if FdFlags::NONBLOCK.contains(flags) => flags,
+            _ => return Ok(Errno::Inval as u32),
+        };
+        if caller.data_mut().try_add_tcp_stream().is_err() {
+            return Ok(Errno::Nfile as u32);
+        }
+        let stream = match socket.io(listener.accept()).await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                caller.data_mut().quota.remove_tcp_stream();
+                return Ok(errno(error) as u32);
+            }
+        };
+        let fd = caller
+            .data_mut()
+            .insert_wasi_socket(SocketKind::Stream(stream), flags);
+        let memory = get_memory(&mut caller)?;
+        Ok(write_u32(memory.data_mut(&mut caller), ro_fd_ptr, fd)
+            .err()
+            .unwrap_or(0))
+    })
+}
+
+// Looks up the socket behind the file descriptor.
+fn wasi_socket(caller: &mut Caller<ProcessState>, fd: u32) -> Result<Arc<WasiSocket>, Errno> {
+    let state = caller.data_mut();
+    match state.resources.wasi_sockets.get(fd) {
+        Some(socket) => Ok(socket),
+        None if state.wasi.table().contains_key(fd) => Err(Errno::Notsock),
+        None => Err(Errno::Badf),
     }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "sock_send", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "sock_send", |_: i32, _: i32, _: i32, _: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::sock_send` unavailable in this environment."))
-    })?;
+}
+
+// Returns the (offset, length) pairs of an iovec array, if the array and all buffers are inside
+// the memory.
+fn iovecs(memory: &[u8], ptr: u32, len: u32) -> Option<Vec<(usize, usize)>> {
+    let start = ptr as usize;
+    let end = start.checked_add(len as usize * 8)?;
+    memory
+        .get(start..end)?
+        .chunks_exact(8)
+        .map(|iovec| {
+            let ptr = u32::from_le_bytes(iovec[0..4].try_into().expect("works")) as usize;
+            let len = u32::from_le_bytes(iovec[4..8].try_into().expect("works")) as usize;
+            memory.get(ptr..ptr.checked_add(len)?)?;
+            Some((ptr, len))
+        })
+        .collect()
+}
+
+// Writes the value to the guest memory, fails with the `EFAULT` error number if it's outside.
+fn write_u32(memory: &mut [u8], ptr: u32, value: u32) -> Result<(), u32> {
+    let ptr = ptr as usize;
+    match memory.get_mut(ptr..ptr.saturating_add(4)) {
+        Some(slot) => {
+            slot.copy_from_slice(&value.to_le_bytes());
+            Ok(())
+        }
+        None => Err(Errno::Fault as u32),
     }
-    if !namespace_matches_filter("wasi_snapshot_preview1", "sock_shutdown", namespace_filter) {
-        linker.func_wrap("wasi_snapshot_preview1", "sock_shutdown", |_: i32, _: i32| -> Result<i32, Trap> {
-        Err(Trap::new("Host function `wasi_snapshot_preview1::sock_shutdown` unavailable in this environment."))
-    })?;
+}
+
+fn wasi_functions() -> Result<Vec<(String, FuncType)>> {
+    let engine = Engine::default();
+    let mut linker: Linker<WasiCtx> = Linker::new(&engine);
+    wasmtime_wasi::sync::snapshots::preview_1::add_wasi_snapshot_preview1_to_linker(
+        &mut linker,
+        |ctx| ctx,
+    )?;
+    let mut store = Store::new(&engine, WasiCtxBuilder::new().build());
+    let functions = linker
+        .iter(&mut store)
+        .filter_map(|(namespace, name, export)| match export {
+            Extern::Func(func) if namespace == NAMESPACE => Some((name.to_string(), func)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    Ok(functions
+        .into_iter()
+        .map(|(name, func)| (name, func.ty(&store)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use wasmtime::{Engine, ExternType, Module};
+
+    use super::{NAMESPACE, WASI_FUNCTIONS};
+    use crate::capability::WasiCapability;
+
+    #[test]
+    fn all_imports_cover_wasi() {
+        let engine = Engine::default();
+        let raw_module = std::fs::read("./target/wasm/all_imports.wasm").unwrap();
+        let module = Module::new(&engine, raw_module).unwrap();
+        let imports: HashMap<_, _> = module
+            .imports()
+            .filter(|import| import.module() == NAMESPACE)
+            .map(|import| match import.ty() {
+                ExternType::Func(func_ty) => (import.name().unwrap().to_string(), func_ty),
+                _ => panic!("WASI imports are functions"),
+            })
+            .collect();
+        assert_eq!(imports.len(), WASI_FUNCTIONS.len());
+        for (name, func_ty) in WASI_FUNCTIONS.iter() {
+            assert_eq!(imports.get(name), Some(func_ty), "Signature of `{}`", name);
+        }
     }
 
-    Ok(())
+    #[test]
+    fn capabilities_cover_wasi() {
+        for (name, _) in WASI_FUNCTIONS.iter() {
+            assert!(
+                WasiCapability::all()
+                    .iter()
+                    .any(|capability| capability.functions().contains(&name.as_str())),
+                "`{}` doesn't belong to any WASI capability",
+                name
+            );
+        }
+    }
 }
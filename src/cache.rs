@@ -0,0 +1,227 @@
+/*!
+Caches of compiled modules.
+
+JIT compiling big modules with Cranelift can take seconds. Modules that are added to many
+environments are only compiled once, as long as the environments share the same plugins and code
+generation settings and don't use an instance pool. The compiled module is kept in memory while
+at least one environment is still using it.
+
+If an [`EnvConfig`] has a cache directory set, compiled modules are also serialized into it and
+loaded from there the next time the same module is added to an environment, even by a different
+run of the runtime.
+
+Entries are keyed by a hash of the original module, the plugins of the environment and all
+settings that influence code generation (CPU time limit, memory limits and instance pool limits).
+Changing any of them results in a different key, so stale artifacts are never used.
+Artifacts built by a different Wasmtime version are rejected when deserializing and are
+overwritten with a freshly compiled module.
+
+Artifacts inside the cache directory contain native code that is executed without validation,
+only directories that are exclusively writable by trusted users should be used.
+*/
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use wasmtime::{Engine, Module};
+
+use crate::config::EnvConfig;
+
+lazy_static! {
+    // Compiled modules shared between environments. Each key has its own lock, so that a module
+    // added to many environments at the same time is only compiled once.
+    static ref SHARED_MODULES: Mutex<HashMap<String, Arc<Mutex<Weak<Module>>>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Returns the module compiled from `data` for an environment with `config` if another environment
+// is still using it, otherwise it's compiled with `compile`. Environments using the same module
+// need to share an engine that doesn't use the pooling allocator.
+pub(crate) fn shared_module(
+    data: &[u8],
+    config: &EnvConfig,
+    compile: impl FnOnce() -> Result<Module>,
+) -> Result<Arc<Module>> {
+    let slot = {
+        let mut modules = SHARED_MODULES.lock().unwrap();
+        // Forget modules that are not used anymore
+        modules.retain(|_, slot| {
+            Arc::strong_count(slot) > 1
+                || slot
+                    .try_lock()
+                    .map_or(true, |module| module.strong_count() > 0)
+        });
+        modules
+            .entry(key(data, config, false))
+            .or_insert_with(|| Arc::new(Mutex::new(Weak::new())))
+            .clone()
+    };
+    let mut slot = slot.lock().unwrap();
+    if let Some(module) = slot.upgrade() {
+        return Ok(module);
+    }
+    let module = Arc::new(compile()?);
+    *slot = Arc::downgrade(&module);
+    Ok(module)
+}
+
+pub(crate) struct ModuleCache {
+    dir: PathBuf,
+}
+
+impl ModuleCache {
+    pub(crate) fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    // Returns the cached module compiled from `data` for an environment with `config`. `pooled`
+    // indicates if the `engine` uses the pooling instance allocator.
+    pub(crate) fn load(
+        &self,
+        engine: &Engine,
+        data: &[u8],
+        config: &EnvConfig,
+        pooled: bool,
+    ) -> Option<Module> {
+        let path = self.path(data, config, pooled);
+        let artifact = fs::read(&path).ok()?;
+        // Safety: Artifacts are only written by `ModuleCache::store` and the cache directory is
+        // trusted.
+        match unsafe { Module::deserialize(engine, artifact) } {
+            Ok(module) => {
+                debug!("Loaded compiled module from {}", path.display());
+                Some(module)
+            }
+            Err(err) => {
+                warn!("Invalid module cache entry {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    // Returns the cached module or compiles the `patched` module and adds it to the cache.
+    pub(crate) fn load_or_compile(
+        &self,
+        engine: &Engine,
+        data: &[u8],
+        config: &EnvConfig,
+        pooled: bool,
+        patched: &[u8],
+    ) -> Result<Module> {
+        if let Some(module) = self.load(engine, data, config, pooled) {
+            return Ok(module);
+        }
+        let module = Module::new(engine, patched)?;
+        // Failing to write the cache entry only means that the module is compiled again next time
+        if let Err(err) = self.store(data, config, pooled, &module) {
+            warn!("Failed to write module cache entry: {}", err);
+        }
+        Ok(module)
+    }
+
+    fn store(&self, data: &[u8], config: &EnvConfig, pooled: bool, module: &Module) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let artifact = module.serialize()?;
+        // Write to a temporary file first, so that other runtimes sharing the cache directory
+        // never observe a partially written artifact.
+        let tmp = self.dir.join(format!("{}.tmp", Uuid::new_v4()));
+        fs::write(&tmp, artifact)?;
+        let path = self.path(data, config, pooled);
+        if let Err(err) = fs::rename(&tmp, &path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+        debug!("Wrote compiled module to {}", path.display());
+        Ok(())
+    }
+
+    fn path(&self, data: &[u8], config: &EnvConfig, pooled: bool) -> PathBuf {
+        self.dir
+            .join(format!("{}.cwasm", key(data, config, pooled)))
+    }
+}
+
+// Hashes everything that influences the compiled artifact.
+fn key(data: &[u8], config: &EnvConfig, pooled: bool) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update((data.len() as u64).to_le_bytes());
+    hasher.update(data);
+    hasher.update((config.plugins().len() as u64).to_le_bytes());
+    for plugin in config.plugins() {
+        hasher.update(plugin.hash());
+    }
+    hasher.update([config.max_cpu_time().is_some() as u8, pooled as u8]);
+    hasher.update((config.max_memory() as u64).to_le_bytes());
+    if pooled {
+        hasher.update((config.max_memories() as u64).to_le_bytes());
+        hasher.update((config.max_tables() as u64).to_le_bytes());
+        hasher.update(config.max_table_elements().to_le_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut key, byte| {
+            let _ = write!(key, "{:02x}", byte);
+            key
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use crate::{EnvConfig, Environment};
+
+    #[async_std::test]
+    async fn module_cache() {
+        let dir = std::env::temp_dir().join(format!("lunatic-cache-{}", Uuid::new_v4()));
+        let entries = || fs::read_dir(&dir).unwrap().count();
+        let raw_module = wat::parse_str(r#"(module (func (export "hello")))"#).unwrap();
+
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_cache_dir(Some(dir.clone()));
+        let environment = Environment::new(config.clone()).unwrap();
+        environment.create_module(raw_module.clone()).await.unwrap();
+        assert_eq!(entries(), 1);
+        // Loaded from the cache
+        environment.create_module(raw_module.clone()).await.unwrap();
+        assert_eq!(entries(), 1);
+
+        // Corrupted entries are replaced
+        let entry = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        fs::write(&entry, b"corrupted").unwrap();
+        let module = environment.create_module(raw_module.clone()).await.unwrap();
+        module
+            .spawn("hello", Vec::new(), None)
+            .await
+            .unwrap()
+            .0
+            .await;
+        assert_ne!(fs::read(&entry).unwrap(), b"corrupted");
+
+        // Plugins invalidate the cache
+        config
+            .add_plugin(wat::parse_str("(module)").unwrap())
+            .unwrap();
+        let environment = Environment::new(config).unwrap();
+        environment.create_module(raw_module).await.unwrap();
+        assert_eq!(entries(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,416 @@
+/*!
+Capabilities define which host functions processes spawned into an environment can use.
+
+Each host function is identified by its full name, the namespace and function name joined with
+`::` (e.g. `lunatic::process::spawn`). Rules are matched against the full name segment by segment:
+
+* `lunatic::process::spawn` - exactly this host function.
+* `lunatic::process::*` - all host functions inside the `lunatic::process` namespace and its
+  sub-namespaces.
+* `*` - all host functions.
+
+A trailing `::` is treated the same as `::*`, so `lunatic::` and `lunatic::*` are equivalent. Exact
+rules that only name a namespace (e.g. `lunatic::process`) would never match any host function and
+are rejected.
+
+A host function is available if it matches at least one allow rule and no deny rule. Deny rules
+always win, independent of the order in which rules were added.
+
+WASI functions can also be allowed or denied in coarse groups (see [`WasiCapability`]), without
+knowing the name of each function. Allowing a group adds one rule per function in it.
+
+Capabilities can be bounded by a parent (see [`Capabilities::bounded_by`]). In this case a host
+function is only available if it's also available to the parent, no matter what rules are added
+to the child. This is used to prevent processes from escaping their sandbox by creating more
+privileged environments.
+*/
+
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Result};
+
+// Namespaces of the host functions provided by the runtime.
+const HOST_NAMESPACES: &[&str] = &[
+    "lunatic::error",
+    "lunatic::message",
+    "lunatic::networking",
+    "lunatic::plugin",
+    "lunatic::process",
+    WASI_NAMESPACE,
+];
+
+/// A set of allow and deny rules for host functions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    allowed: Vec<Rule>,
+    denied: Vec<Rule>,
+    parent: Option<Box<Capabilities>>,
+}
+
+impl Capabilities {
+    /// Create an empty set of capabilities that doesn't allow any host function.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty set of capabilities that can never allow more than the `parent`.
+    pub fn bounded_by(parent: &Capabilities) -> Self {
+        Self {
+            allowed: Vec::new(),
+            denied: Vec::new(),
+            parent: Some(Box::new(parent.clone())),
+        }
+    }
+
+    /// Allow all host functions matching the `rule`.
+    ///
+    /// Fails if the rule is malformed.
+    pub fn allow(&mut self, rule: &str) -> Result<()> {
+        let rule = rule.parse()?;
+        self.allowed.push(rule);
+        Ok(())
+    }
+
+    /// Deny all host functions matching the `rule`, even if they are matched by an allow rule.
+    ///
+    /// Fails if the rule is malformed.
+    pub fn deny(&mut self, rule: &str) -> Result<()> {
+        let rule = rule.parse()?;
+        self.denied.push(rule);
+        Ok(())
+    }
+
+    /// Allow all WASI functions belonging to the `capability`.
+    pub fn allow_wasi(&mut self, capability: WasiCapability) {
+        for function in capability.functions() {
+            self.allowed.push(Rule::wasi(function));
+        }
+    }
+
+    /// Deny all WASI functions belonging to the `capability`.
+    ///
+    /// Groups share some functions (e.g. `fd_read` is part of `Stdio` and `FilesystemRead`), so
+    /// denying one group can take functions away from another allowed group.
+    pub fn deny_wasi(&mut self, capability: WasiCapability) {
+        for function in capability.functions() {
+            self.denied.push(Rule::wasi(function));
+        }
+    }
+
+    /// Returns true if all WASI functions belonging to the `capability` are allowed.
+    pub fn is_wasi_allowed(&self, capability: WasiCapability) -> bool {
+        capability
+            .functions()
+            .iter()
+            .all(|function| self.is_allowed(WASI_NAMESPACE, function))
+    }
+
+    pub fn allowed(&self) -> &[Rule] {
+        &self.allowed
+    }
+
+    pub fn denied(&self) -> &[Rule] {
+        &self.denied
+    }
+
+    /// Returns true if the host function `namespace::name` is allowed and not denied.
+    pub fn is_allowed(&self, namespace: &str, name: &str) -> bool {
+        let full_name: Vec<&str> = namespace.split("::").chain(Some(name)).collect();
+        self.is_allowed_segments(&full_name)
+    }
+
+    fn is_allowed_segments(&self, full_name: &[&str]) -> bool {
+        let allowed = self.allowed.iter().any(|rule| rule.matches(full_name));
+        let denied = self.denied.iter().any(|rule| rule.matches(full_name));
+        let allowed_by_parent = match &self.parent {
+            Some(parent) => parent.is_allowed_segments(full_name),
+            None => true,
+        };
+        allowed && !denied && allowed_by_parent
+    }
+}
+
+const WASI_NAMESPACE: &str = "wasi_snapshot_preview1";
+
+/// A group of WASI functions that guests need for one kind of access to the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WasiCapability {
+    /// Reading command line arguments.
+    Args,
+    /// Reading environment variables.
+    Environment,
+    /// Reading the wall and monotonic clocks.
+    Clocks,
+    /// Getting random bytes.
+    Random,
+    /// Exiting the process with an exit code or signal.
+    ProcessExit,
+    /// Reading stdin, writing stdout and stderr. This includes looking up preopened directories,
+    /// which libc does on startup.
+    Stdio,
+    /// Opening, reading and listing files and directories in preopened directories.
+    FilesystemRead,
+    /// Creating, writing, renaming and removing files and directories in preopened directories.
+    /// Without it, preopened directories are always read-only.
+    FilesystemWrite,
+    /// Waiting on events (`poll_oneoff`) and yielding (`sched_yield`).
+    Scheduling,
+    /// Using sockets that were handed to the process.
+    Sockets,
+}
+
+impl WasiCapability {
+    /// Names of the `wasi_snapshot_preview1` functions belonging to the capability.
+    pub fn functions(&self) -> &'static [&'static str] {
+        match self {
+            WasiCapability::Args => &["args_get", "args_sizes_get"],
+            WasiCapability::Environment => &["environ_get", "environ_sizes_get"],
+            WasiCapability::Clocks => &["clock_res_get", "clock_time_get"],
+            WasiCapability::Random => &["random_get"],
+            WasiCapability::ProcessExit => &["proc_exit", "proc_raise"],
+            WasiCapability::Stdio => &[
+                "fd_read",
+                "fd_write",
+                "fd_close",
+                "fd_fdstat_get",
+                "fd_prestat_get",
+                "fd_prestat_dir_name",
+            ],
+            WasiCapability::FilesystemRead => &[
+                "fd_read",
+                "fd_pread",
+                "fd_close",
+                "fd_seek",
+                "fd_tell",
+                "fd_advise",
+                "fd_readdir",
+                "fd_renumber",
+                "fd_fdstat_get",
+                "fd_fdstat_set_flags",
+                "fd_fdstat_set_rights",
+                "fd_filestat_get",
+                "fd_prestat_get",
+                "fd_prestat_dir_name",
+                "path_open",
+                "path_readlink",
+                "path_filestat_get",
+            ],
+            WasiCapability::FilesystemWrite => &[
+                "fd_write",
+                "fd_pwrite",
+                "fd_allocate",
+                "fd_datasync",
+                "fd_sync",
+                "fd_filestat_set_size",
+                "fd_filestat_set_times",
+                "path_create_directory",
+                "path_filestat_set_times",
+                "path_link",
+                "path_remove_directory",
+                "path_rename",
+                "path_symlink",
+                "path_unlink_file",
+            ],
+            WasiCapability::Scheduling => &["poll_oneoff", "sched_yield"],
+            WasiCapability::Sockets => &["sock_accept", "sock_recv", "sock_send", "sock_shutdown"],
+        }
+    }
+
+    /// All capabilities, together they cover every WASI function.
+    pub fn all() -> [WasiCapability; 10] {
+        [
+            WasiCapability::Args,
+            WasiCapability::Environment,
+            WasiCapability::Clocks,
+            WasiCapability::Random,
+            WasiCapability::ProcessExit,
+            WasiCapability::Stdio,
+            WasiCapability::FilesystemRead,
+            WasiCapability::FilesystemWrite,
+            WasiCapability::Scheduling,
+            WasiCapability::Sockets,
+        ]
+    }
+}
+
+/// A single validated rule matching one or more host functions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    segments: Vec<String>,
+    // If true, the rule matches everything under `segments`.
+    wildcard: bool,
+}
+
+impl Rule {
+    // Returns true if the segments of a host function's full name are matched by this rule.
+    fn matches(&self, full_name: &[&str]) -> bool {
+        let length_matches = if self.wildcard {
+            full_name.len() > self.segments.len()
+        } else {
+            full_name.len() == self.segments.len()
+        };
+        length_matches && self.segments.iter().zip(full_name).all(|(a, b)| a == b)
+    }
+}
+
+impl Rule {
+    fn wasi(function: &str) -> Self {
+        Self {
+            segments: vec![WASI_NAMESPACE.to_string(), function.to_string()],
+            wildcard: false,
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        if rule.is_empty() {
+            return Err(anyhow!("Capability rule can't be empty"));
+        }
+        let mut segments: Vec<&str> = rule.split("::").collect();
+        // `namespace::` is an alias for `namespace::*`
+        let wildcard = match segments.last() {
+            Some(&"*") | Some(&"") => {
+                segments.pop();
+                true
+            }
+            _ => false,
+        };
+        for segment in segments.iter() {
+            if segment.is_empty() {
+                return Err(anyhow!(
+                    "Capability rule `{}` contains an empty segment",
+                    rule
+                ));
+            }
+            if let Some(c) = segment
+                .chars()
+                .find(|c| *c == '*' || *c == ':' || c.is_whitespace())
+            {
+                return Err(anyhow!(
+                    "Capability rule `{}` contains an invalid character `{}`, wildcards are only \
+                     allowed as the last segment",
+                    rule,
+                    c
+                ));
+            }
+        }
+        // Host functions are always named by a namespace and a function name
+        if !wildcard && (segments.len() < 2 || HOST_NAMESPACES.contains(&rule)) {
+            return Err(anyhow!(
+                "Capability rule `{}` names a namespace and doesn't match any host function, use \
+                 `{}::*` to match all host functions inside of it",
+                rule,
+                rule
+            ));
+        }
+        Ok(Self {
+            segments: segments.into_iter().map(String::from).collect(),
+            wildcard,
+        })
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.segments.is_empty(), self.wildcard) {
+            (true, _) => write!(f, "*"),
+            (false, true) => write!(f, "{}::*", self.segments.join("::")),
+            (false, false) => write!(f, "{}", self.segments.join("::")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, Rule, WasiCapability};
+
+    #[test]
+    fn rule_parsing() {
+        assert!("".parse::<Rule>().is_err());
+        assert!("lunatic::::process".parse::<Rule>().is_err());
+        assert!("lunatic::*::spawn".parse::<Rule>().is_err());
+        assert!("lunatic::proc*".parse::<Rule>().is_err());
+        assert!("lunatic:process".parse::<Rule>().is_err());
+        assert!("lunatic:: process".parse::<Rule>().is_err());
+        // Exact rules naming only a namespace never match
+        assert!("lunatic".parse::<Rule>().is_err());
+        assert!("lunatic::process".parse::<Rule>().is_err());
+        assert!("wasi_snapshot_preview1".parse::<Rule>().is_err());
+
+        let rule: Rule = "lunatic::".parse().unwrap();
+        assert_eq!(rule.to_string(), "lunatic::*");
+        let rule: Rule = "*".parse().unwrap();
+        assert_eq!(rule.to_string(), "*");
+        let rule: Rule = "lunatic::process::spawn".parse().unwrap();
+        assert_eq!(rule.to_string(), "lunatic::process::spawn");
+    }
+
+    #[test]
+    fn capability_matching() {
+        let mut capabilities = Capabilities::new();
+        assert!(!capabilities.is_allowed("lunatic::process", "spawn"));
+
+        // Rules match whole segments and not string prefixes
+        capabilities.allow("lunatic::proc::*").unwrap();
+        assert!(!capabilities.is_allowed("lunatic::process", "spawn"));
+
+        capabilities.allow("lunatic::process::*").unwrap();
+        assert!(capabilities.is_allowed("lunatic::process", "spawn"));
+        assert!(!capabilities.is_allowed("lunatic::processes", "spawn"));
+        assert!(!capabilities.is_allowed("lunatic::networking", "tcp_bind"));
+
+        // Exact rules don't match functions sharing the prefix
+        capabilities.allow("lunatic::networking::tcp_bind").unwrap();
+        assert!(capabilities.is_allowed("lunatic::networking", "tcp_bind"));
+        assert!(!capabilities.is_allowed("lunatic::networking", "tcp_bind_v6"));
+
+        // Deny wins, even if the rule was added before a matching allow rule
+        capabilities.deny("lunatic::process::spawn").unwrap();
+        capabilities.allow("*").unwrap();
+        assert!(!capabilities.is_allowed("lunatic::process", "spawn"));
+        assert!(capabilities.is_allowed("lunatic::process", "inherit_spawn"));
+        assert!(capabilities.is_allowed("wasi_snapshot_preview1", "fd_write"));
+    }
+
+    #[test]
+    fn bounded_capabilities() {
+        let mut parent = Capabilities::new();
+        parent.allow("lunatic::*").unwrap();
+        parent.deny("lunatic::networking::*").unwrap();
+
+        let mut child = Capabilities::bounded_by(&parent);
+        assert!(!child.is_allowed("lunatic::process", "spawn"));
+        child.allow("*").unwrap();
+        assert!(child.is_allowed("lunatic::process", "spawn"));
+        // Not allowed by the parent
+        assert!(!child.is_allowed("wasi_snapshot_preview1", "fd_write"));
+        // Denied by the parent
+        assert!(!child.is_allowed("lunatic::networking", "tcp_bind"));
+
+        // Bounds are transitive
+        let mut grandchild = Capabilities::bounded_by(&child);
+        grandchild.allow("lunatic::networking::*").unwrap();
+        assert!(!grandchild.is_allowed("lunatic::networking", "tcp_bind"));
+    }
+
+    #[test]
+    fn wasi_capabilities() {
+        let mut capabilities = Capabilities::new();
+        capabilities.allow_wasi(WasiCapability::Stdio);
+        capabilities.allow_wasi(WasiCapability::FilesystemRead);
+        assert!(capabilities.is_allowed("wasi_snapshot_preview1", "fd_write"));
+        assert!(capabilities.is_allowed("wasi_snapshot_preview1", "path_open"));
+        assert!(!capabilities.is_allowed("wasi_snapshot_preview1", "path_unlink_file"));
+        assert!(!capabilities.is_allowed("wasi_snapshot_preview1", "clock_time_get"));
+        assert!(capabilities.is_wasi_allowed(WasiCapability::FilesystemRead));
+        assert!(!capabilities.is_wasi_allowed(WasiCapability::FilesystemWrite));
+
+        // Denying a group removes shared functions from other groups
+        capabilities.deny_wasi(WasiCapability::FilesystemWrite);
+        assert!(!capabilities.is_allowed("wasi_snapshot_preview1", "fd_write"));
+        assert!(!capabilities.is_wasi_allowed(WasiCapability::Stdio));
+    }
+}
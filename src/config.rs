@@ -1,6 +1,19 @@
-use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::plugin::Plugin;
+use anyhow::{anyhow, Result};
+
+use crate::{
+    capability::{Capabilities, WasiCapability},
+    environment::UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+    plugin::Plugin,
+    preopen::PreopenedDir,
+    scheduler::{Priority, DEFAULT_WEIGHT},
+    stdio::{WasiOutput, WasiStdin},
+    vfs::{VirtualFs, VirtualMount},
+};
 
 /// Configuration structure for environments.
 #[derive(Clone)]
@@ -9,10 +22,42 @@ pub struct EnvConfig {
     max_memory: usize,
     // Maximum amount of compute expressed in units of 100k instructions.
     max_fuel: Option<u64>,
-    allowed_namespaces: Vec<String>,
+    // Maximum CPU time a process can use.
+    max_cpu_time: Option<Duration>,
+    // Maximum wall-clock time a process can run for.
+    max_runtime: Option<Duration>,
+    // Limits on the resources of a single process' Wasm instance.
+    max_table_elements: u32,
+    max_instances: usize,
+    max_tables: usize,
+    max_memories: usize,
+    // Number of processes that can be allocated from the instance pool.
+    instance_pool_size: Option<u32>,
+    // Environment wide quotas, shared between all processes (see the `quota` module).
+    max_total_memory: Option<usize>,
+    max_processes: Option<usize>,
+    max_fuel_per_window: Option<(u64, Duration)>,
+    max_tcp_streams: Option<usize>,
+    max_disk_usage: Option<usize>,
+    // Seed of the virtual time and randomness (see the `virtual_time` module).
+    deterministic_seed: Option<u64>,
+    // Scheduling parameters (see the `scheduler` module).
+    time_slice: u64,
+    scheduling_weight: u32,
+    priority: Priority,
+    capabilities: Capabilities,
     plugins: Vec<Plugin>,
     wasi_args: Option<Vec<String>>,
     wasi_envs: Option<Vec<(String, String)>>,
+    // Standard streams of processes (see the `stdio` module).
+    wasi_stdin: WasiStdin,
+    wasi_stdout: WasiOutput,
+    wasi_stderr: WasiOutput,
+    // Host directories accessible to processes (see the `preopen` module).
+    preopened_dirs: Vec<PreopenedDir>,
+    virtual_mounts: Vec<VirtualMount>,
+    // Directory used to cache compiled modules between runs (see the `cache` module).
+    cache_dir: Option<PathBuf>,
 }
 
 impl EnvConfig {
@@ -21,11 +66,98 @@ impl EnvConfig {
         Self {
             max_memory,
             max_fuel,
-            allowed_namespaces: Vec::new(),
+            max_cpu_time: None,
+            max_runtime: None,
+            max_table_elements: 9_999,
+            max_instances: 1,
+            max_tables: 1,
+            max_memories: 1,
+            instance_pool_size: None,
+            max_total_memory: None,
+            max_processes: None,
+            max_fuel_per_window: None,
+            max_tcp_streams: None,
+            max_disk_usage: None,
+            deterministic_seed: None,
+            time_slice: UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            scheduling_weight: DEFAULT_WEIGHT,
+            priority: Priority::default(),
+            capabilities: Capabilities::new(),
             plugins: Vec::new(),
             wasi_args: None,
             wasi_envs: None,
+            wasi_stdin: WasiStdin::default(),
+            wasi_stdout: WasiOutput::default(),
+            wasi_stderr: WasiOutput::default(),
+            preopened_dirs: Vec::new(),
+            virtual_mounts: Vec::new(),
+            cache_dir: None,
+        }
+    }
+
+    /// Create a new environment configuration that is bounded by the `parent` configuration.
+    ///
+    /// Processes spawned into environments created from it can't use more memory or fuel than
+    /// the parent allows, can only use host functions that are also available to the parent and
+    /// will always run the parent's plugins before its own. If `max_fuel` is `None` the parent's
+    /// fuel limit is inherited. The time slice, CPU time limit, maximum runtime and instance
+    /// resource limits are always inherited, as are the standard streams and the module cache
+    /// directory.
+    ///
+    /// Fails if `max_memory` or `max_fuel` exceed the limits of the parent.
+    pub fn new_bounded(
+        parent: &EnvConfig,
+        max_memory: usize,
+        max_fuel: Option<u64>,
+    ) -> Result<Self> {
+        if max_memory > parent.max_memory {
+            return Err(anyhow!(
+                "Maximum memory of {} bytes exceeds the parent's limit of {} bytes",
+                max_memory,
+                parent.max_memory
+            ));
         }
+        let max_fuel = match (max_fuel, parent.max_fuel) {
+            (Some(max_fuel), Some(parent_max_fuel)) if max_fuel > parent_max_fuel => {
+                return Err(anyhow!(
+                    "Maximum fuel of {} exceeds the parent's limit of {}",
+                    max_fuel,
+                    parent_max_fuel
+                ))
+            }
+            (None, parent_max_fuel) => parent_max_fuel,
+            (max_fuel, _) => max_fuel,
+        };
+        Ok(Self {
+            max_memory,
+            max_fuel,
+            max_cpu_time: parent.max_cpu_time,
+            max_runtime: parent.max_runtime,
+            max_table_elements: parent.max_table_elements,
+            max_instances: parent.max_instances,
+            max_tables: parent.max_tables,
+            max_memories: parent.max_memories,
+            instance_pool_size: None,
+            max_total_memory: None,
+            max_processes: None,
+            max_fuel_per_window: None,
+            max_tcp_streams: None,
+            max_disk_usage: None,
+            deterministic_seed: parent.deterministic_seed,
+            time_slice: parent.time_slice,
+            scheduling_weight: parent.scheduling_weight,
+            priority: parent.priority,
+            capabilities: Capabilities::bounded_by(&parent.capabilities),
+            plugins: parent.plugins.clone(),
+            wasi_args: None,
+            wasi_envs: None,
+            wasi_stdin: parent.wasi_stdin.clone(),
+            wasi_stdout: parent.wasi_stdout.clone(),
+            wasi_stderr: parent.wasi_stderr.clone(),
+            preopened_dirs: Vec::new(),
+            virtual_mounts: Vec::new(),
+            cache_dir: parent.cache_dir.clone(),
+        })
     }
 
     pub fn max_memory(&self) -> usize {
@@ -36,13 +168,221 @@ impl EnvConfig {
         self.max_fuel
     }
 
-    pub fn allowed_namespace(&self) -> &[String] {
-        &self.allowed_namespaces
+    pub fn max_cpu_time(&self) -> Option<Duration> {
+        self.max_cpu_time
+    }
+
+    /// Set the maximum CPU time a process can use. Processes exceeding it are interrupted and
+    /// terminated with the [`ExitReason::OutOfCpuTime`](crate::ExitReason::OutOfCpuTime) reason.
+    ///
+    /// The limit is checked periodically, so a process can overrun it by a few milliseconds.
+    pub fn set_max_cpu_time(&mut self, max_cpu_time: Option<Duration>) {
+        self.max_cpu_time = max_cpu_time;
+    }
+
+    pub fn max_runtime(&self) -> Option<Duration> {
+        self.max_runtime
+    }
+
+    /// Set the maximum wall-clock time a process can run for, measured from the moment it was
+    /// spawned. Processes still running after it are killed, even if they are waiting on a host
+    /// function, and linked processes are notified with the
+    /// [`ExitReason::DeadlineExceeded`](crate::ExitReason::DeadlineExceeded) reason.
+    pub fn set_max_runtime(&mut self, max_runtime: Option<Duration>) {
+        self.max_runtime = max_runtime;
+    }
+
+    pub fn max_table_elements(&self) -> u32 {
+        self.max_table_elements
+    }
+
+    /// Set the maximum number of elements a single table can grow to. The limit is inclusive, a
+    /// table can hold exactly `max_table_elements` elements. Defaults to 9 999.
+    pub fn set_max_table_elements(&mut self, max_table_elements: u32) {
+        self.max_table_elements = max_table_elements;
+    }
+
+    pub fn max_instances(&self) -> usize {
+        self.max_instances
+    }
+
+    /// Set the maximum number of Wasm instances a process can create. Defaults to 1.
+    pub fn set_max_instances(&mut self, max_instances: usize) {
+        self.max_instances = max_instances;
+    }
+
+    pub fn max_tables(&self) -> usize {
+        self.max_tables
+    }
+
+    /// Set the maximum number of tables a process can define. Defaults to 1.
+    pub fn set_max_tables(&mut self, max_tables: usize) {
+        self.max_tables = max_tables;
+    }
+
+    pub fn max_memories(&self) -> usize {
+        self.max_memories
+    }
+
+    /// Set the maximum number of memories a process can define. Defaults to 1.
+    ///
+    /// `max_memory` limits the size of all memories of a process together.
+    pub fn set_max_memories(&mut self, max_memories: usize) {
+        self.max_memories = max_memories;
+    }
+
+    pub fn instance_pool_size(&self) -> Option<u32> {
+        self.instance_pool_size
+    }
+
+    /// Allocate the instances of up to `size` processes from a pool that is reserved when the
+    /// environment is created. Spawning processes from the pool is faster than allocating their
+    /// resources on demand. Once the pool is exhausted, or if a module exceeds the pool's limits,
+    /// processes fall back to on demand allocation.
+    ///
+    /// Each process in the pool reserves address space for `max_memories` memories of
+    /// `max_memory` size (up to 4 GB each), so `max_memory` should be kept as small as possible.
+    pub fn set_instance_pool_size(&mut self, size: Option<u32>) {
+        self.instance_pool_size = size;
+    }
+
+    pub fn max_total_memory(&self) -> Option<usize> {
+        self.max_total_memory
+    }
+
+    /// Set the maximum amount of memory in bytes used by all processes in the environment
+    /// together.
+    pub fn set_max_total_memory(&mut self, max_total_memory: Option<usize>) {
+        self.max_total_memory = max_total_memory;
+    }
+
+    pub fn max_processes(&self) -> Option<usize> {
+        self.max_processes
+    }
+
+    /// Set the maximum number of processes that can be alive in the environment at the same time.
+    pub fn set_max_processes(&mut self, max_processes: Option<usize>) {
+        self.max_processes = max_processes;
+    }
+
+    pub fn max_fuel_per_window(&self) -> Option<(u64, Duration)> {
+        self.max_fuel_per_window
+    }
+
+    /// Set the maximum amount of fuel that all processes in the environment can consume together
+    /// inside a time window.
+    ///
+    /// Fuel is expressed in the same units as `max_fuel`. Once the budget is used up, processes
+    /// are suspended until the window resets.
+    pub fn set_max_fuel_per_window(&mut self, max_fuel_per_window: Option<(u64, Duration)>) {
+        self.max_fuel_per_window = max_fuel_per_window;
+    }
+
+    pub fn max_tcp_streams(&self) -> Option<usize> {
+        self.max_tcp_streams
+    }
+
+    /// Set the maximum number of TCP streams that can be held by all processes in the environment
+    /// together.
+    ///
+    /// Only opening new streams (`tcp_accept` & `tcp_connect`) is refused when the limit is
+    /// reached. Streams received in messages or cloned are counted, but never refused.
+    pub fn set_max_tcp_streams(&mut self, max_tcp_streams: Option<usize>) {
+        self.max_tcp_streams = max_tcp_streams;
+    }
+
+    pub fn max_disk_usage(&self) -> Option<usize> {
+        self.max_disk_usage
+    }
+
+    /// Set the maximum number of bytes that all processes in the environment can write into
+    /// preopened directories together.
+    ///
+    /// Only growth caused by the processes is counted, files that already exist don't use up the
+    /// quota. Deleting files releases their size again, truncating or overwriting them doesn't.
+    /// Writes that would exceed the quota fail with a "no space left" error.
+    pub fn set_max_disk_usage(&mut self, max_disk_usage: Option<usize>) {
+        self.max_disk_usage = max_disk_usage;
     }
 
-    /// Allow a WebAssembly host function namespace to be used with this config.
-    pub fn allow_namespace<S: Into<String>>(&mut self, namespace: S) {
-        self.allowed_namespaces.push(namespace.into())
+    pub fn deterministic_seed(&self) -> Option<u64> {
+        self.deterministic_seed
+    }
+
+    /// Make clocks, randomness, sleeps and receive timeouts of processes deterministic, driven by
+    /// `seed` instead of the host (see the `virtual_time` module).
+    ///
+    /// Environments created from inside of processes inherit the seed.
+    pub fn set_deterministic_seed(&mut self, seed: Option<u64>) {
+        self.deterministic_seed = seed;
+    }
+
+    pub fn time_slice(&self) -> u64 {
+        self.time_slice
+    }
+
+    /// Set the number of instructions a process can execute before it yields and lets other
+    /// processes run.
+    ///
+    /// Smaller time slices lower the latency of other processes, but add more scheduling
+    /// overhead. Defaults to `UNIT_OF_COMPUTE_IN_INSTRUCTIONS`.
+    pub fn set_time_slice(&mut self, instructions: u64) {
+        self.time_slice = instructions.max(1);
+    }
+
+    pub fn scheduling_weight(&self) -> u32 {
+        self.scheduling_weight
+    }
+
+    /// Set the share of CPU time processes in the environment get, relative to other environments
+    /// with the same priority. An environment with a weight of 200 gets twice as much CPU time as
+    /// one with the default weight of 100, if both are busy.
+    ///
+    /// Has no effect on environments created from inside of a process, they share the scheduling
+    /// group of the parent environment.
+    pub fn set_scheduling_weight(&mut self, weight: u32) {
+        self.scheduling_weight = weight.max(1);
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Set the priority of processes in the environment. Processes waiting to run are always
+    /// scheduled before processes of environments with a lower priority.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Allow host functions matching the rule to be used with this config.
+    ///
+    /// Rules can match a single function (`lunatic::process::spawn`) or a whole namespace
+    /// (`lunatic::process::*`). See the [`capability`](crate::capability) module for details.
+    pub fn allow_namespace(&mut self, rule: &str) -> Result<()> {
+        self.capabilities.allow(rule)
+    }
+
+    /// Deny host functions matching the rule, even if they are allowed by another rule.
+    pub fn deny_namespace(&mut self, rule: &str) -> Result<()> {
+        self.capabilities.deny(rule)
+    }
+
+    /// Allow processes to use the group of WASI functions belonging to the `capability`.
+    ///
+    /// Environments created with [`EnvConfig::new`] don't allow any WASI functions, capabilities
+    /// can be granted one by one.
+    pub fn allow_wasi(&mut self, capability: WasiCapability) {
+        self.capabilities.allow_wasi(capability)
+    }
+
+    /// Deny the group of WASI functions belonging to the `capability`, even if they are allowed by
+    /// another rule.
+    pub fn deny_wasi(&mut self, capability: WasiCapability) {
+        self.capabilities.deny_wasi(capability)
     }
 
     pub fn plugins(&self) -> &Vec<Plugin> {
@@ -75,20 +415,165 @@ impl EnvConfig {
     pub fn wasi_envs(&self) -> &Option<Vec<(String, String)>> {
         &self.wasi_envs
     }
+
+    pub fn wasi_stdin(&self) -> &WasiStdin {
+        &self.wasi_stdin
+    }
+
+    /// Set where the standard input of processes reads from. Defaults to the host's stdin.
+    pub fn set_wasi_stdin(&mut self, stdin: WasiStdin) {
+        self.wasi_stdin = stdin;
+    }
+
+    pub fn wasi_stdout(&self) -> &WasiOutput {
+        &self.wasi_stdout
+    }
+
+    /// Set where the standard output of processes is written to. Defaults to the host's stdout.
+    pub fn set_wasi_stdout(&mut self, stdout: WasiOutput) {
+        self.wasi_stdout = stdout;
+    }
+
+    pub fn wasi_stderr(&self) -> &WasiOutput {
+        &self.wasi_stderr
+    }
+
+    /// Set where the standard error of processes is written to. Defaults to the host's stderr.
+    pub fn set_wasi_stderr(&mut self, stderr: WasiOutput) {
+        self.wasi_stderr = stderr;
+    }
+
+    pub fn preopened_dirs(&self) -> &[PreopenedDir] {
+        &self.preopened_dirs
+    }
+
+    /// Give processes access to the directory `host_path` under `guest_path`, e.g. `/data`.
+    ///
+    /// Processes can't access anything outside of the directory. If `read_only` is set, they can
+    /// only list and read files. Fails if `host_path` is not a directory.
+    pub fn preopen_dir(
+        &mut self,
+        host_path: impl Into<PathBuf>,
+        guest_path: impl Into<String>,
+        read_only: bool,
+    ) -> Result<()> {
+        let host_path = host_path.into();
+        if !host_path.is_dir() {
+            return Err(anyhow!("{} is not a directory", host_path.display()));
+        }
+        self.preopened_dirs.push(PreopenedDir {
+            host_path,
+            guest_path: guest_path.into(),
+            read_only,
+        });
+        Ok(())
+    }
+
+    pub fn virtual_mounts(&self) -> &[VirtualMount] {
+        &self.virtual_mounts
+    }
+
+    /// Mount an in-memory file system into processes under `guest_path`.
+    ///
+    /// If `shared` is set, all processes work on the same file system. Otherwise each process gets
+    /// its own copy of the content at the time it's spawned. Virtual file systems are mounted after
+    /// all host directories.
+    pub fn mount_virtual_fs(&mut self, fs: VirtualFs, guest_path: impl Into<String>, shared: bool) {
+        self.virtual_mounts.push(VirtualMount {
+            fs,
+            guest_path: guest_path.into(),
+            shared,
+        });
+    }
+
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+
+    /// Set the directory in which compiled modules are cached, so that they don't need to be
+    /// compiled again the next time they are loaded. The directory is created if it doesn't exist.
+    ///
+    /// Cached artifacts contain native code, the directory must not be writable by untrusted
+    /// users.
+    pub fn set_cache_dir(&mut self, cache_dir: Option<PathBuf>) {
+        self.cache_dir = cache_dir;
+    }
 }
 
 impl Default for EnvConfig {
     fn default() -> Self {
+        let mut capabilities = Capabilities::new();
+        capabilities
+            .allow("lunatic::*")
+            .expect("valid capability rule");
+        capabilities
+            .allow("wasi_snapshot_preview1::*")
+            .expect("valid capability rule");
         Self {
             max_memory: 0xA00000000, // = 4 GB in bytes
             max_fuel: None,
-            allowed_namespaces: vec![
-                String::from("lunatic::"),
-                String::from("wasi_snapshot_preview1::"),
-            ],
+            max_cpu_time: None,
+            max_runtime: None,
+            max_table_elements: 9_999,
+            max_instances: 1,
+            max_tables: 1,
+            max_memories: 1,
+            instance_pool_size: None,
+            max_total_memory: None,
+            max_processes: None,
+            max_fuel_per_window: None,
+            max_tcp_streams: None,
+            max_disk_usage: None,
+            deterministic_seed: None,
+            time_slice: UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            scheduling_weight: DEFAULT_WEIGHT,
+            priority: Priority::default(),
+            capabilities,
             plugins: vec![],
             wasi_args: None,
             wasi_envs: None,
+            wasi_stdin: WasiStdin::default(),
+            wasi_stdout: WasiOutput::default(),
+            wasi_stderr: WasiOutput::default(),
+            preopened_dirs: Vec::new(),
+            virtual_mounts: Vec::new(),
+            cache_dir: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EnvConfig;
+    use crate::stdio::{WasiOutput, WasiStdin};
+
+    #[test]
+    fn bounded_config() {
+        let mut parent = EnvConfig::new(1024, Some(10));
+        parent.allow_namespace("lunatic::process::*").unwrap();
+
+        // Limits can't exceed the parent's
+        assert!(EnvConfig::new_bounded(&parent, 2048, Some(10)).is_err());
+        assert!(EnvConfig::new_bounded(&parent, 1024, Some(11)).is_err());
+        // Unlimited fuel inherits the parent's limit
+        let child = EnvConfig::new_bounded(&parent, 512, None).unwrap();
+        assert_eq!(child.max_fuel(), Some(10));
+
+        // Namespaces not allowed by the parent stay forbidden
+        let mut child = EnvConfig::new_bounded(&parent, 512, Some(5)).unwrap();
+        child.allow_namespace("lunatic::").unwrap();
+        assert!(child.capabilities().is_allowed("lunatic::process", "spawn"));
+        assert!(!child
+            .capabilities()
+            .is_allowed("lunatic::networking", "tcp_bind"));
+
+        // Standard streams are inherited, so children can't escape a redirected output
+        parent.set_wasi_stdin(WasiStdin::Null);
+        parent.set_wasi_stdout(WasiOutput::Null);
+        parent.set_wasi_stderr(WasiOutput::Log);
+        let child = EnvConfig::new_bounded(&parent, 512, None).unwrap();
+        assert!(matches!(child.wasi_stdin(), WasiStdin::Null));
+        assert!(matches!(child.wasi_stdout(), WasiOutput::Null));
+        assert!(matches!(child.wasi_stderr(), WasiOutput::Log));
+    }
+}
@@ -1,69 +1,117 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use anyhow::{anyhow, Result};
 use lazy_static::lazy_static;
-use wasmtime::{Config, Engine, InstanceAllocationStrategy, Linker, OptLevel, ProfilingStrategy};
+use wasmparser::{ImportSectionEntryType, MemoryType, Parser, Payload};
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, InstanceLimits, Linker, ModuleLimits, OptLevel,
+    PoolingAllocationStrategy, ProfilingStrategy,
+};
 
 use super::config::EnvConfig;
 use crate::{
-    api, module::Module, plugin::patch_module, registry::LocalRegistry, state::ProcessState,
+    api,
+    cache::{shared_module, ModuleCache},
+    module::Module,
+    plugin::patch_module,
+    quota::EnvQuota,
+    registry::LocalRegistry,
+    scheduler::SchedulingGroup,
+    state::ProcessState,
+    virtual_time::Clock,
 };
 
 // One unit of fuel represents around 100k instructions.
 pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 
+const WASM_PAGE_SIZE: u64 = 0x10000;
+
+// Precompiled modules start with this header, followed by the length of the original module, the
+// original module and the serialized Wasmtime module.
+const PRECOMPILED_HEADER: &[u8] = b"\0lunatic-cwasm";
+
 /// The environment represents a set of characteristics that processes spawned from it will have.
 ///
 /// Environments let us set limits on processes:
 /// * Memory limits
 /// * Compute limits
-/// * Access to host functions
+/// * Quotas shared by all processes
+/// * Access to host functions (see [`Capabilities`](crate::capability::Capabilities))
 ///
 /// They also define the set of plugins. Plugins can be used to modify loaded Wasm modules.
 /// Plugins are WIP and not well documented.
 #[derive(Clone)]
 pub struct Environment {
-    engine: Engine,
+    engine: Arc<Engine>,
     linker: Linker<ProcessState>,
+    instance_pool: Option<Arc<InstancePool>>,
     config: EnvConfig,
     registry: LocalRegistry,
+    quota: Arc<EnvQuota>,
+    scheduling_group: Arc<SchedulingGroup>,
+    clock: Clock,
 }
 
 impl Environment {
     /// Create a new environment from a configuration.
     pub fn new(config: EnvConfig) -> Result<Self> {
-        let mut wasmtime_config = Config::new();
-        wasmtime_config
-            .async_support(true)
-            .debug_info(false)
-            // The behaviour of fuel running out is defined on the Store
-            .consume_fuel(true)
-            .wasm_reference_types(true)
-            .wasm_bulk_memory(true)
-            .wasm_multi_value(true)
-            .wasm_multi_memory(true)
-            .wasm_module_linking(false)
-            // Disable profiler
-            .profiler(ProfilingStrategy::None)?
-            .cranelift_opt_level(OptLevel::SpeedAndSize)
+        Self::new_with_parent(config, None)
+    }
+
+    // Create a new environment from inside a process. Resources used by it count against the
+    // quotas of the `parent` environment and it shares the parent's scheduling group.
+    pub(crate) fn new_child(config: EnvConfig, parent: &Environment) -> Result<Self> {
+        Self::new_with_parent(config, Some(parent))
+    }
+
+    fn new_with_parent(config: EnvConfig, parent: Option<&Environment>) -> Result<Self> {
+        let (engine, instance_pool) = match config.instance_pool_size() {
+            Some(size) => {
+                let engine = Arc::new(create_engine(&config, Some(size))?);
+                let fallback_engine = shared_engine(&config)?;
+                let fallback_linker = create_linker(&fallback_engine, &config)?;
+                let pool = InstancePool {
+                    size,
+                    module_limits: module_limits(&config),
+                    used: AtomicU32::new(0),
+                    fallback_engine,
+                    fallback_linker,
+                };
+                (engine, Some(Arc::new(pool)))
+            }
             // Allocate resources on demand because we can't predict how many process will exist
-            .allocation_strategy(InstanceAllocationStrategy::OnDemand)
-            // Memories are always static (can't be bigger than max_memory)
-            .static_memory_maximum_size(config.max_memory() as u64)
-            // Set memory guards to 4 Mb
-            .static_memory_guard_size(0x400000)
-            .dynamic_memory_guard_size(0x400000);
-        let engine = Engine::new(&wasmtime_config)?;
-        let mut linker = Linker::new(&engine);
-        // Allow plugins to shadow host functions
-        linker.allow_shadowing(true);
-
-        // Register host functions for linker
-        api::register(&mut linker, config.allowed_namespace())?;
+            None => (shared_engine(&config)?, None),
+        };
+        let linker = create_linker(&engine, &config)?;
 
+        let quota = Arc::new(EnvQuota::new(
+            &config,
+            parent.map(|parent| parent.quota.clone()),
+        ));
+        let scheduling_group = match parent {
+            Some(parent) => parent.scheduling_group.clone(),
+            None => Arc::new(SchedulingGroup::new(
+                config.scheduling_weight(),
+                config.priority(),
+            )),
+        };
+        let clock = Clock::new(config.deterministic_seed());
         Ok(Self {
             engine,
             linker,
+            instance_pool,
             config,
             registry: LocalRegistry::new(),
+            quota,
+            scheduling_group,
+            clock,
         })
     }
 
@@ -74,18 +122,123 @@ impl Environment {
     /// compiled by `Wasmtime`.
     pub async fn create_module(&self, data: Vec<u8>) -> Result<Module> {
         let env = self.clone();
-        let new_module = patch_module(&data, self.config.plugins())?;
         // The compilation of a module is a CPU intensive tasks and can take some time.
-        let module = async_std::task::spawn_blocking(move || {
-            match wasmtime::Module::new(env.engine(), new_module.as_slice()) {
-                Ok(wasmtime_module) => Ok(Module::new(data, env, wasmtime_module)),
-                Err(err) => Err(err),
+        let module = async_std::task::spawn_blocking(move || -> Result<Module> {
+            let cache = env.config.cache_dir().map(ModuleCache::new);
+            let compile = |engine: &Engine, pooled: bool, new_module: &[u8]| match &cache {
+                Some(cache) => {
+                    cache.load_or_compile(engine, &data, &env.config, pooled, new_module)
+                }
+                None => wasmtime::Module::new(engine, new_module),
+            };
+            match &env.instance_pool {
+                // Engines without an instance pool are shared, so the module can be shared with
+                // other environments too.
+                None => {
+                    let wasmtime_module = shared_module(&data, &env.config, || {
+                        // Plugins don't need to run if the module was compiled by a previous run
+                        if let Some(cache) = &cache {
+                            if let Some(module) =
+                                cache.load(env.engine(), &data, &env.config, false)
+                            {
+                                return Ok(module);
+                            }
+                        }
+                        let new_module = patch_module(&data, env.config.plugins())?;
+                        compile(env.engine(), false, &new_module)
+                    })?;
+                    Ok(Module::new(data, env, wasmtime_module, None))
+                }
+                Some(pool) => {
+                    let new_module = patch_module(&data, env.config.plugins())?;
+                    if fits_module_limits(&new_module, &pool.module_limits)? {
+                        // Keep the patched module around in case the instance pool gets exhausted
+                        let wasmtime_module = compile(env.engine(), true, &new_module)?;
+                        Ok(Module::new(
+                            data,
+                            env,
+                            Arc::new(wasmtime_module),
+                            Some(new_module),
+                        ))
+                    } else {
+                        // The module doesn't fit into the limits of the instance pool
+                        let wasmtime_module = shared_module(&data, &env.config, || {
+                            compile(&pool.fallback_engine, false, &new_module)
+                        })?;
+                        Ok(Module::new(data, env, wasmtime_module, None))
+                    }
+                }
             }
         })
         .await?;
         Ok(module)
     }
 
+    /// Apply the plugins of this environment to a module and compile it ahead of time.
+    ///
+    /// The returned artifact can be loaded with
+    /// [`create_module_from_precompiled`](Environment::create_module_from_precompiled) by an
+    /// environment with the same configuration, without compiling the module again. It also
+    /// contains the original module, so that processes can add it to other environments.
+    pub async fn precompile_module(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        let engine = self.engine.clone();
+        let new_module = patch_module(&data, self.config.plugins())?;
+        let compiled = async_std::task::spawn_blocking(move || {
+            engine.precompile_module(new_module.as_slice())
+        })
+        .await?;
+        let mut artifact =
+            Vec::with_capacity(PRECOMPILED_HEADER.len() + 8 + data.len() + compiled.len());
+        artifact.extend_from_slice(PRECOMPILED_HEADER);
+        artifact.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        artifact.extend_from_slice(&data);
+        artifact.extend_from_slice(&compiled);
+        Ok(artifact)
+    }
+
+    /// Create a module from an artifact produced by
+    /// [`precompile_module`](Environment::precompile_module).
+    ///
+    /// Fails if the artifact was compiled for an environment with an incompatible configuration
+    /// or by a different version of the runtime.
+    ///
+    /// # Safety
+    ///
+    /// The artifact contains native code that is executed without any validation. It must come
+    /// from a trusted source.
+    pub unsafe fn create_module_from_precompiled(&self, artifact: &[u8]) -> Result<Module> {
+        let rest = artifact
+            .strip_prefix(PRECOMPILED_HEADER)
+            .ok_or_else(|| anyhow!("Not a precompiled lunatic module"))?;
+        if rest.len() < 8 {
+            return Err(anyhow!("Precompiled module is truncated"));
+        }
+        let (length, rest) = rest.split_at(8);
+        let length = u64::from_le_bytes(length.try_into().unwrap()) as usize;
+        if rest.len() < length {
+            return Err(anyhow!("Precompiled module is truncated"));
+        }
+        let (data, compiled) = rest.split_at(length);
+        let wasmtime_module = wasmtime::Module::deserialize(&self.engine, compiled)?;
+        // The patched module is needed to compile a fallback once the instance pool is exhausted
+        let patched_module = match self.instance_pool {
+            Some(_) => Some(patch_module(data, self.config.plugins())?),
+            None => None,
+        };
+        Ok(Module::new(
+            data.to_vec(),
+            self.clone(),
+            Arc::new(wasmtime_module),
+            patched_module,
+        ))
+    }
+
+    /// Returns true if `data` is an artifact produced by
+    /// [`precompile_module`](Environment::precompile_module).
+    pub fn is_precompiled(data: &[u8]) -> bool {
+        data.starts_with(PRECOMPILED_HEADER)
+    }
+
     pub fn engine(&self) -> &Engine {
         &self.engine
     }
@@ -101,6 +254,226 @@ impl Environment {
     pub fn registry(&self) -> &LocalRegistry {
         &self.registry
     }
+
+    pub(crate) fn instance_pool(&self) -> Option<&Arc<InstancePool>> {
+        self.instance_pool.as_ref()
+    }
+
+    pub(crate) fn quota(&self) -> &Arc<EnvQuota> {
+        &self.quota
+    }
+
+    pub(crate) fn scheduling_group(&self) -> &Arc<SchedulingGroup> {
+        &self.scheduling_group
+    }
+
+    pub(crate) fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    // Returns true if both values are handles to the same environment.
+    pub(crate) fn same_as(&self, other: &Environment) -> bool {
+        Arc::ptr_eq(&self.quota, &other.quota)
+    }
+}
+
+lazy_static! {
+    // Engines without an instance pool, shared by all environments with the same code generation
+    // settings. Modules can only be shared between environments that use the same engine. Guests
+    // can pick the memory limit freely, so engines are forgotten once no environment uses them.
+    static ref SHARED_ENGINES: Mutex<HashMap<EngineKey, Weak<Engine>>> = Mutex::new(HashMap::new());
+}
+
+// Settings of an engine that influence code generation.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct EngineKey {
+    interruptable: bool,
+    memory_pages: u64,
+}
+
+impl EngineKey {
+    fn new(config: &EnvConfig) -> Self {
+        Self {
+            // Required to stop processes that exceed their CPU time
+            interruptable: config.max_cpu_time().is_some(),
+            // Wasm memories can't be bigger than 4 GB
+            memory_pages: (config.max_memory() as u64 / WASM_PAGE_SIZE).min(0x10000),
+        }
+    }
+}
+
+// Returns an engine that allocates instances on demand, creating it if no environment with the
+// same settings exists yet.
+fn shared_engine(config: &EnvConfig) -> Result<Arc<Engine>> {
+    let key = EngineKey::new(config);
+    let mut engines = SHARED_ENGINES.lock().unwrap();
+    if let Some(engine) = engines.get(&key).and_then(Weak::upgrade) {
+        return Ok(engine);
+    }
+    // Forget engines that are not used anymore
+    engines.retain(|_, engine| engine.strong_count() > 0);
+    let engine = Arc::new(create_engine(config, None)?);
+    engines.insert(key, Arc::downgrade(&engine));
+    Ok(engine)
+}
+
+// Creates an engine. If `pool_size` is specified, instances are allocated from a pool that can hold
+// this many processes.
+fn create_engine(config: &EnvConfig, pool_size: Option<u32>) -> Result<Engine> {
+    let key = EngineKey::new(config);
+    let mut wasmtime_config = Config::new();
+    wasmtime_config
+        .async_support(true)
+        .debug_info(false)
+        // The behaviour of fuel running out is defined on the Store
+        .consume_fuel(true)
+        .interruptable(key.interruptable)
+        .wasm_reference_types(true)
+        .wasm_bulk_memory(true)
+        .wasm_multi_value(true)
+        .wasm_multi_memory(true)
+        .wasm_module_linking(false)
+        // Disable profiler
+        .profiler(ProfilingStrategy::None)?
+        .cranelift_opt_level(OptLevel::SpeedAndSize)
+        // Memories are always static (can't be bigger than max_memory)
+        .static_memory_maximum_size(key.memory_pages * WASM_PAGE_SIZE)
+        // Set memory guards to 4 Mb
+        .static_memory_guard_size(0x400000)
+        .dynamic_memory_guard_size(0x400000);
+    match pool_size {
+        Some(pool_size) => {
+            let module_limits = module_limits(config);
+            let instance_limits = InstanceLimits {
+                count: pool_size.saturating_mul(config.max_instances() as u32),
+            };
+            // Each memory in the pool reserves address space for its maximum size
+            wasmtime_config.allocation_strategy(InstanceAllocationStrategy::Pooling {
+                strategy: PoolingAllocationStrategy::NextAvailable,
+                module_limits,
+                instance_limits,
+            });
+        }
+        None => {
+            wasmtime_config.allocation_strategy(InstanceAllocationStrategy::OnDemand);
+        }
+    }
+    Engine::new(&wasmtime_config)
+}
+
+// Limits of modules that can be instantiated from the instance pool.
+fn module_limits(config: &EnvConfig) -> ModuleLimits {
+    ModuleLimits {
+        memories: config.max_memories() as u32,
+        tables: config.max_tables() as u32,
+        table_elements: config.max_table_elements(),
+        memory_pages: EngineKey::new(config).memory_pages,
+        ..ModuleLimits::default()
+    }
+}
+
+// Returns true if the module fits into the limits of an instance pool. These are the same checks
+// Wasmtime performs when compiling a module for the pooling allocator, done up front so that other
+// compilation errors are not mistaken for modules that need the fallback engine.
+fn fits_module_limits(data: &[u8], limits: &ModuleLimits) -> Result<bool> {
+    let (mut imported_functions, mut imported_tables) = (0, 0);
+    let (mut imported_memories, mut imported_globals) = (0, 0);
+    let (mut types, mut functions, mut globals) = (0, 0, 0);
+    let (mut tables, mut memories) = (Vec::new(), Vec::new());
+    for payload in Parser::new(0).parse_all(data) {
+        match payload? {
+            Payload::TypeSection(section) => types += section.get_count(),
+            Payload::ImportSection(imports) => {
+                for import in imports {
+                    match import?.ty {
+                        ImportSectionEntryType::Function(_) => imported_functions += 1,
+                        ImportSectionEntryType::Table(_) => imported_tables += 1,
+                        ImportSectionEntryType::Memory(_) => imported_memories += 1,
+                        ImportSectionEntryType::Global(_) => imported_globals += 1,
+                        _ => {}
+                    }
+                }
+            }
+            Payload::FunctionSection(section) => functions += section.get_count(),
+            Payload::TableSection(section) => {
+                for table in section {
+                    tables.push(table?.limits.initial);
+                }
+            }
+            Payload::MemorySection(section) => {
+                for memory in section {
+                    memories.push(match memory? {
+                        MemoryType::M32 { limits, .. } => limits.initial as u64,
+                        MemoryType::M64 { limits, .. } => limits.initial,
+                    });
+                }
+            }
+            Payload::GlobalSection(section) => globals += section.get_count(),
+            _ => {}
+        }
+    }
+    Ok(imported_functions <= limits.imported_functions
+        && imported_tables <= limits.imported_tables
+        && imported_memories <= limits.imported_memories
+        && imported_globals <= limits.imported_globals
+        && types <= limits.types
+        && functions <= limits.functions
+        && tables.len() <= limits.tables as usize
+        && memories.len() <= limits.memories as usize
+        && globals <= limits.globals
+        && tables
+            .iter()
+            .all(|&initial| initial <= limits.table_elements)
+        && memories
+            .iter()
+            .all(|&initial| initial <= limits.memory_pages))
+}
+
+// Registers all host functions allowed by the environment's capabilities with a linker.
+fn create_linker(engine: &Engine, config: &EnvConfig) -> Result<Linker<ProcessState>> {
+    let mut linker = Linker::new(engine);
+    // Allow plugins to shadow host functions
+    linker.allow_shadowing(true);
+    api::register(&mut linker, config.capabilities())?;
+    Ok(linker)
+}
+
+// Instances of processes spawned into environments using the pooling allocator are allocated from
+// a fixed size pool. Once all slots are taken, processes are instantiated with a fallback engine
+// that allocates resources on demand.
+pub(crate) struct InstancePool {
+    size: u32,
+    module_limits: ModuleLimits,
+    used: AtomicU32,
+    pub(crate) fallback_engine: Arc<Engine>,
+    pub(crate) fallback_linker: Linker<ProcessState>,
+}
+
+impl InstancePool {
+    // Reserves a slot for one process. Returns `None` if the pool is exhausted.
+    pub(crate) fn reserve(self: &Arc<Self>) -> Option<PoolSlot> {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                if used < self.size {
+                    Some(used + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(PoolSlot { pool: self.clone() })
+    }
+}
+
+// A reserved slot in the instance pool. It must outlive the store using it.
+pub(crate) struct PoolSlot {
+    pool: Arc<InstancePool>,
+}
+
+impl Drop for PoolSlot {
+    fn drop(&mut self) {
+        self.pool.used.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 // All plugins share one environment
@@ -114,3 +487,56 @@ lazy_static! {
         PluginEnv { engine }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{fits_module_limits, module_limits, EngineKey, Environment, SHARED_ENGINES};
+    use crate::EnvConfig;
+
+    #[test]
+    fn shared_engines_are_released() {
+        // A memory limit no other test uses
+        let config = EnvConfig::new(0x130000, None);
+        let key = EngineKey::new(&config);
+        let first = Environment::new(config.clone()).unwrap();
+        let second = Environment::new(config.clone()).unwrap();
+        assert!(Arc::ptr_eq(&first.engine, &second.engine));
+
+        let engine = Arc::downgrade(&first.engine);
+        drop(first);
+        drop(second);
+        assert!(engine.upgrade().is_none());
+        // Unused engines are forgotten once the next one is created
+        Environment::new(EnvConfig::new(0x140000, None)).unwrap();
+        assert!(!SHARED_ENGINES.lock().unwrap().contains_key(&key));
+    }
+
+    #[async_std::test]
+    async fn pool_limits() {
+        // 16 pages of memory
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_instance_pool_size(Some(1));
+        let limits = module_limits(&config);
+        let fits = |wat: &str| fits_module_limits(&wat::parse_str(wat).unwrap(), &limits).unwrap();
+        assert!(fits("(module (memory 16) (table 10 funcref))"));
+        assert!(!fits("(module (memory 17))"));
+        assert!(!fits("(module (memory 1) (memory 1))"));
+        assert!(!fits("(module (table 10000 funcref))"));
+        assert!(fits(&format!(
+            "(module {})",
+            "(global i32 (i32.const 0))".repeat(10)
+        )));
+        assert!(!fits(&format!(
+            "(module {})",
+            "(global i32 (i32.const 0))".repeat(11)
+        )));
+
+        // Invalid modules that fit into the pool fail instead of falling back to on demand allocation
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str("(module (func (result i32)))").unwrap();
+        let error = environment.create_module(raw_module).await.err().unwrap();
+        assert!(!error.to_string().contains("exceeds the limit"));
+    }
+}
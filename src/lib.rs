@@ -21,6 +21,12 @@ Wasm modules, checkout out the [lunatic crate](https://crates.io/crates/lunatic)
   [`Environment`] is created with an [`EnvConfig`] to tweak various settings, like maximum
   memory and compute usage.
 
+* [`Capabilities`](capability::Capabilities) - define which host functions processes inside
+  an [`Environment`] can use.
+
+* [`scheduler`] - makes sure that CPU time is fairly shared between environments, according to
+  their weights and priorities.
+
 * [`WasmProcess`](process::WasmProcess) - a handle to send signals and messages to spawned
   Wasm processes. It implements the [`Process`](process::Process) trait.
 
@@ -34,6 +40,8 @@ TODO
 */
 
 pub(crate) mod api;
+pub(crate) mod cache;
+pub mod capability;
 mod config;
 mod environment;
 pub(crate) mod mailbox;
@@ -41,10 +49,19 @@ pub mod message;
 pub(crate) mod module;
 // pub mod node;
 pub mod plugin;
+pub mod preopen;
 pub(crate) mod process;
+pub(crate) mod quota;
 pub mod registry;
+pub mod scheduler;
+pub mod snapshot;
 pub(crate) mod state;
+pub mod stdio;
+pub mod vfs;
+pub(crate) mod virtual_time;
+pub(crate) mod wasi_socket;
 
 pub use config::EnvConfig;
 pub use environment::Environment;
-pub use process::{spawn, Finished, Process, Signal, WasmProcess};
+pub use module::SpawnOptions;
+pub use process::{spawn, ExitReason, Finished, Process, Signal, WasmProcess};
@@ -125,6 +125,26 @@ impl MessageMailbox {
         // Otherwise put message into queue
         mailbox.messages.push_back(message);
     }
+
+    // Takes all messages out of the mailbox if every one of them matches the `predicate`.
+    pub(crate) fn take_all_if(&self, predicate: impl Fn(&Message) -> bool) -> Option<Vec<Message>> {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        let all_match = mailbox
+            .found
+            .iter()
+            .chain(mailbox.messages.iter())
+            .all(predicate);
+        if !all_match {
+            return None;
+        }
+        let found = mailbox.found.take();
+        Some(
+            found
+                .into_iter()
+                .chain(mailbox.messages.drain(..))
+                .collect(),
+        )
+    }
 }
 
 impl Future for &MessageMailbox {
@@ -150,15 +170,16 @@ mod tests {
     };
 
     use super::{Message, MessageMailbox};
+    use crate::process::ExitReason;
 
     #[async_std::test]
     async fn no_tag_signal_message() {
         let mailbox = MessageMailbox::default();
-        let message = Message::Signal(None);
+        let message = Message::Signal(None, ExitReason::Failure);
         mailbox.push(message);
         let result = mailbox.pop(None).await;
         match result {
-            Message::Signal(None) => (),
+            Message::Signal(None, _) => (),
             _ => panic!("Wrong message received"),
         }
     }
@@ -167,7 +188,7 @@ mod tests {
     async fn tag_signal_message() {
         let mailbox = MessageMailbox::default();
         let tag = Some(1337);
-        let message = Message::Signal(tag);
+        let message = Message::Signal(tag, ExitReason::Failure);
         mailbox.push(message);
         let message = mailbox.pop(None).await;
         assert_eq!(message.tag(), tag);
@@ -181,11 +202,11 @@ mod tests {
         let tag3 = Some(3);
         let tag4 = Some(4);
         let tag5 = Some(5);
-        mailbox.push(Message::Signal(tag1));
-        mailbox.push(Message::Signal(tag2));
-        mailbox.push(Message::Signal(tag3));
-        mailbox.push(Message::Signal(tag4));
-        mailbox.push(Message::Signal(tag5));
+        mailbox.push(Message::Signal(tag1, ExitReason::Failure));
+        mailbox.push(Message::Signal(tag2, ExitReason::Failure));
+        mailbox.push(Message::Signal(tag3, ExitReason::Failure));
+        mailbox.push(Message::Signal(tag4, ExitReason::Failure));
+        mailbox.push(Message::Signal(tag5, ExitReason::Failure));
         let message = mailbox.pop(tag2).await;
         assert_eq!(message.tag(), tag2);
         let message = mailbox.pop(tag1).await;
@@ -226,7 +247,7 @@ mod tests {
         assert!(result.is_pending());
         assert_eq!(*waker_ref.0.lock().unwrap(), false);
         // Pushing a message to the mailbox will call the waker
-        mailbox.push(Message::Signal(tag));
+        mailbox.push(Message::Signal(tag, ExitReason::Failure));
         assert_eq!(*waker_ref.0.lock().unwrap(), true);
         // Next poll will return the value
         let result = fut.as_mut().poll(&mut context);
@@ -249,15 +270,15 @@ mod tests {
         assert!(result.is_pending());
         assert_eq!(*waker_ref.0.lock().unwrap(), false);
         // Pushing a message with the `None` tag should not trigger the waker
-        mailbox.push(Message::Signal(None));
+        mailbox.push(Message::Signal(None, ExitReason::Failure));
         assert_eq!(*waker_ref.0.lock().unwrap(), false);
         // Next poll will still not have the value with the tag 1337
         let result = fut.as_mut().poll(&mut context);
         assert!(result.is_pending());
         // Pushing another None in the meantime should not remove the waker
-        mailbox.push(Message::Signal(None));
+        mailbox.push(Message::Signal(None, ExitReason::Failure));
         // Pushing a message with tag 1337 should trigger the waker
-        mailbox.push(Message::Signal(Some(1337)));
+        mailbox.push(Message::Signal(Some(1337), ExitReason::Failure));
         assert_eq!(*waker_ref.0.lock().unwrap(), true);
         // Next poll will have the message ready
         let result = fut.as_mut().poll(&mut context);
@@ -279,7 +300,7 @@ mod tests {
         assert!(result.is_pending());
         assert_eq!(*waker_ref.0.lock().unwrap(), false);
         // Pushing a message with the `None` tag should call the waker()
-        mailbox.push(Message::Signal(None));
+        mailbox.push(Message::Signal(None, ExitReason::Failure));
         assert_eq!(*waker_ref.0.lock().unwrap(), true);
         // Dropping the future will cancel it
         drop(fut);
@@ -294,7 +315,7 @@ mod tests {
         let result = fut.poll(&mut context);
         match result {
             Poll::Ready(message) => match message {
-                Message::Signal(tag) => assert_eq!(tag, None),
+                Message::Signal(tag, _) => assert_eq!(tag, None),
                 _ => panic!("Unexpected message"),
             },
             _ => panic!("Unexpected message"),
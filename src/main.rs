@@ -1,8 +1,11 @@
-use std::{env, fs, path::Path};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use clap::{crate_version, App, Arg, ArgSettings};
+use clap::{crate_version, App, AppSettings, Arg, ArgMatches, ArgSettings};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use lunatic_runtime::{EnvConfig, Environment};
 
 #[async_std::main]
@@ -12,19 +15,42 @@ async fn main() -> Result<()> {
     // Parse command line arguments
     let args = App::new("lunatic")
         .version(crate_version!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
         .arg(
             Arg::new("plugin")
                 .short('P')
                 .long("plugin")
                 .value_name("PLUGIN")
                 .about("Adds plugin")
+                .global(true)
                 .setting(ArgSettings::MultipleOccurrences)
                 .setting(ArgSettings::TakesValue),
         )
+        .arg(
+            Arg::new("cache_dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .about("Caches compiled modules in this directory")
+                .setting(ArgSettings::TakesValue),
+        )
+        .arg(
+            Arg::new("dir")
+                .long("dir")
+                .value_name("HOST_DIR[::GUEST_DIR]")
+                .about("Grants access to a host directory, optionally under a different path")
+                .setting(ArgSettings::MultipleOccurrences)
+                .setting(ArgSettings::TakesValue),
+        )
+        .arg(
+            Arg::new("allow_precompiled")
+                .long("allow-precompiled")
+                .about("Allows running precompiled .cwasm files, only use it with trusted files"),
+        )
         .arg(
             Arg::new("wasm")
                 .value_name("WASM")
-                .about("Entry .wasm file")
+                .about("Entry .wasm or precompiled .cwasm file")
                 .required(true)
                 .index(1),
         )
@@ -36,8 +62,34 @@ async fn main() -> Result<()> {
                 .multiple_values(true)
                 .index(2),
         )
+        .subcommand(
+            App::new("compile")
+                .about("Applies plugins and compiles a .wasm file ahead of time")
+                .arg(
+                    Arg::new("input")
+                        .value_name("WASM")
+                        .about("The .wasm file to compile")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("CWASM")
+                        .about("Output file, defaults to the input file with a .cwasm extension")
+                        .setting(ArgSettings::TakesValue),
+                ),
+        )
         .get_matches();
 
+    match args.subcommand() {
+        Some(("compile", args)) => compile(args).await,
+        _ => run(&args).await,
+    }
+}
+
+async fn run(args: &ArgMatches) -> Result<()> {
     let mut config = EnvConfig::default();
 
     // Set correct command line arguments for the guest
@@ -51,21 +103,44 @@ async fn main() -> Result<()> {
     // Inherit environment variables
     config.set_wasi_envs(env::vars().collect());
 
-    // Add plugins passed through the --plugin or -P flags to the environment
-    if let Some(plugins) = args.values_of("plugin") {
-        for plugin in plugins {
-            let path = Path::new(plugin);
-            let module = fs::read(path)?;
-            config.add_plugin(module)?;
+    add_plugins(&mut config, args)?;
+
+    // Give the guest access to host directories
+    if let Some(dirs) = args.values_of("dir") {
+        for dir in dirs {
+            let (host_path, guest_path) = dir.split_once("::").unwrap_or((dir, dir));
+            config.preopen_dir(host_path, guest_path, false)?;
         }
     }
+
+    // Reuse modules compiled by previous runs
+    if let Some(cache_dir) = args.value_of("cache_dir") {
+        config.set_cache_dir(Some(PathBuf::from(cache_dir)));
+    }
     let env = Environment::new(config)?;
 
     // Spawn main process
     let path = args.value_of("wasm").unwrap();
     let path = Path::new(path);
     let module = fs::read(path)?;
-    let module = env.create_module(module).await?;
+    let module = if Environment::is_precompiled(&module) {
+        if !args.is_present("allow_precompiled") {
+            return Err(anyhow!(
+                "{} is a precompiled module, pass --allow-precompiled to run it",
+                path.to_string_lossy()
+            ));
+        }
+        // Safety: Precompiled modules contain machine code that is executed without validation.
+        // The user opted into loading them with `--allow-precompiled` and is responsible for
+        // only passing files produced by `lunatic compile`.
+        unsafe { env.create_module_from_precompiled(&module) }.context(format!(
+            "Failed to load precompiled module {}, it may have been compiled by a different \
+             version of lunatic",
+            path.to_string_lossy()
+        ))?
+    } else {
+        env.create_module(module).await?
+    };
     let (task, _) = module
         .spawn("_start", Vec::new(), None)
         .await
@@ -77,3 +152,35 @@ async fn main() -> Result<()> {
     task.await;
     Ok(())
 }
+
+async fn compile(args: &ArgMatches) -> Result<()> {
+    // Precompiled modules can only be loaded by environments with the same configuration
+    let mut config = EnvConfig::default();
+    add_plugins(&mut config, args)?;
+    let env = Environment::new(config)?;
+
+    let input = Path::new(args.value_of("input").unwrap());
+    let output = match args.value_of("output") {
+        Some(output) => PathBuf::from(output),
+        None => input.with_extension("cwasm"),
+    };
+    let module = fs::read(input)?;
+    let artifact = env
+        .precompile_module(module)
+        .await
+        .context(format!("Failed to compile {}", input.to_string_lossy()))?;
+    fs::write(&output, artifact)?;
+    Ok(())
+}
+
+// Add plugins passed through the --plugin or -P flags to the environment
+fn add_plugins(config: &mut EnvConfig, args: &ArgMatches) -> Result<()> {
+    if let Some(plugins) = args.values_of("plugin") {
+        for plugin in plugins {
+            let path = Path::new(plugin);
+            let module = fs::read(path)?;
+            config.add_plugin(module)?;
+        }
+    }
+    Ok(())
+}
@@ -1,7 +1,9 @@
 /*!
 The [`Message`] is a special variant of a [`Signal`](crate::Signal) that can be sent to
 processes. The most common kind of Message is a [`DataMessage`], but there are also some special
-kinds of messages, like the [`Message::Signal`], that is received if a linked process dies.
+kinds of messages, like the [`Message::Signal`], that is received if a linked process dies, or the
+[`Message::Upgrade`] and [`Message::Suspend`], that are received if the process should upgrade to a
+new version of its module or suspend itself.
 */
 
 use std::{
@@ -10,28 +12,36 @@ use std::{
     sync::Arc,
 };
 
-use async_std::net::TcpStream;
+use async_std::{channel::Sender, net::TcpStream};
 
-use crate::Process;
+use crate::{module::Module, process::ExitReason, snapshot::Snapshot, Process};
 
 /// Can be sent between processes by being embedded into a  [`Signal::Message`][0]
 ///
-/// A [`Message`] has 2 variants:
+/// A [`Message`] has 4 variants:
 /// * Data - Regular message containing a tag, buffer and resources.
-/// * Signal - A signal (`LinkDied`) that was turned into a message.
+/// * Signal - A signal (`LinkDied`) that was turned into a message. It contains the link tag and the
+///            reason why the linked process died.
+/// * Upgrade - A signal (`Upgrade`) that was turned into a message. It contains the new version of
+///             the module the process should continue running and its registry version.
+/// * Suspend - A signal (`Suspend`) that was turned into a message. It contains the channel the
+///             snapshot of the process is sent to once it's suspended.
 ///
 /// [0]: crate::Signal
 #[derive(Debug)]
 pub enum Message {
     Data(DataMessage),
-    Signal(Option<i64>),
+    Signal(Option<i64>, ExitReason),
+    Upgrade(Module, Option<String>),
+    Suspend(Sender<Snapshot>),
 }
 
 impl Message {
     pub fn tag(&self) -> Option<i64> {
         match self {
             Message::Data(message) => message.tag,
-            Message::Signal(tag) => *tag,
+            Message::Signal(tag, _) => *tag,
+            Message::Upgrade(_, _) | Message::Suspend(_) => None,
         }
     }
 }
@@ -118,6 +128,16 @@ impl DataMessage {
     pub fn size(&self) -> usize {
         self.buffer.len()
     }
+
+    /// The raw data of the message.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Returns true if resources were added to the message.
+    pub fn has_resources(&self) -> bool {
+        !self.resources.is_empty()
+    }
 }
 
 impl Write for DataMessage {
@@ -2,21 +2,71 @@
 
 use anyhow::{anyhow, Result};
 use async_std::channel::unbounded;
+use async_std::sync::Mutex;
 use async_std::task::JoinHandle;
 use log::trace;
+use semver::Version;
 use uuid::Uuid;
-use wasmtime::{Store, Val};
+use wasmtime::{Engine, Func, Instance, Linker, Store, Val};
 
-use std::sync::Arc;
+use std::{
+    fmt::Debug,
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::{
-    environment::UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+    cache::{shared_module, ModuleCache},
+    environment::{PoolSlot, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
     mailbox::MessageMailbox,
-    process::{self, Process, Signal, WasmProcess},
+    message::{DataMessage, Message},
+    process::{self, DeadlineExceeded, OutOfCpuTime, OutOfFuel, Process, Signal, WasmProcess},
+    quota::FuelMetered,
+    scheduler::{CpuTimer, Scheduled},
+    snapshot::Snapshot,
     state::ProcessState,
+    stdio::{WasiOutput, WasiStdin},
     Environment,
 };
 
+// Keeps the slot in the instance pool reserved until the store is dropped. Fields are dropped in
+// declaration order.
+struct PooledStore {
+    store: Store<ProcessState>,
+    _slot: Option<PoolSlot>,
+}
+
+/// Options for spawning a process with [`Module::spawn_with`].
+#[derive(Default)]
+pub struct SpawnOptions {
+    /// Links the new process to a parent, using the tag for `LinkDied` signals.
+    pub link: Option<(Option<i64>, WasmProcess)>,
+    /// A process that receives a [`Message::Signal`] with the tag and exit reason if the new
+    /// process fails or is killed. Unlike a link, this never kills the monitoring process.
+    pub monitor: Option<(Option<i64>, Arc<dyn Process>)>,
+    /// A message that is put into the mailbox before the process starts running.
+    pub message: Option<Message>,
+    /// Name and version under which the process is registered in the registry of its
+    /// environment, before it starts running.
+    pub register: Option<(String, String)>,
+    /// Kills the process if it's still running after this duration.
+    pub deadline: Option<Duration>,
+    /// Overrides the WASI arguments of the environment.
+    pub wasi_args: Option<Vec<String>>,
+    /// Overrides the WASI environment variables of the environment.
+    pub wasi_envs: Option<Vec<(String, String)>>,
+    /// Overrides the standard input of the environment.
+    pub wasi_stdin: Option<WasiStdin>,
+    /// Overrides the standard output of the environment.
+    pub wasi_stdout: Option<WasiOutput>,
+    /// Overrides the standard error of the environment.
+    pub wasi_stderr: Option<WasiOutput>,
+}
+
 /// A compiled WebAssembly module that can be used to spawn [`WasmProcesses`][0].
 ///
 /// Modules are created from [`Environments`](crate::environment::Environment).
@@ -27,19 +77,42 @@ pub struct Module {
     inner: Arc<InnerModule>,
 }
 
+impl Debug for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Module").finish_non_exhaustive()
+    }
+}
+
 struct InnerModule {
     data: Vec<u8>,
     env: Environment,
-    wasmtime_module: wasmtime::Module,
+    wasmtime_module: Arc<wasmtime::Module>,
+    // If the module was compiled for the environment's instance pool, the patched module is kept
+    // around to compile it for the on-demand engine once the pool is exhausted.
+    fallback_module: Option<Mutex<FallbackModule>>,
+}
+
+enum FallbackModule {
+    Patched(Vec<u8>),
+    Compiled(Arc<wasmtime::Module>),
 }
 
 impl Module {
-    pub(crate) fn new(data: Vec<u8>, env: Environment, wasmtime_module: wasmtime::Module) -> Self {
+    // `patched_module` should only be passed in if `wasmtime_module` was compiled for the
+    // environment's instance pool.
+    pub(crate) fn new(
+        data: Vec<u8>,
+        env: Environment,
+        wasmtime_module: Arc<wasmtime::Module>,
+        patched_module: Option<Vec<u8>>,
+    ) -> Self {
         Self {
             inner: Arc::new(InnerModule {
                 data,
                 env,
                 wasmtime_module,
+                fallback_module: patched_module
+                    .map(|patched| Mutex::new(FallbackModule::Patched(patched))),
             }),
         }
     }
@@ -50,54 +123,250 @@ impl Module {
     /// configuration of the environment will define some characteristics of the process, such as
     /// maximum memory, fuel and available host functions.
     ///
+    /// If the process uses up all of its fuel it's terminated and linked processes are notified
+    /// with the [`ExitReason::OutOfFuel`](crate::ExitReason::OutOfFuel) reason. The same happens
+    /// with the [`ExitReason::OutOfCpuTime`](crate::ExitReason::OutOfCpuTime) reason if it exceeds
+    /// its CPU time limit, or [`ExitReason::DeadlineExceeded`](crate::ExitReason::DeadlineExceeded)
+    /// if it's still running after the environment's `max_runtime`.
+    ///
     /// After it's spawned the process will keep running in the background. A process can be killed
     /// by sending a `Signal::Kill` to it. If you would like to block until the process is finished
     /// you can `.await` on the returned `JoinHandle<()>`.
+    ///
+    /// A `Signal::Upgrade` asks the process to continue running a newer module from the same
+    /// environment. The process decides when it's safe to upgrade and hands its state over to the
+    /// new module's `lunatic_code_change` function (see `lunatic::process::code_change`).
     pub async fn spawn(
         &self,
         function: &str,
         params: Vec<Val>,
         link: Option<(Option<i64>, WasmProcess)>,
     ) -> Result<(JoinHandle<()>, WasmProcess)> {
+        self.spawn_with_deadline(function, params, link, None).await
+    }
+
+    /// Spawns a new process from the module that is killed if it's still running after `deadline`.
+    ///
+    /// The deadline can only shorten the environment's `max_runtime`, never extend it. Processes
+    /// are also killed while they are waiting, e.g. on a message or a TCP stream.
+    pub async fn spawn_with_deadline(
+        &self,
+        function: &str,
+        params: Vec<Val>,
+        link: Option<(Option<i64>, WasmProcess)>,
+        deadline: Option<Duration>,
+    ) -> Result<(JoinHandle<()>, WasmProcess)> {
+        let options = SpawnOptions {
+            link,
+            deadline,
+            ..SpawnOptions::default()
+        };
+        self.spawn_inner(function, params, options, None).await
+    }
+
+    /// Spawns a new process from the module, configured with `options`.
+    ///
+    /// Everything in `options` is set up before the process starts running, so that an initial
+    /// message or the registry entry is never observed missing by the process itself or the
+    /// processes it talks to.
+    pub async fn spawn_with(
+        &self,
+        function: &str,
+        params: Vec<Val>,
+        options: SpawnOptions,
+    ) -> Result<(JoinHandle<()>, WasmProcess)> {
+        self.spawn_inner(function, params, options, None).await
+    }
+
+    /// Spawns a new process from a snapshot of a suspended process.
+    ///
+    /// The snapshot must have been taken from a process spawned from the same module. After the
+    /// captured memories, globals and messages are restored, the exported `lunatic_resume`
+    /// function is called (see the [`snapshot`](crate::snapshot) module).
+    pub async fn restore(
+        &self,
+        snapshot: &Snapshot,
+        link: Option<(Option<i64>, WasmProcess)>,
+    ) -> Result<(JoinHandle<()>, WasmProcess)> {
+        if !snapshot.matches_module(&self.inner.data) {
+            return Err(anyhow!("Snapshot was taken from a different module"));
+        }
+        let options = SpawnOptions {
+            link,
+            ..SpawnOptions::default()
+        };
+        self.spawn_inner("lunatic_resume", Vec::new(), options, Some(snapshot))
+            .await
+    }
+
+    /// Spawns a new process that starts with the memories and globals of a snapshot instead of
+    /// the module's initial state, and calls `function`.
+    ///
+    /// This is used to fork processes, so that children don't need to repeat the costly
+    /// initialization of their parent. Memories are copied eagerly, because Wasmtime doesn't
+    /// support copy-on-write memory images yet.
+    ///
+    /// Fails if the module has memories or mutable globals that are not exported, because they
+    /// are not part of the snapshot.
+    pub async fn fork(
+        &self,
+        snapshot: &Snapshot,
+        function: &str,
+        params: Vec<Val>,
+        link: Option<(Option<i64>, WasmProcess)>,
+    ) -> Result<(JoinHandle<()>, WasmProcess)> {
+        if !snapshot.matches_module(&self.inner.data) {
+            return Err(anyhow!("Snapshot was taken from a different module"));
+        }
+        Snapshot::check_forkable(&self.inner.data)?;
+        let options = SpawnOptions {
+            link,
+            ..SpawnOptions::default()
+        };
+        self.spawn_inner(function, params, options, Some(snapshot))
+            .await
+    }
+
+    async fn spawn_inner(
+        &self,
+        function: &str,
+        params: Vec<Val>,
+        mut options: SpawnOptions,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<(JoinHandle<()>, WasmProcess)> {
+        // Fail before the process exists if it can't be registered
+        if let Some((_, version)) = &options.register {
+            Version::parse(version)?;
+        }
         // TODO: Switch to new_v1() for distributed Lunatic to assure uniqueness across nodes.
         let id = Uuid::new_v4();
         trace!("Spawning process: {}", id);
         let signal_mailbox = unbounded::<Signal>();
         let message_mailbox = MessageMailbox::default();
+        if let Some(snapshot) = snapshot {
+            snapshot
+                .messages()?
+                .into_iter()
+                .for_each(|message| message_mailbox.push(message));
+        }
+        if let Some(message) = options.message.take() {
+            message_mailbox.push(message);
+        }
+        // Fails if the environment reached the maximum number of processes
+        let quota = self.environment().quota().spawn()?;
         let state = ProcessState::new(
             id,
             self.clone(),
             signal_mailbox.0.clone(),
             message_mailbox.clone(),
             self.environment().config(),
+            quota,
+            &options,
         )?;
 
-        let mut store = Store::new(self.environment().engine(), state);
-        store.limiter(|state| state);
-
-        // Trap if out of fuel
-        store.out_of_fuel_trap();
-        // Define maximum fuel
-        match self.environment().config().max_fuel() {
-            Some(max_fuel) => {
-                store.out_of_fuel_async_yield(max_fuel, UNIT_OF_COMPUTE_IN_INSTRUCTIONS)
-            }
-            // If no limit is specified use maximum
-            None => store.out_of_fuel_async_yield(u64::MAX, UNIT_OF_COMPUTE_IN_INSTRUCTIONS),
-        };
-
-        let instance = self
+        let time_slice = self.environment().config().time_slice();
+        let max_fuel = self
             .environment()
-            .linker()
-            .instantiate_async(&mut store, self.wasmtime_module())
+            .config()
+            .max_fuel()
+            .map(|max_fuel| max_fuel.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS));
+        let mut cpu_timer = None;
+        let (pooled_store, instance, entry) = self
+            .instantiate(state, function, max_fuel, &mut cpu_timer, snapshot)
             .await?;
-        let entry = instance
-            .get_func(&mut store, function)
-            .map_or(Err(anyhow!("Function '{}' not found", function)), |func| {
-                Ok(func)
-            })?;
 
-        let fut = async move { entry.call_async(&mut store, &params).await };
+        let fuel_quota = pooled_store.store.data().quota.env().clone();
+        let fuel_consumed = Arc::new(AtomicU64::new(0));
+        let process_fuel_consumed = fuel_consumed.clone();
+        let mut process_cpu_timer = cpu_timer.clone();
+        let fut = async move {
+            let (mut pooled_store, mut instance, mut entry, mut params, mut max_fuel) =
+                (pooled_store, instance, entry, params, max_fuel);
+            loop {
+                let store = &mut pooled_store.store;
+                let result = entry.call_async(&mut *store, &params).await;
+                let instance_fuel = store.fuel_consumed().unwrap_or(0);
+                process_fuel_consumed.fetch_add(instance_fuel, Ordering::SeqCst);
+                // The process stopped at a safe point and hands a snapshot of itself over
+                if let Some(suspend) = store.data_mut().suspend.take() {
+                    let snapshot = Snapshot::capture(store, &instance, suspend.messages)?;
+                    let _ = suspend.sender.try_send(snapshot);
+                    return Ok(Vec::new().into_boxed_slice());
+                }
+                let code_change = match store.data_mut().code_change.take() {
+                    Some(code_change) => code_change,
+                    // Distinguish running out of fuel or CPU time from other traps
+                    None => {
+                        return match (result, max_fuel) {
+                            (Err(_), _)
+                                if process_cpu_timer
+                                    .as_ref()
+                                    .map_or(false, |timer| timer.exceeded()) =>
+                            {
+                                Err(OutOfCpuTime.into())
+                            }
+                            (Err(_), Some(max_fuel)) if store.fuel_consumed() >= Some(max_fuel) => {
+                                Err(OutOfFuel.into())
+                            }
+                            (result, _) => result,
+                        }
+                    }
+                };
+                // The process stopped at a safe point and continues with the new module. It keeps
+                // its identity, mailbox and resources, but the old instance is dropped.
+                max_fuel = max_fuel.map(|max_fuel| max_fuel.saturating_sub(instance_fuel));
+                let PooledStore { store, _slot } = pooled_store;
+                let mut state = store.into_data();
+                drop(_slot);
+                state.quota.release_memory();
+                state.module = code_change.module.clone();
+                // The old state is passed to the new module as the current message
+                let mut message = DataMessage::new(None, code_change.state.len());
+                message.write_all(&code_change.state)?;
+                state.message = Some(Message::Data(message));
+                let (new_store, new_instance, new_entry) = code_change
+                    .module
+                    .instantiate(
+                        state,
+                        "lunatic_code_change",
+                        max_fuel,
+                        &mut process_cpu_timer,
+                        None,
+                    )
+                    .await?;
+                // Other processes can find the process under its new version. Invalid versions are
+                // already rejected by `lunatic::process::upgrade`.
+                if let Some(version) = &code_change.version {
+                    let id = new_store.store.data().id;
+                    let registry = code_change.module.environment().registry();
+                    let _ = registry.update_version(id, version);
+                }
+                pooled_store = new_store;
+                instance = new_instance;
+                entry = new_entry;
+                params = Vec::new();
+            }
+        };
+        // Charge consumed fuel against the environment's fuel quota
+        let fut = FuelMetered::new(fut, &fuel_quota, time_slice, fuel_consumed);
+        // Share CPU time fairly with processes from other environments
+        let fut = Scheduled::new(
+            fut,
+            self.environment().scheduling_group().clone(),
+            cpu_timer,
+        );
+        let max_runtime = match (self.environment().config().max_runtime(), options.deadline) {
+            (Some(max_runtime), Some(deadline)) => Some(max_runtime.min(deadline)),
+            (max_runtime, deadline) => max_runtime.or(deadline),
+        };
+        let fut = async move {
+            match max_runtime {
+                Some(max_runtime) => async_std::future::timeout(max_runtime, fut)
+                    .await
+                    .unwrap_or_else(|_| Err(DeadlineExceeded.into())),
+                None => fut.await,
+            }
+        };
         let child_process = process::new(fut, id, signal_mailbox.1, message_mailbox);
         let child_process_handle = WasmProcess::new(id, signal_mailbox.0.clone());
 
@@ -119,7 +388,7 @@ impl Module {
         // Only after the yield function we can guarantee that the child is going to be notified
         // if the parent fails. This is ok, as the actual spawning of the child happens after the
         // call, so the child wouldn't even exist if the parent failed before.
-        if let Some((tag, process)) = link {
+        if let Some((tag, process)) = options.link {
             // Send signal to itself to perform the linking
             process.send(Signal::Link(None, Arc::new(child_process_handle.clone())));
             // Suspend itself to process all new signals
@@ -130,6 +399,20 @@ impl Module {
                 .try_send(Signal::Link(tag, Arc::new(process)))
                 .expect("receiver must exist at this point");
         }
+        // Like the link signal, the monitor signal is processed before any child code runs
+        if let Some((tag, process)) = options.monitor {
+            signal_mailbox
+                .0
+                .try_send(Signal::Monitor(tag, process))
+                .expect("receiver must exist at this point");
+        }
+        if let Some((name, version)) = options.register {
+            self.environment().registry().insert(
+                name,
+                &version,
+                Arc::new(child_process_handle.clone()),
+            )?;
+        }
 
         // Spawn a background process
         trace!("Process size: {}", std::mem::size_of_val(&child_process));
@@ -137,6 +420,128 @@ impl Module {
         Ok((join, child_process_handle))
     }
 
+    // Instantiates the module for a process with the given `state` and returns the store, the
+    // instance and the exported `function`. At most `max_fuel` instructions can be executed. If CPU
+    // time is limited, `cpu_timer` is created or pointed to the new store. If a `snapshot` is
+    // passed in, its memories and globals are restored before the function is looked up.
+    async fn instantiate(
+        &self,
+        state: ProcessState,
+        function: &str,
+        max_fuel: Option<u64>,
+        cpu_timer: &mut Option<Arc<CpuTimer>>,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<(PooledStore, Instance, Func)> {
+        let (engine, linker, wasmtime_module, pool_slot) = self.instance_target().await?;
+        let mut store = Store::new(engine, state);
+        store.limiter(|state| state);
+
+        // The store starts without fuel. Each time it runs out, one time slice worth of fuel is
+        // injected and the process yields, until `max_fuel` units are used up and the process
+        // traps.
+        let time_slice = self.environment().config().time_slice();
+        // If no limit is specified use maximum
+        let injections = max_fuel.map_or(u64::MAX, |max_fuel| {
+            (max_fuel / time_slice) + (max_fuel % time_slice != 0) as u64
+        });
+        store.out_of_fuel_async_yield(injections, time_slice);
+        if let Some(limit) = self.environment().config().max_cpu_time() {
+            let interrupt = store.interrupt_handle()?;
+            match cpu_timer {
+                Some(cpu_timer) => cpu_timer.set_interrupt_handle(interrupt),
+                None => *cpu_timer = Some(CpuTimer::new(limit, interrupt)),
+            }
+        }
+
+        let instance = linker
+            .instantiate_async(&mut store, &wasmtime_module)
+            .await?;
+        store.data_mut().instance = Some(instance);
+        if let Some(snapshot) = snapshot {
+            snapshot.apply(&mut store, &instance)?;
+        }
+        let entry = instance
+            .get_func(&mut store, function)
+            .map_or(Err(anyhow!("Function '{}' not found", function)), |func| {
+                Ok(func)
+            })?;
+        Ok((
+            PooledStore {
+                store,
+                _slot: pool_slot,
+            },
+            instance,
+            entry,
+        ))
+    }
+
+    // Returns the engine, linker and compiled module that should be used to instantiate a new
+    // process, and reserves a slot in the environment's instance pool if it's used.
+    async fn instance_target(
+        &self,
+    ) -> Result<(
+        &Engine,
+        &Linker<ProcessState>,
+        wasmtime::Module,
+        Option<PoolSlot>,
+    )> {
+        let env = self.environment();
+        let pool = match env.instance_pool() {
+            Some(pool) => pool,
+            None => {
+                return Ok((
+                    env.engine(),
+                    env.linker(),
+                    self.wasmtime_module().clone(),
+                    None,
+                ))
+            }
+        };
+        let fallback_module = match &self.inner.fallback_module {
+            Some(fallback_module) => fallback_module,
+            // The module doesn't fit into the pool and was compiled for the on-demand engine
+            None => {
+                return Ok((
+                    &pool.fallback_engine,
+                    &pool.fallback_linker,
+                    self.wasmtime_module().clone(),
+                    None,
+                ))
+            }
+        };
+        if let Some(slot) = pool.reserve() {
+            return Ok((
+                env.engine(),
+                env.linker(),
+                self.wasmtime_module().clone(),
+                Some(slot),
+            ));
+        }
+        // The pool is exhausted, compile the module for the on-demand engine if this didn't
+        // happen already.
+        let mut fallback_module = fallback_module.lock().await;
+        let module = match &*fallback_module {
+            FallbackModule::Compiled(module) => (**module).clone(),
+            FallbackModule::Patched(patched) => {
+                let engine = pool.fallback_engine.clone();
+                let patched = patched.clone();
+                let data = self.inner.data.clone();
+                let config = env.config().clone();
+                let module = async_std::task::spawn_blocking(move || {
+                    shared_module(&data, &config, || match config.cache_dir() {
+                        Some(dir) => ModuleCache::new(dir)
+                            .load_or_compile(&engine, &data, &config, false, &patched),
+                        None => wasmtime::Module::new(&engine, patched.as_slice()),
+                    })
+                })
+                .await?;
+                *fallback_module = FallbackModule::Compiled(module.clone());
+                (*module).clone()
+            }
+        };
+        Ok((&*pool.fallback_engine, &pool.fallback_linker, module, None))
+    }
+
     pub fn environment(&self) -> &Environment {
         &self.inner.env
     }
@@ -145,8 +550,362 @@ impl Module {
         &self.inner.wasmtime_module
     }
 
+    /// The name of the module from the WebAssembly name section, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.inner.wasmtime_module.name()
+    }
+
     /// The raw WebAssembly data that the Module was created from.
     pub fn data(&self) -> Vec<u8> {
         self.inner.data.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use async_std::channel::unbounded;
+    use uuid::Uuid;
+
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{EnvConfig, Environment, ExitReason, Process, Signal, WasmProcess};
+
+    #[async_std::test]
+    async fn out_of_fuel_exit_reason() {
+        let config = EnvConfig::new(0x100000, Some(1));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (func (export "spin")
+                    (loop br 0)))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("spin", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        // The first signal is the parent linking itself to the child
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(matches!(
+            receiver.recv().await,
+            Ok(Signal::LinkDied(Some(1), ExitReason::OutOfFuel))
+        ));
+    }
+
+    #[async_std::test]
+    async fn out_of_cpu_time_exit_reason() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_max_cpu_time(Some(Duration::from_millis(10)));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (func (export "spin")
+                    (loop br 0)))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("spin", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(matches!(
+            receiver.recv().await,
+            Ok(Signal::LinkDied(Some(1), ExitReason::OutOfCpuTime))
+        ));
+    }
+
+    #[async_std::test]
+    async fn table_elements_limit() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_max_table_elements(10);
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (table 0 funcref)
+                (func (export "grow")
+                    ;; Growing up to the limit succeeds, one element more fails
+                    (if (i32.ne (table.grow (ref.null func) (i32.const 10)) (i32.const 0))
+                        (then unreachable))
+                    (if (i32.ne (table.grow (ref.null func) (i32.const 1)) (i32.const -1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("grow", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[async_std::test]
+    async fn deadline_exceeded_exit_reason() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_max_runtime(Some(Duration::from_secs(60)));
+        config.allow_namespace("lunatic::message::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        // Blocks forever waiting on a message
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (func (export "wait")
+                    (drop (call $receive (i64.const 0) (i32.const 0)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn_with_deadline(
+                "wait",
+                Vec::new(),
+                Some((Some(1), parent)),
+                Some(Duration::from_millis(10)),
+            )
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(matches!(
+            receiver.recv().await,
+            Ok(Signal::LinkDied(Some(1), ExitReason::DeadlineExceeded))
+        ));
+    }
+
+    #[async_std::test]
+    async fn multi_memory_limits() {
+        // Two memories of 1 page (64 KiB) each
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (memory 1)
+                (memory 1)
+                (func (export "hello")))
+            "#,
+        )
+        .unwrap();
+
+        let config = EnvConfig::new(0x100000, None);
+        let environment = Environment::new(config).unwrap();
+        let module = environment.create_module(raw_module.clone()).await.unwrap();
+        assert!(module.spawn("hello", Vec::new(), None).await.is_err());
+
+        let mut config = EnvConfig::new(0x20000, None);
+        config.set_max_memories(2);
+        let environment = Environment::new(config).unwrap();
+        let module = environment.create_module(raw_module.clone()).await.unwrap();
+        assert!(module.spawn("hello", Vec::new(), None).await.is_ok());
+
+        // The maximum memory limits both memories together
+        let mut config = EnvConfig::new(0x10000, None);
+        config.set_max_memories(2);
+        let environment = Environment::new(config).unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        assert!(module.spawn("hello", Vec::new(), None).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn instance_pool_fallback() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.set_instance_pool_size(Some(1));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (func (export "wait")
+                    (drop (call $receive (i64.const 0) (i32.const 0))))
+                (memory 1))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        // The second process doesn't fit into the pool and is allocated on demand
+        let (_, first) = module.spawn("wait", Vec::new(), None).await.unwrap();
+        let (second_task, second) = module.spawn("wait", Vec::new(), None).await.unwrap();
+        second.send(Signal::Kill);
+        second_task.await;
+        first.send(Signal::Kill);
+
+        // Modules exceeding the pool limits (at most 10 globals) are always allocated on demand
+        let globals = "(global i32 (i32.const 0))".repeat(11);
+        let raw_module =
+            wat::parse_str(format!("(module {} (func (export \"hello\")))", globals)).unwrap();
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_instance_pool_size(Some(1));
+        let environment = Environment::new(config).unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let pool = environment.instance_pool().unwrap();
+        assert!(wasmtime::Engine::same(
+            module.inner.wasmtime_module.engine(),
+            &pool.fallback_engine
+        ));
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("hello", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        // The process finished normally
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[async_std::test]
+    async fn precompiled_module() {
+        let raw_module = wat::parse_str(r#"(module (func (export "hello")))"#).unwrap();
+        let environment = Environment::new(EnvConfig::new(0x100000, None)).unwrap();
+        let artifact = environment
+            .precompile_module(raw_module.clone())
+            .await
+            .unwrap();
+        assert!(Environment::is_precompiled(&artifact));
+        assert!(!Environment::is_precompiled(&raw_module));
+
+        let module = unsafe { environment.create_module_from_precompiled(&artifact) }.unwrap();
+        assert_eq!(module.data(), raw_module);
+        let (task, _) = module.spawn("hello", Vec::new(), None).await.unwrap();
+        task.await;
+
+        // Truncated artifacts and artifacts compiled for a different configuration are rejected
+        let truncated = &artifact[..artifact.len() / 2];
+        assert!(unsafe { environment.create_module_from_precompiled(truncated) }.is_err());
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_max_cpu_time(Some(Duration::from_secs(60)));
+        let environment = Environment::new(config).unwrap();
+        assert!(unsafe { environment.create_module_from_precompiled(&artifact) }.is_err());
+    }
+
+    #[async_std::test]
+    async fn shared_modules() {
+        let raw_module = wat::parse_str(r#"(module (func (export "shared")))"#).unwrap();
+        let environment = Environment::new(EnvConfig::new(0x100000, None)).unwrap();
+        let first = environment.create_module(raw_module.clone()).await.unwrap();
+
+        // Capabilities don't influence code generation
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("lunatic::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        let second = environment.create_module(raw_module.clone()).await.unwrap();
+        assert!(Arc::ptr_eq(
+            &first.inner.wasmtime_module,
+            &second.inner.wasmtime_module
+        ));
+        let (task, _) = second.spawn("shared", Vec::new(), None).await.unwrap();
+        task.await;
+
+        // Plugins and code generation settings do
+        let mut config = EnvConfig::new(0x100000, None);
+        config
+            .add_plugin(wat::parse_str("(module)").unwrap())
+            .unwrap();
+        let environment = Environment::new(config).unwrap();
+        let third = environment.create_module(raw_module.clone()).await.unwrap();
+        assert!(!Arc::ptr_eq(
+            &first.inner.wasmtime_module,
+            &third.inner.wasmtime_module
+        ));
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_max_cpu_time(Some(Duration::from_secs(60)));
+        let environment = Environment::new(config).unwrap();
+        let fourth = environment.create_module(raw_module).await.unwrap();
+        assert!(!Arc::ptr_eq(
+            &first.inner.wasmtime_module,
+            &fourth.inner.wasmtime_module
+        ));
+    }
+
+    #[async_std::test]
+    async fn code_change() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.allow_namespace("lunatic::process::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        // Keeps a counter in memory and hands it over on upgrade
+        let old_version = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (import "lunatic::process" "code_change" (func $code_change (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "run")
+                    (i32.store (i32.const 0) (i32.const 42))
+                    (loop
+                        (if (i32.eq (call $receive (i64.const 0) (i32.const 0)) (i32.const 2))
+                            (then (drop (call $code_change (i32.const 0) (i32.const 4)))))
+                        (br 0))))
+            "#,
+        )
+        .unwrap();
+        // Fails if it didn't receive the counter
+        let new_version = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "lunatic_code_change")
+                    (if (i32.ne (call $read_data (i32.const 0) (i32.const 4)) (i32.const 4))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 0)) (i32.const 42))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let old_module = environment.create_module(old_version).await.unwrap();
+        let new_module = environment.create_module(new_version).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, process) = old_module
+            .spawn("run", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        environment
+            .registry()
+            .insert("counter".to_string(), "1.0.0", Arc::new(process.clone()))
+            .unwrap();
+        process.send(Signal::Upgrade(new_module, Some("2.0.0".to_string())));
+        // The new version finishes normally and is registered under the new version
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(receiver.try_recv().is_err());
+        let registry = environment.registry();
+        assert!(registry.get("counter", "^1").unwrap().is_none());
+        let registered = registry.get("counter", "^2").unwrap().unwrap();
+        assert_eq!(registered.id(), process.id());
+
+        // Modules from other environments are rejected and the old version keeps running
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        let other_environment = Environment::new(config).unwrap();
+        let raw_module =
+            wat::parse_str(r#"(module (func (export "lunatic_code_change")))"#).unwrap();
+        let other_module = other_environment.create_module(raw_module).await.unwrap();
+        let (task, process) = old_module.spawn("run", Vec::new(), None).await.unwrap();
+        process.send(Signal::Upgrade(other_module, None));
+        let result = async_std::future::timeout(Duration::from_millis(100), task).await;
+        assert!(result.is_err());
+        process.send(Signal::Kill);
+    }
+}
@@ -21,6 +21,7 @@ execution, like the `heap_profiler` plugin does.
 use std::{collections::HashMap, convert::TryInto};
 
 use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
 use wasmparser::{
     Chunk, Export, FunctionBody, Import, Name, NameSectionReader, Parser, Payload, Range,
     SectionReader, TypeDef, Validator,
@@ -33,13 +34,20 @@ use crate::{api, environment::PLUGIN_ENV, state::PluginState};
 #[derive(Clone)]
 pub struct Plugin {
     module: Module,
+    // Identifies the plugin in the module cache
+    hash: [u8; 32],
 }
 
 impl Plugin {
     /// Creates a new [`Plugin`] from raw WebAssembly data.
     pub fn new(module: Vec<u8>) -> Result<Self> {
+        let hash = Sha256::digest(&module).into();
         let module = Module::new(&PLUGIN_ENV.engine, module)?;
-        Ok(Self { module })
+        Ok(Self { module, hash })
+    }
+
+    pub(crate) fn hash(&self) -> &[u8; 32] {
+        &self.hash
     }
 }
 
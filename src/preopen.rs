@@ -0,0 +1,585 @@
+/*!
+Host directories and virtual file systems accessible to WASI processes.
+
+Directories are preopened per environment with
+[`EnvConfig::preopen_dir`](crate::EnvConfig::preopen_dir) and show up inside of each process under
+their guest path. Processes can't reach anything outside of them.
+
+In-memory file systems ([`VirtualFs`](crate::vfs::VirtualFs)) are mounted the same way, after all
+host directories.
+
+Read-only directories only allow listing, reading and stating files. Writable directories are
+counted against the environment's disk quota
+([`EnvConfig::set_max_disk_usage`](crate::EnvConfig::set_max_disk_usage)) if one is set. The quota
+tracks by how much the processes grow files. It's conservative: space freed by truncating or
+overwriting files is not returned, only deleting files releases their size. Processes writing to
+their own copy of a virtual file system (not shared) give the space back when they finish, as the
+copy is dropped with them.
+*/
+
+use std::{
+    any::Any,
+    io::{self, IoSlice, IoSliceMut, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use wasi_common::{
+    dir::{DirCaps, ReaddirCursor, ReaddirEntity},
+    file::{Advice, FdFlags, FileCaps, FileType, Filestat, OFlags},
+    Error, SystemTimeSpec, WasiCtx, WasiDir, WasiFile,
+};
+use wasmtime_wasi::{ambient_authority, Dir};
+
+use crate::{capability::WasiCapability, quota::EnvQuota, EnvConfig};
+
+/// A host directory made accessible to processes.
+#[derive(Clone, Debug)]
+pub struct PreopenedDir {
+    pub(crate) host_path: PathBuf,
+    pub(crate) guest_path: String,
+    pub(crate) read_only: bool,
+}
+
+impl PreopenedDir {
+    pub fn host_path(&self) -> &Path {
+        &self.host_path
+    }
+
+    pub fn guest_path(&self) -> &str {
+        &self.guest_path
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+}
+
+// Adds the host directories and virtual file systems to the WASI context, right after the
+// standard streams.
+pub(crate) fn preopen_dirs(
+    wasi: &mut WasiCtx,
+    config: &EnvConfig,
+    quota: &Arc<EnvQuota>,
+) -> Result<()> {
+    // Without the write capability all directories are read-only, independent of their options.
+    let writable = config
+        .capabilities()
+        .is_wasi_allowed(WasiCapability::FilesystemWrite);
+    let mut fds = 3..;
+    for (preopen, fd) in config.preopened_dirs().iter().zip(fds.by_ref()) {
+        let dir = Dir::open_ambient_dir(&preopen.host_path, ambient_authority())?;
+        let dir: Box<dyn WasiDir> = Box::new(wasmtime_wasi::dir::Dir::from_cap_std(dir));
+        let (dir, caps, file_caps) = if preopen.read_only || !writable {
+            (dir, read_only_dir_caps(), read_only_file_caps())
+        } else {
+            (
+                with_quota(dir, quota, false),
+                DirCaps::all(),
+                FileCaps::all(),
+            )
+        };
+        wasi.insert_dir(fd, dir, caps, file_caps, preopen.guest_path.clone().into());
+    }
+    for (mount, fd) in config.virtual_mounts().iter().zip(fds) {
+        let guest_path = mount.guest_path.clone().into();
+        if writable {
+            let dir = with_quota(mount.root(), quota, !mount.shared);
+            wasi.insert_dir(fd, dir, DirCaps::all(), FileCaps::all(), guest_path);
+        } else {
+            let (caps, file_caps) = (read_only_dir_caps(), read_only_file_caps());
+            wasi.insert_dir(fd, mount.root(), caps, file_caps, guest_path);
+        }
+    }
+    Ok(())
+}
+
+// Counts writes inside of the directory against the disk quota, if the environment has one.
+// Usage of `private` directories is released once they are dropped.
+fn with_quota(dir: Box<dyn WasiDir>, quota: &Arc<EnvQuota>, private: bool) -> Box<dyn WasiDir> {
+    if quota.has_disk_quota() {
+        let usage = DiskUsage {
+            quota: quota.clone(),
+            private: private.then(|| {
+                Arc::new(PrivateUsage {
+                    quota: quota.clone(),
+                    charged: AtomicUsize::new(0),
+                })
+            }),
+        };
+        Box::new(QuotaDir::new(dir, usage))
+    } else {
+        dir
+    }
+}
+
+fn read_only_dir_caps() -> DirCaps {
+    DirCaps::OPEN
+        | DirCaps::READDIR
+        | DirCaps::READLINK
+        | DirCaps::PATH_FILESTAT_GET
+        | DirCaps::FILESTAT_GET
+}
+
+fn read_only_file_caps() -> FileCaps {
+    FileCaps::READ
+        | FileCaps::SEEK
+        | FileCaps::TELL
+        | FileCaps::ADVISE
+        | FileCaps::FILESTAT_GET
+        | FileCaps::FDSTAT_SET_FLAGS
+        | FileCaps::POLL_READWRITE
+}
+
+// Returned to the guest as `Errno::Nospc` if a write would exceed the quota.
+fn no_space() -> Error {
+    // ENOSPC
+    io::Error::from_raw_os_error(28).into()
+}
+
+// Charges disk usage to the environment's quota.
+#[derive(Clone)]
+struct DiskUsage {
+    quota: Arc<EnvQuota>,
+    private: Option<Arc<PrivateUsage>>,
+}
+
+impl DiskUsage {
+    fn try_reserve(&self, bytes: usize) -> bool {
+        if !self.quota.try_reserve_disk(bytes) {
+            return false;
+        }
+        if let Some(private) = &self.private {
+            private.charged.fetch_add(bytes, Ordering::SeqCst);
+        }
+        true
+    }
+
+    fn force_reserve(&self, bytes: usize) {
+        self.quota.force_reserve_disk(bytes);
+        if let Some(private) = &self.private {
+            private.charged.fetch_add(bytes, Ordering::SeqCst);
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        match &self.private {
+            // Content copied from the mounted file system was never charged by this process
+            Some(private) => {
+                let charged = private
+                    .charged
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |charged| {
+                        Some(charged.saturating_sub(bytes))
+                    })
+                    .unwrap_or_default();
+                self.quota.release_disk(charged.min(bytes));
+            }
+            None => self.quota.release_disk(bytes),
+        }
+    }
+}
+
+// Usage charged to a file system private to one process, released when the process drops it.
+struct PrivateUsage {
+    quota: Arc<EnvQuota>,
+    charged: AtomicUsize,
+}
+
+impl Drop for PrivateUsage {
+    fn drop(&mut self) {
+        self.quota.release_disk(*self.charged.get_mut());
+    }
+}
+
+// A directory that counts writes to files inside of it against the environment's disk quota.
+struct QuotaDir {
+    inner: Box<dyn WasiDir>,
+    usage: DiskUsage,
+}
+
+impl QuotaDir {
+    fn new(inner: Box<dyn WasiDir>, usage: DiskUsage) -> Self {
+        Self { inner, usage }
+    }
+
+    // Directories that need to be passed to another directory need to be unwrapped first, so
+    // that the inner implementation can downcast them to its own type.
+    fn unwrap(dir: &dyn WasiDir) -> &dyn WasiDir {
+        match dir.as_any().downcast_ref::<QuotaDir>() {
+            Some(dir) => &*dir.inner,
+            None => dir,
+        }
+    }
+}
+
+#[async_trait]
+impl WasiDir for QuotaDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let file = self
+            .inner
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+            .await?;
+        if write {
+            Ok(Box::new(QuotaFile {
+                inner: file,
+                usage: self.usage.clone(),
+            }))
+        } else {
+            Ok(file)
+        }
+    }
+
+    async fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = self.inner.open_dir(symlink_follow, path).await?;
+        Ok(Box::new(QuotaDir::new(dir, self.usage.clone())))
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.inner.create_dir(path).await
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.inner.readdir(cursor).await
+    }
+
+    async fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.inner.symlink(old_path, new_path).await
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.inner.remove_dir(path).await
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let stat = self.inner.get_path_filestat(path, false).await?;
+        self.inner.unlink_file(path).await?;
+        // Other links keep the content alive
+        if stat.filetype == FileType::RegularFile && stat.nlink <= 1 {
+            self.usage.release(stat.size as usize);
+        }
+        Ok(())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.inner.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.inner.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.inner.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        self.inner
+            .rename(path, QuotaDir::unwrap(dest_dir), dest_path)
+            .await
+    }
+
+    async fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        self.inner
+            .hard_link(path, QuotaDir::unwrap(target_dir), target_path)
+            .await
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        self.inner
+            .set_times(path, atime, mtime, follow_symlinks)
+            .await
+    }
+}
+
+// A file opened for writing that counts its growth against the environment's disk quota.
+struct QuotaFile {
+    inner: Box<dyn WasiFile>,
+    usage: DiskUsage,
+}
+
+impl QuotaFile {
+    async fn size(&self) -> Result<u64, Error> {
+        Ok(self.inner.get_filestat().await?.size)
+    }
+
+    // Reserves `reserved` bytes before running `operation` and corrects the reservation once the
+    // new size of the file is known.
+    async fn grow<T, F>(&self, reserved: u64, operation: F) -> Result<T, Error>
+    where
+        F: std::future::Future<Output = Result<T, Error>>,
+    {
+        let before = self.size().await?;
+        if !self.usage.try_reserve(reserved as usize) {
+            return Err(no_space());
+        }
+        let result = operation.await;
+        let grown = self.size().await?.saturating_sub(before);
+        if grown < reserved {
+            self.usage.release((reserved - grown) as usize);
+        } else {
+            // Someone else wrote to the file at the same time
+            self.usage.force_reserve((grown - reserved) as usize);
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl WasiFile for QuotaFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn datasync(&self) -> Result<(), Error> {
+        self.inner.datasync().await
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        self.inner.sync().await
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        self.inner.get_filetype().await
+    }
+
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        self.inner.get_fdflags().await
+    }
+
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.inner.set_fdflags(flags).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.inner.get_filestat().await
+    }
+
+    async fn set_filestat_size(&self, size: u64) -> Result<(), Error> {
+        let growth = size.saturating_sub(self.size().await?);
+        self.grow(growth, self.inner.set_filestat_size(size)).await
+    }
+
+    async fn advise(&self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.inner.advise(offset, len, advice).await
+    }
+
+    async fn allocate(&self, offset: u64, len: u64) -> Result<(), Error> {
+        let growth = (offset + len).saturating_sub(self.size().await?);
+        self.grow(growth, self.inner.allocate(offset, len)).await
+    }
+
+    async fn set_times(
+        &self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        self.inner.set_times(atime, mtime).await
+    }
+
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.inner.read_vectored(bufs).await
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.inner.read_vectored_at(bufs, offset).await
+    }
+
+    async fn write_vectored<'a>(&self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let len: u64 = bufs.iter().map(|buf| buf.len() as u64).sum();
+        let growth = if self.inner.get_fdflags().await?.contains(FdFlags::APPEND) {
+            len
+        } else {
+            let position = self.inner.seek(SeekFrom::Current(0)).await?;
+            (position + len).saturating_sub(self.size().await?)
+        };
+        self.grow(growth, self.inner.write_vectored(bufs)).await
+    }
+
+    async fn write_vectored_at<'a>(&self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        let len: u64 = bufs.iter().map(|buf| buf.len() as u64).sum();
+        let growth = (offset + len).saturating_sub(self.size().await?);
+        self.grow(growth, self.inner.write_vectored_at(bufs, offset))
+            .await
+    }
+
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        self.inner.seek(pos).await
+    }
+
+    async fn peek(&self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.inner.peek(buf).await
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.inner.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.inner.readable().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.inner.writable().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use async_std::channel::unbounded;
+    use uuid::Uuid;
+
+    use crate::{
+        message::Message, stdio::WasiOutput, vfs::VirtualFs, EnvConfig, Environment, Signal,
+        WasmProcess,
+    };
+
+    #[async_std::test]
+    async fn preopen_dirs() {
+        let root = std::env::temp_dir().join(format!("lunatic-{}", Uuid::new_v4()));
+        let config_dir = root.join("config");
+        let scratch_dir = root.join("scratch");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::create_dir_all(&scratch_dir).unwrap();
+        fs::write(config_dir.join("config.txt"), "hello").unwrap();
+
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.preopen_dir(&config_dir, "/config", true).unwrap();
+        config.preopen_dir(&scratch_dir, "/scratch", false).unwrap();
+        config.set_max_disk_usage(Some(8));
+        assert!(config
+            .preopen_dir(config_dir.join("config.txt"), "/file", false)
+            .is_err());
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\64\00\00\00\05\00\00\00")
+                (data (i32.const 8) "\c8\00\00\00\0a\00\00\00")
+                (data (i32.const 32) "config.txt")
+                (data (i32.const 48) "copy.txt")
+                (data (i32.const 64) "new.txt")
+                (data (i32.const 80) "big.txt")
+                (data (i32.const 200) "0123456789")
+                (func (export "run")
+                    ;; Copy config.txt into the scratch directory
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 32) (i32.const 10)
+                        (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    (drop (call $fd_read (i32.load (i32.const 20)) (i32.const 0) (i32.const 1) (i32.const 16)))
+                    (drop (call $path_open (i32.const 4) (i32.const 0) (i32.const 48) (i32.const 8)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    (drop (call $fd_write (i32.load (i32.const 20)) (i32.const 0) (i32.const 1) (i32.const 16)))
+                    ;; Creating files in a read-only directory fails
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 64) (i32.const 7)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    ;; Only 3 bytes of the quota are left
+                    (drop (call $path_open (i32.const 4) (i32.const 0) (i32.const 80) (i32.const 7)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    (drop (call $fd_write (i32.load (i32.const 20)) (i32.const 8) (i32.const 1) (i32.const 16)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, _) = module.spawn("run", Vec::new(), None).await.unwrap();
+        task.await;
+
+        let copy = fs::read_to_string(scratch_dir.join("copy.txt"));
+        let new = config_dir.join("new.txt").exists();
+        let big = fs::read(scratch_dir.join("big.txt"));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(copy.unwrap(), "hello");
+        assert!(!new);
+        assert!(big.unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn private_mount_releases_quota() {
+        let (sender, receiver) = unbounded();
+        let output = WasmProcess::new(Uuid::new_v4(), sender);
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.mount_virtual_fs(VirtualFs::new(), "/tmp", false);
+        config.set_max_disk_usage(Some(8));
+        config.set_wasi_stdout(WasiOutput::Process(Arc::new(output), None));
+        let environment = Environment::new(config).unwrap();
+        // Writes 8 bytes into a scratch file and prints the error number of the write
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\40\00\00\00\08\00\00\00")
+                (data (i32.const 8) "\18\00\00\00\04\00\00\00")
+                (data (i32.const 32) "scratch.txt")
+                (data (i32.const 64) "01234567")
+                (func (export "run")
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 32) (i32.const 11)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    (i32.store (i32.const 24) (call $fd_write (i32.load (i32.const 20)) (i32.const 0) (i32.const 1) (i32.const 16)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 8) (i32.const 1) (i32.const 16)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        // Each process writes into its own copy, the usage is released when it finishes
+        for _ in 0..2 {
+            let (task, _) = module.spawn("run", Vec::new(), None).await.unwrap();
+            task.await;
+            match receiver.try_recv() {
+                Ok(Signal::Message(Message::Data(data))) => assert_eq!(data.buffer(), [0; 4]),
+                _ => panic!("Expected the error number of the write"),
+            }
+        }
+    }
+}
@@ -1,14 +1,15 @@
 use std::{collections::HashMap, fmt::Debug, future::Future, hash::Hash, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::{debug, trace};
+use serde::{Deserialize, Serialize};
 
-use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::channel::{bounded, unbounded, Receiver, Sender};
 use async_std::task::JoinHandle;
 
 use uuid::Uuid;
 
-use crate::{mailbox::MessageMailbox, message::Message};
+use crate::{mailbox::MessageMailbox, message::Message, module::Module, snapshot::Snapshot};
 
 /// The `Process` is the main abstraction unit in lunatic.
 ///
@@ -55,11 +56,22 @@ pub enum Signal {
     Link(Option<i64>, Arc<dyn Process>),
     // Request from a process to be unlinked
     UnLink(Arc<dyn Process>),
+    // Sent from a process that wants to be notified if this process dies. Unlike links, monitors
+    // are one-directional and the monitoring process receives a `Message::Signal` with the tag
+    // and exit reason, it's never killed.
+    Monitor(Option<i64>, Arc<dyn Process>),
     // Sent to linked processes when the link dies. Contains the tag used when the link was
-    // established. Depending on the value of `die_when_link_dies` (default is `true`) this
-    // receiving process will turn this signal into a message or the process will immediately
-    // die as well.
-    LinkDied(Option<i64>),
+    // established and the reason of death. Depending on the value of `die_when_link_dies`
+    // (default is `true`) this receiving process will turn this signal into a message or the
+    // process will immediately die as well.
+    LinkDied(Option<i64>, ExitReason),
+    // Request to continue running a new version of the module. The signal is turned into a
+    // message and the process decides when it's safe to upgrade. If a version is given, the
+    // process is moved to it in the registry of its environment once it upgrades.
+    Upgrade(Module, Option<String>),
+    // Request to suspend the process and send a snapshot of it to the channel. The signal is
+    // turned into a message and the process decides when it's safe to suspend.
+    Suspend(Sender<Snapshot>),
 }
 
 impl Debug for Signal {
@@ -70,11 +82,82 @@ impl Debug for Signal {
             Self::DieWhenLinkDies(_) => write!(f, "DieWhenLinkDies"),
             Self::Link(_, _) => write!(f, "Link"),
             Self::UnLink(_) => write!(f, "UnLink"),
-            Self::LinkDied(_) => write!(f, "LinkDied"),
+            Self::Monitor(_, _) => write!(f, "Monitor"),
+            Self::LinkDied(_, _) => write!(f, "LinkDied"),
+            Self::Upgrade(_, _) => write!(f, "Upgrade"),
+            Self::Suspend(_) => write!(f, "Suspend"),
         }
     }
 }
 
+/// The reason a process died, reported to all linked and monitoring processes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitReason {
+    /// The process returned from its entry function or exited with status 0. Only reported to
+    /// monitoring processes, linked processes are not affected.
+    Normal,
+    /// The process trapped or returned an error.
+    Failure,
+    /// The process was killed by a signal or because a linked process died.
+    Killed,
+    /// The process used up all of its fuel.
+    OutOfFuel,
+    /// The process used up all of its CPU time.
+    OutOfCpuTime,
+    /// The process was still running after its deadline.
+    DeadlineExceeded,
+}
+
+impl ExitReason {
+    /// Numeric representation of the exit reason used by the guest API.
+    pub fn code(&self) -> u32 {
+        match self {
+            ExitReason::Normal => 0,
+            ExitReason::Failure => 1,
+            ExitReason::Killed => 2,
+            ExitReason::OutOfFuel => 3,
+            ExitReason::OutOfCpuTime => 4,
+            ExitReason::DeadlineExceeded => 5,
+        }
+    }
+}
+
+/// Error returned from a process that was terminated because it used up all of its fuel.
+#[derive(Debug)]
+pub struct OutOfFuel;
+
+impl std::fmt::Display for OutOfFuel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process used up all of its fuel")
+    }
+}
+
+impl std::error::Error for OutOfFuel {}
+
+/// Error returned from a process that was terminated because it used up all of its CPU time.
+#[derive(Debug)]
+pub struct OutOfCpuTime;
+
+impl std::fmt::Display for OutOfCpuTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process used up all of its CPU time")
+    }
+}
+
+impl std::error::Error for OutOfCpuTime {}
+
+/// Error returned from a process that was terminated because it exceeded its deadline.
+#[derive(Debug)]
+pub struct DeadlineExceeded;
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process exceeded its deadline")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
 /// The reason of a process finishing
 pub enum Finished<T> {
     /// This just means that the process finished without external interaction.
@@ -100,6 +183,19 @@ impl WasmProcess {
     pub fn new(id: Uuid, signal_mailbox: Sender<Signal>) -> Self {
         Self { id, signal_mailbox }
     }
+
+    /// Asks the process to suspend itself and returns a snapshot of its state.
+    ///
+    /// Processes only suspend at a point they consider safe (see the [`snapshot`](crate::snapshot)
+    /// module), this call waits until they do. Fails if the process finishes without suspending.
+    pub async fn suspend(&self) -> Result<Snapshot> {
+        let (sender, receiver) = bounded(1);
+        self.send(Signal::Suspend(sender));
+        receiver
+            .recv()
+            .await
+            .map_err(|_| anyhow!("Process finished without suspending"))
+    }
 }
 
 impl Process for WasmProcess {
@@ -133,6 +229,8 @@ pub(crate) async fn new<F, T>(
     let mut die_when_link_dies = true;
     // Process linked to this one
     let mut links = HashMap::new();
+    // Processes monitoring this one
+    let mut monitors = HashMap::new();
     // TODO: Maybe wrapping this in some kind of `std::panic::catch_unwind` wold be a good idea,
     //       to protect against panics in host function calls that unwind through Wasm code.
     //       Currently a panic would just kill the task, but not notify linked processes.
@@ -148,20 +246,26 @@ pub(crate) async fn new<F, T>(
                     Ok(Signal::Link(tag, proc)) => { links.insert(proc, tag); },
                     // Remove process from list
                     Ok(Signal::UnLink(proc)) => { links.remove(&proc); }
+                    // Put process into list of monitoring processes
+                    Ok(Signal::Monitor(tag, proc)) => { monitors.insert(proc, tag); },
                     // Exit loop and don't poll anymore the future if Signal::Kill received.
                     Ok(Signal::Kill) => break Finished::Signal(Signal::Kill),
                     // Depending if `die_when_link_dies` is set, process will die or turn the
                     // signal into a message
-                    Ok(Signal::LinkDied(tag)) => {
+                    Ok(Signal::LinkDied(tag, reason)) => {
                         if die_when_link_dies {
                             // Even this was not a **kill** signal it has the same effect on
                             // this process and should be propagated as such.
                             break Finished::Signal(Signal::Kill)
                         } else {
-                            let message = Message::Signal(tag);
+                            let message = Message::Signal(tag, reason);
                             message_mailbox.push(message);
                         }
                     },
+                    Ok(Signal::Upgrade(module, version)) => {
+                        message_mailbox.push(Message::Upgrade(module, version))
+                    }
+                    Ok(Signal::Suspend(sender)) => message_mailbox.push(Message::Suspend(sender)),
                     Err(_) => unreachable!("The process holds the sending side and is never closed")
                 }
             }
@@ -175,27 +279,47 @@ pub(crate) async fn new<F, T>(
             if let Some(trap) = err.downcast_ref::<wasmtime::Trap>() {
                 if let Some(exit_status) = trap.i32_exit_status() {
                     if exit_status == 0 {
+                        notify_monitors(&monitors, ExitReason::Normal);
                         return;
                     }
                 }
             };
             debug!("Process {} failed: {}", id, err);
+            let reason = if err.downcast_ref::<OutOfFuel>().is_some() {
+                ExitReason::OutOfFuel
+            } else if err.downcast_ref::<OutOfCpuTime>().is_some() {
+                ExitReason::OutOfCpuTime
+            } else if err.downcast_ref::<DeadlineExceeded>().is_some() {
+                ExitReason::DeadlineExceeded
+            } else {
+                ExitReason::Failure
+            };
             // Notify all links that we finished with an error
             links.iter().for_each(|(proc, tag)| {
-                let _ = proc.send(Signal::LinkDied(*tag));
+                let _ = proc.send(Signal::LinkDied(*tag, reason));
             });
+            notify_monitors(&monitors, reason);
         }
         Finished::Signal(Signal::Kill) => {
             debug!("Process {} was killed", id);
             // Notify all links that we finished because of a kill signal
             links.iter().for_each(|(proc, tag)| {
-                let _ = proc.send(Signal::LinkDied(*tag));
+                let _ = proc.send(Signal::LinkDied(*tag, ExitReason::Killed));
             });
+            notify_monitors(&monitors, ExitReason::Killed);
         }
-        _ => {} // Finished normally
+        Finished::Normal(Ok(_)) => notify_monitors(&monitors, ExitReason::Normal),
+        Finished::Signal(_) => {}
     }
 }
 
+// Sends a signal message with the exit reason to all monitoring processes.
+fn notify_monitors(monitors: &HashMap<Arc<dyn Process>, Option<i64>>, reason: ExitReason) {
+    monitors.iter().for_each(|(proc, tag)| {
+        proc.send(Signal::Message(Message::Signal(*tag, reason)));
+    });
+}
+
 /// A process spawned from a native Rust closure.
 #[derive(Clone, Debug)]
 pub struct NativeProcess {
@@ -0,0 +1,529 @@
+/*!
+Environment wide quotas.
+
+Limits set directly on an [`EnvConfig`](crate::EnvConfig), like `max_memory`, apply to each process
+separately. Quotas are shared between all processes spawned into the same
+[`Environment`](crate::Environment) and allow us to put a hard ceiling on the resources used by
+all of them together:
+
+* Total memory used by all processes
+* Number of processes alive at the same time
+* Fuel consumed by all processes inside of a time window
+* Number of TCP streams held by all processes
+* Bytes written into preopened directories by all processes
+
+Environments created from inside of a process are bound by the quotas of the environment they were
+created from. Resources used inside of them are counted against the quotas of all ancestors.
+*/
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{environment::UNIT_OF_COMPUTE_IN_INSTRUCTIONS, EnvConfig};
+
+// Quotas and current resource usage of an environment.
+pub(crate) struct EnvQuota {
+    parent: Option<Arc<EnvQuota>>,
+    memory: Counter,
+    processes: Counter,
+    tcp_streams: Counter,
+    disk: Counter,
+    fuel: Option<Mutex<FuelWindow>>,
+}
+
+impl EnvQuota {
+    pub(crate) fn new(config: &EnvConfig, parent: Option<Arc<EnvQuota>>) -> Self {
+        Self {
+            parent,
+            memory: Counter::new(config.max_total_memory()),
+            processes: Counter::new(config.max_processes()),
+            tcp_streams: Counter::new(config.max_tcp_streams()),
+            disk: Counter::new(config.max_disk_usage()),
+            fuel: config
+                .max_fuel_per_window()
+                .map(|(budget, window)| Mutex::new(FuelWindow::new(budget, window))),
+        }
+    }
+
+    // Reserves a process slot in this environment and all ancestors.
+    //
+    // The returned guard is used to track all other resources used by the process and will
+    // release them once it's dropped.
+    pub(crate) fn spawn(self: &Arc<Self>) -> Result<ProcessQuota> {
+        if !self.try_reserve(|quota| &quota.processes, 1) {
+            return Err(anyhow!(
+                "Environment reached the maximum number of processes"
+            ));
+        }
+        Ok(ProcessQuota {
+            env: self.clone(),
+            memory: 0,
+            tcp_streams: 0,
+        })
+    }
+
+    // Returns true if this environment or any ancestor has a fuel quota.
+    pub(crate) fn has_fuel_quota(&self) -> bool {
+        self.fuel.is_some()
+            || self
+                .parent
+                .as_ref()
+                .map_or(false, |parent| parent.has_fuel_quota())
+    }
+
+    // Returns true if this environment or any ancestor has a disk quota.
+    pub(crate) fn has_disk_quota(&self) -> bool {
+        self.disk.limit.is_some()
+            || self
+                .parent
+                .as_ref()
+                .map_or(false, |parent| parent.has_disk_quota())
+    }
+
+    // Disk usage outlives processes, so it's tracked directly on the environment and not released
+    // when a process finishes. Only usage of process-private file systems is given back when they
+    // are dropped (see `preopen`).
+    pub(crate) fn try_reserve_disk(&self, bytes: usize) -> bool {
+        self.try_reserve(|quota| &quota.disk, bytes)
+    }
+
+    pub(crate) fn force_reserve_disk(&self, bytes: usize) {
+        self.force_reserve(|quota| &quota.disk, bytes);
+    }
+
+    pub(crate) fn release_disk(&self, bytes: usize) {
+        // Files created before the quota was tracked can release more than was reserved
+        let release = |quota: &EnvQuota| {
+            let _ = quota
+                .disk
+                .used
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                    Some(used.saturating_sub(bytes))
+                });
+        };
+        let mut quota = Some(self);
+        while let Some(current) = quota {
+            release(current);
+            quota = current.parent.as_deref();
+        }
+    }
+
+    // Charges fuel for `instructions` to this environment and all ancestors.
+    //
+    // If the fuel budget of any of them is exhausted nothing is charged and the time when the
+    // latest of the exhausted windows resets is returned.
+    fn charge_fuel(&self, instructions: u64) -> Result<(), Instant> {
+        let now = Instant::now();
+        let mut windows = Vec::new();
+        let mut quota = Some(self);
+        while let Some(current) = quota {
+            if let Some(window) = &current.fuel {
+                windows.push(window.lock().unwrap());
+            }
+            quota = current.parent.as_deref();
+        }
+        let resume_at = windows
+            .iter_mut()
+            .filter_map(|window| window.exhausted_until(now, instructions))
+            .max();
+        match resume_at {
+            Some(resume_at) => Err(resume_at),
+            None => {
+                windows
+                    .iter_mut()
+                    .for_each(|window| window.used += instructions);
+                Ok(())
+            }
+        }
+    }
+
+    // Corrects the fuel charged to this environment and all ancestors for a process once the exact
+    // amount it consumed is known. Missing fuel is charged even if it exceeds the budget, because it
+    // was already used, and overcharged fuel is given back to the current window.
+    fn adjust_fuel(&self, charged: u64, consumed: u64) {
+        let mut quota = Some(self);
+        while let Some(current) = quota {
+            if let Some(window) = &current.fuel {
+                let mut window = window.lock().unwrap();
+                window.used = window.used.saturating_add(consumed).saturating_sub(charged);
+            }
+            quota = current.parent.as_deref();
+        }
+    }
+
+    // Tries to reserve `amount` of the counter in this environment and all ancestors. If any of
+    // them doesn't have enough space left, nothing is reserved.
+    fn try_reserve(&self, counter: fn(&EnvQuota) -> &Counter, amount: usize) -> bool {
+        if !counter(self).try_add(amount) {
+            return false;
+        }
+        if let Some(parent) = &self.parent {
+            if !parent.try_reserve(counter, amount) {
+                counter(self).sub(amount);
+                return false;
+            }
+        }
+        true
+    }
+
+    // Reserves `amount` of the counter in this environment and all ancestors, ignoring limits.
+    fn force_reserve(&self, counter: fn(&EnvQuota) -> &Counter, amount: usize) {
+        counter(self).used.fetch_add(amount, Ordering::SeqCst);
+        if let Some(parent) = &self.parent {
+            parent.force_reserve(counter, amount);
+        }
+    }
+
+    fn release(&self, counter: fn(&EnvQuota) -> &Counter, amount: usize) {
+        counter(self).sub(amount);
+        if let Some(parent) = &self.parent {
+            parent.release(counter, amount);
+        }
+    }
+}
+
+// Resources used by a single process that are counted against the environment's quotas.
+//
+// All of them are released when the process finishes and its state is dropped.
+pub(crate) struct ProcessQuota {
+    env: Arc<EnvQuota>,
+    memory: usize,
+    tcp_streams: usize,
+}
+
+impl ProcessQuota {
+    pub(crate) fn env(&self) -> &Arc<EnvQuota> {
+        &self.env
+    }
+
+    // Memory used by all memories of the process in bytes.
+    pub(crate) fn memory(&self) -> usize {
+        self.memory
+    }
+
+    // Tries to grow the memory used by the process by `bytes`.
+    pub(crate) fn try_grow_memory(&mut self, bytes: usize) -> bool {
+        if self.env.try_reserve(|quota| &quota.memory, bytes) {
+            self.memory += bytes;
+            true
+        } else {
+            false
+        }
+    }
+
+    // Releases the memory of an instance that was dropped while the process keeps running.
+    pub(crate) fn release_memory(&mut self) {
+        self.env.release(|quota| &quota.memory, self.memory);
+        self.memory = 0;
+    }
+
+    // Tries to reserve space for a newly opened TCP stream.
+    pub(crate) fn try_add_tcp_stream(&mut self) -> Result<()> {
+        if self.env.try_reserve(|quota| &quota.tcp_streams, 1) {
+            self.tcp_streams += 1;
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Environment reached the maximum number of TCP streams"
+            ))
+        }
+    }
+
+    // Counts a TCP stream that was not newly opened (e.g. received in a message or cloned)
+    // without enforcing the limit.
+    pub(crate) fn add_tcp_stream(&mut self) {
+        self.env.force_reserve(|quota| &quota.tcp_streams, 1);
+        self.tcp_streams += 1;
+    }
+
+    pub(crate) fn remove_tcp_stream(&mut self) {
+        self.env.release(|quota| &quota.tcp_streams, 1);
+        self.tcp_streams -= 1;
+    }
+}
+
+impl Drop for ProcessQuota {
+    fn drop(&mut self) {
+        self.env.release(|quota| &quota.processes, 1);
+        self.env.release(|quota| &quota.memory, self.memory);
+        self.env
+            .release(|quota| &quota.tcp_streams, self.tcp_streams);
+    }
+}
+
+struct Counter {
+    limit: Option<usize>,
+    used: AtomicUsize,
+}
+
+impl Counter {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    fn try_add(&self, amount: usize) -> bool {
+        match self.limit {
+            Some(limit) => self
+                .used
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                    used.checked_add(amount).filter(|total| *total <= limit)
+                })
+                .is_ok(),
+            None => {
+                self.used.fetch_add(amount, Ordering::SeqCst);
+                true
+            }
+        }
+    }
+
+    fn sub(&self, amount: usize) {
+        self.used.fetch_sub(amount, Ordering::SeqCst);
+    }
+}
+
+struct FuelWindow {
+    // Budget and used fuel in instructions.
+    budget: u64,
+    length: Duration,
+    start: Instant,
+    used: u64,
+}
+
+impl FuelWindow {
+    fn new(budget: u64, length: Duration) -> Self {
+        Self {
+            budget: budget.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS),
+            length,
+            start: Instant::now(),
+            used: 0,
+        }
+    }
+
+    // Returns the time when the window resets if there is not enough fuel left for `instructions`.
+    fn exhausted_until(&mut self, now: Instant, instructions: u64) -> Option<Instant> {
+        if now >= self.start + self.length {
+            self.start = now;
+            self.used = 0;
+        }
+        if self.used + instructions > self.budget {
+            Some(self.start + self.length)
+        } else {
+            None
+        }
+    }
+}
+
+// Charges the fuel consumed by a process against the environment's fuel quota.
+//
+// The store can only be inspected while the process isn't running Wasm code, so the exact amount of
+// consumed fuel is only known once an instance stops. Until then the fuel is estimated: processes
+// run with a fixed amount of fuel and once it runs out the store injects one time slice worth of
+// fuel and yields by waking the task and returning `Poll::Pending`. This future detects these
+// yields by wrapping the task's waker and charges one time slice for each of them. The process adds
+// the fuel consumed by each instance to `consumed` once it stops and when the process finishes the
+// estimate is replaced by the exact amount. This charges the last partial time slice and gives back
+// fuel charged for host functions that wake the task during a poll.
+//
+// If the fuel budget of the current window is exhausted, the process is not polled again until the
+// window resets.
+pub(crate) struct FuelMetered<F> {
+    inner: Pin<Box<F>>,
+    quota: Option<Arc<EnvQuota>>,
+    // Instructions executed between two yields.
+    time_slice: u64,
+    // Estimated fuel charged so far.
+    charged: u64,
+    // Exact fuel consumed by all stopped instances of the process.
+    consumed: Arc<AtomicU64>,
+    detector: Arc<YieldDetector>,
+    waker: Option<Waker>,
+    // A time slice that was consumed, but could not be charged yet.
+    uncharged: bool,
+    throttle: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<F: Future> FuelMetered<F> {
+    pub(crate) fn new(
+        inner: F,
+        quota: &Arc<EnvQuota>,
+        time_slice: u64,
+        consumed: Arc<AtomicU64>,
+    ) -> Self {
+        let quota = if quota.has_fuel_quota() {
+            Some(quota.clone())
+        } else {
+            None
+        };
+        Self {
+            inner: Box::pin(inner),
+            quota,
+            time_slice,
+            charged: 0,
+            consumed,
+            detector: Arc::new(YieldDetector::default()),
+            waker: None,
+            uncharged: false,
+            throttle: None,
+        }
+    }
+}
+
+impl<F: Future> Future for FuelMetered<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let quota = match &this.quota {
+            Some(quota) => quota,
+            // No quota defined, just poll the inner future.
+            None => return this.inner.as_mut().poll(cx),
+        };
+        loop {
+            if let Some(throttle) = this.throttle.as_mut() {
+                if throttle.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.throttle = None;
+            }
+            if !this.uncharged {
+                break;
+            }
+            match quota.charge_fuel(this.time_slice) {
+                Ok(()) => {
+                    this.charged += this.time_slice;
+                    this.uncharged = false;
+                }
+                Err(resume_at) => {
+                    let wait = resume_at.saturating_duration_since(Instant::now());
+                    this.throttle = Some(Box::pin(async_std::task::sleep(wait)));
+                }
+            }
+        }
+
+        *this.detector.waker.lock().unwrap() = Some(cx.waker().clone());
+        this.detector.woken.store(false, Ordering::SeqCst);
+        let detector = &this.detector;
+        let waker = this
+            .waker
+            .get_or_insert_with(|| Waker::from(detector.clone()));
+        let result = this.inner.as_mut().poll(&mut Context::from_waker(waker));
+        match result {
+            Poll::Pending if this.detector.woken.swap(false, Ordering::SeqCst) => {
+                this.uncharged = true
+            }
+            Poll::Pending => {}
+            Poll::Ready(_) => quota.adjust_fuel(this.charged, this.consumed.load(Ordering::SeqCst)),
+        }
+        result
+    }
+}
+
+// Forwards wake-ups to the task's waker and remembers if one happened.
+#[derive(Default)]
+struct YieldDetector {
+    woken: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Wake for YieldDetector {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::SeqCst);
+        if let Some(waker) = self.waker.lock().unwrap().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::EnvQuota;
+    use crate::{environment::UNIT_OF_COMPUTE_IN_INSTRUCTIONS, EnvConfig, Environment};
+
+    #[test]
+    fn process_and_memory_quotas() {
+        let mut config = EnvConfig::default();
+        config.set_max_processes(Some(2));
+        config.set_max_total_memory(Some(100));
+        let parent = Arc::new(EnvQuota::new(&config, None));
+        let child = Arc::new(EnvQuota::new(&EnvConfig::default(), Some(parent.clone())));
+
+        let mut first = parent.spawn().unwrap();
+        let mut second = child.spawn().unwrap();
+        // The child environment counts against the parent's quota
+        assert!(child.spawn().is_err());
+        assert!(first.try_grow_memory(60));
+        assert!(!second.try_grow_memory(60));
+        assert!(second.try_grow_memory(40));
+
+        // Finished processes release their resources
+        drop(first);
+        let mut third = child.spawn().unwrap();
+        assert!(third.try_grow_memory(60));
+    }
+
+    #[test]
+    fn fuel_window() {
+        let mut config = EnvConfig::default();
+        config.set_max_fuel_per_window(Some((2, Duration::from_secs(60))));
+        let quota = EnvQuota::new(&config, None);
+        assert!(quota.charge_fuel(UNIT_OF_COMPUTE_IN_INSTRUCTIONS).is_ok());
+        assert!(quota.charge_fuel(UNIT_OF_COMPUTE_IN_INSTRUCTIONS).is_ok());
+        assert!(quota.charge_fuel(1).is_err());
+
+        // Estimates are corrected once the exact amount is known
+        quota.adjust_fuel(UNIT_OF_COMPUTE_IN_INSTRUCTIONS, 10);
+        assert!(quota
+            .charge_fuel(UNIT_OF_COMPUTE_IN_INSTRUCTIONS - 10)
+            .is_ok());
+        assert!(quota.charge_fuel(1).is_err());
+    }
+
+    #[async_std::test]
+    async fn exact_fuel_charge() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.set_max_fuel_per_window(Some((1, Duration::from_secs(60))));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (func (export "run") (local i32)
+                    (loop
+                        (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+                        (br_if 0 (i32.lt_u (local.get 0) (i32.const 100))))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, _) = module.spawn("run", Vec::new(), None).await.unwrap();
+        task.await;
+        // Only the fuel used by the process is charged, not the whole time slice
+        let used = environment
+            .quota()
+            .fuel
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .used;
+        assert!(used > 0 && used < UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+    }
+}
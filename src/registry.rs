@@ -10,6 +10,7 @@ use std::{
 
 use anyhow::Result;
 use semver::{Version, VersionReq};
+use uuid::Uuid;
 
 use crate::Process;
 
@@ -60,6 +61,28 @@ impl LocalRegistry {
         Ok(None)
     }
 
+    /// Moves all entries of the process with `process_id` to a new version.
+    ///
+    /// The version needs to be a correct semver string or the update will fail. Other processes
+    /// registered under the same name and the new version are overwritten.
+    pub fn update_version(&self, process_id: Uuid, version: &str) -> Result<()> {
+        let version = Version::parse(version)?;
+        let mut writer = self.map.as_ref().write().unwrap();
+        for results in writer.values_mut() {
+            let process = match results
+                .iter()
+                .find(|entry| entry.process.id() == process_id)
+            {
+                Some(entry) => entry.process(),
+                None => continue,
+            };
+            results
+                .retain(|entry| entry.process.id() != process_id && !version.eq(entry.version()));
+            results.push(RegistryEntry::new(version.clone(), process));
+        }
+        Ok(())
+    }
+
     /// Returns process under name & version.
     ///
     /// Semver is used for matching.
@@ -152,5 +175,18 @@ mod tests {
         // Looking up ^1 again should return the only left match
         let result = registry.get("test", "^1").unwrap().unwrap();
         assert_eq!(result.id(), proc1.id());
+
+        // Updating the version moves all entries of the process
+        let result = registry.insert("other".to_string(), "1.0.0", proc1.clone());
+        assert!(result.is_ok());
+        assert!(registry.update_version(proc1.id(), "2.0").is_err());
+        let result = registry.update_version(proc1.id(), "2.0.0");
+        assert!(result.is_ok());
+        assert!(registry.get("test", "^1").unwrap().is_none());
+        assert!(registry.get("other", "^1").unwrap().is_none());
+        let result = registry.get("test", "^2").unwrap().unwrap();
+        assert_eq!(result.id(), proc1.id());
+        let result = registry.get("other", "^2").unwrap().unwrap();
+        assert_eq!(result.id(), proc1.id());
     }
 }
@@ -0,0 +1,512 @@
+/*!
+Fair scheduling of Wasm processes.
+
+Processes are regular async tasks running on the async-std executor, but the executor doesn't
+know anything about environments. If one environment spawns many CPU heavy processes, they will
+crowd out processes from all other environments. To prevent this, every poll of a Wasm process
+needs to acquire one of a limited number of run slots from the lunatic scheduler first (by default
+as many as there are CPUs). Processes waiting for a slot are put into a run queue ordered by:
+
+1. The [`Priority`] of the environment. Processes from higher priority environments always run
+   before processes from lower priority ones.
+2. The virtual runtime of the environment. Each time a process finishes running, the time it took
+   is added to the virtual runtime of its environment, scaled down by the environment's weight.
+   The environment that used the least CPU time relative to its weight runs next.
+
+Processes don't give up their slot until they yield. This happens each time they wait on a host
+function (e.g. receiving a message) and each time they use up a time slice, an amount of fuel
+configured with [`EnvConfig::set_time_slice`](crate::EnvConfig::set_time_slice). Counting fuel slows
+down the execution of every instruction, but the Wasmtime version used by the runtime can only
+interrupt running code by trapping and not resume it later, so fuel is the only way to preempt
+processes.
+
+The CPU time used by each process is measured with a wall clock while it's being polled. If a
+process exceeds its CPU time limit
+([`EnvConfig::set_max_cpu_time`](crate::EnvConfig::set_max_cpu_time)), a watchdog thread
+interrupts it.
+
+Environments created from inside of a process share the scheduling group of the environment they
+were created from, so that a process can't gain more CPU time by spawning processes into new
+environments.
+*/
+
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::BinaryHeap,
+    convert::TryFrom,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Once, Weak,
+    },
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use wasmtime::InterruptHandle;
+
+/// The default weight of an environment.
+pub const DEFAULT_WEIGHT: u32 = 100;
+
+/// Priority of processes spawned into an environment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// A snapshot of the scheduler's state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedulerMetrics {
+    /// Number of processes that can run at the same time.
+    pub slots: usize,
+    /// Number of processes currently running.
+    pub running: usize,
+    /// Number of processes waiting for a free slot.
+    pub run_queue_length: usize,
+    /// The longest the run queue was since the metrics were last reset.
+    pub max_run_queue_length: usize,
+}
+
+/// Returns the current state of the scheduler.
+pub fn metrics() -> SchedulerMetrics {
+    SCHEDULER.metrics()
+}
+
+/// Resets `max_run_queue_length` to the current length of the run queue.
+pub fn reset_metrics() {
+    let mut state = SCHEDULER.state.lock().unwrap();
+    state.max_queued = state.queued;
+}
+
+/// Set the number of processes that can run at the same time.
+///
+/// Defaults to the number of CPUs.
+pub fn set_slots(slots: usize) {
+    let wakers = {
+        let mut state = SCHEDULER.state.lock().unwrap();
+        state.slots = slots.max(1);
+        state.dispatch()
+    };
+    wakers.into_iter().for_each(Waker::wake);
+}
+
+// How often the watchdog checks if processes exceeded their CPU time limits.
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(5);
+
+lazy_static! {
+    static ref SCHEDULER: Scheduler = Scheduler::new(num_cpus::get());
+    static ref WATCHDOG: Mutex<Vec<Weak<CpuTimer>>> = Mutex::new(Vec::new());
+}
+
+// Scheduling parameters and CPU time used by all processes of an environment.
+pub(crate) struct SchedulingGroup {
+    weight: u32,
+    priority: Priority,
+    // CPU time used in nanoseconds, scaled by the weight.
+    vruntime: AtomicU64,
+}
+
+impl SchedulingGroup {
+    pub(crate) fn new(weight: u32, priority: Priority) -> Self {
+        Self {
+            weight: weight.max(1),
+            priority,
+            vruntime: AtomicU64::new(0),
+        }
+    }
+
+    fn charge(&self, elapsed: Duration) {
+        let scaled = elapsed.as_nanos() * DEFAULT_WEIGHT as u128 / self.weight as u128;
+        let scaled = u64::try_from(scaled).unwrap_or(u64::MAX);
+        let _ = self
+            .vruntime
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |vruntime| {
+                Some(vruntime.saturating_add(scaled))
+            });
+    }
+}
+
+struct Scheduler {
+    state: Mutex<State>,
+}
+
+impl Scheduler {
+    fn new(slots: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                slots: slots.max(1),
+                running: 0,
+                queue: BinaryHeap::new(),
+                queued: 0,
+                max_queued: 0,
+                next_seq: 0,
+                min_vruntime: 0,
+            }),
+        }
+    }
+
+    // Returns true if the ticket holds a slot and the process can run. Otherwise the process is
+    // put into the run queue and woken up once a slot was assigned to it.
+    fn acquire(&self, group: &SchedulingGroup, ticket: &Arc<Ticket>, waker: &Waker) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let mut ticket_state = ticket.state.lock().unwrap();
+        match &mut *ticket_state {
+            TicketState::Granted => {
+                *ticket_state = TicketState::Idle;
+                true
+            }
+            TicketState::Queued(queued_waker) => {
+                if !queued_waker.will_wake(waker) {
+                    *queued_waker = waker.clone();
+                }
+                false
+            }
+            TicketState::Idle => {
+                if state.running < state.slots && state.queued == 0 {
+                    state.running += 1;
+                    return true;
+                }
+                // Don't let environments that were idle for a long time build up credit.
+                let vruntime = group
+                    .vruntime
+                    .fetch_max(state.min_vruntime, Ordering::SeqCst)
+                    .max(state.min_vruntime);
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.queue.push(Entry {
+                    priority: group.priority,
+                    vruntime,
+                    seq,
+                    ticket: ticket.clone(),
+                });
+                state.queued += 1;
+                state.max_queued = state.max_queued.max(state.queued);
+                *ticket_state = TicketState::Queued(waker.clone());
+                false
+            }
+        }
+    }
+
+    // Gives up the slot of a process that finished running.
+    fn release(&self, group: &SchedulingGroup, elapsed: Duration) {
+        group.charge(elapsed);
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            state.running -= 1;
+            state.dispatch()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    // Removes a ticket from the scheduler, giving up any slot that was assigned to it.
+    fn cancel(&self, ticket: &Ticket) {
+        let wakers = {
+            let mut state = self.state.lock().unwrap();
+            let previous = mem::replace(&mut *ticket.state.lock().unwrap(), TicketState::Idle);
+            match previous {
+                TicketState::Idle => return,
+                // The entry stays in the queue and is skipped once it's popped
+                TicketState::Queued(_) => state.queued -= 1,
+                TicketState::Granted => state.running -= 1,
+            }
+            state.dispatch()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    fn metrics(&self) -> SchedulerMetrics {
+        let state = self.state.lock().unwrap();
+        SchedulerMetrics {
+            slots: state.slots,
+            running: state.running,
+            run_queue_length: state.queued,
+            max_run_queue_length: state.max_queued,
+        }
+    }
+}
+
+struct State {
+    slots: usize,
+    running: usize,
+    queue: BinaryHeap<Entry>,
+    // Number of entries in the queue that were not cancelled.
+    queued: usize,
+    max_queued: usize,
+    next_seq: u64,
+    // Virtual runtime of the last process that got a slot.
+    min_vruntime: u64,
+}
+
+impl State {
+    // Assigns free slots to queued processes and returns the wakers of processes that need to be
+    // woken up. Wakers are called after the lock is released.
+    fn dispatch(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        while self.running < self.slots {
+            let entry = match self.queue.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let mut ticket_state = entry.ticket.state.lock().unwrap();
+            if let TicketState::Queued(waker) =
+                mem::replace(&mut *ticket_state, TicketState::Granted)
+            {
+                self.running += 1;
+                self.queued -= 1;
+                self.min_vruntime = self.min_vruntime.max(entry.vruntime);
+                wakers.push(waker);
+            } else {
+                // Cancelled
+                *ticket_state = TicketState::Idle;
+            }
+        }
+        wakers
+    }
+}
+
+struct Entry {
+    priority: Priority,
+    vruntime: u64,
+    seq: u64,
+    ticket: Arc<Ticket>,
+}
+
+// `BinaryHeap` is a max-heap, the entry that should run next is the "greatest" one.
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.priority
+            .cmp(&other.priority)
+            .then(other.vruntime.cmp(&self.vruntime))
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+#[derive(Default)]
+struct Ticket {
+    // Only modified while holding the scheduler's lock.
+    state: Mutex<TicketState>,
+}
+
+#[derive(Default)]
+enum TicketState {
+    #[default]
+    Idle,
+    Queued(Waker),
+    // A slot was assigned to the process, but it didn't run yet.
+    Granted,
+}
+
+// Measures the CPU time used by a process and interrupts it once it exceeds the limit.
+pub(crate) struct CpuTimer {
+    limit: Duration,
+    // CPU time used by finished polls and the start of the current poll.
+    used: Mutex<(Duration, Option<Instant>)>,
+    interrupt: Mutex<InterruptHandle>,
+    exceeded: AtomicBool,
+}
+
+impl CpuTimer {
+    pub(crate) fn new(limit: Duration, interrupt: InterruptHandle) -> Arc<Self> {
+        let timer = Arc::new(Self {
+            limit,
+            used: Mutex::new((Duration::ZERO, None)),
+            interrupt: Mutex::new(interrupt),
+            exceeded: AtomicBool::new(false),
+        });
+        WATCHDOG.lock().unwrap().push(Arc::downgrade(&timer));
+        static START_WATCHDOG: Once = Once::new();
+        START_WATCHDOG.call_once(|| {
+            thread::Builder::new()
+                .name("lunatic-watchdog".to_string())
+                .spawn(watchdog)
+                .expect("failed to spawn watchdog thread");
+        });
+        timer
+    }
+
+    // Returns true if the process was interrupted because it exceeded the limit.
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
+
+    // Points the timer to a new store of the same process, e.g. after a code change.
+    pub(crate) fn set_interrupt_handle(&self, interrupt: InterruptHandle) {
+        *self.interrupt.lock().unwrap() = interrupt;
+    }
+
+    fn start(&self) {
+        self.used.lock().unwrap().1 = Some(Instant::now());
+    }
+
+    fn stop(&self) {
+        let mut used = self.used.lock().unwrap();
+        if let Some(start) = used.1.take() {
+            used.0 += start.elapsed();
+        }
+    }
+
+    // Interrupts the process if it exceeded the limit. If the process is currently suspended, it
+    // traps as soon as it continues running.
+    fn check(&self) {
+        let used = {
+            let used = self.used.lock().unwrap();
+            used.0 + used.1.map_or(Duration::ZERO, |start| start.elapsed())
+        };
+        if used > self.limit && !self.exceeded.swap(true, Ordering::SeqCst) {
+            self.interrupt.lock().unwrap().interrupt();
+        }
+    }
+}
+
+fn watchdog() {
+    loop {
+        thread::sleep(WATCHDOG_INTERVAL);
+        let timers: Vec<Arc<CpuTimer>> = {
+            let mut timers = WATCHDOG.lock().unwrap();
+            // Forget finished processes
+            timers.retain(|timer| timer.strong_count() > 0);
+            timers.iter().filter_map(Weak::upgrade).collect()
+        };
+        timers.iter().for_each(|timer| timer.check());
+    }
+}
+
+// Runs the inner future only while holding a slot of the scheduler.
+pub(crate) struct Scheduled<F> {
+    inner: Pin<Box<F>>,
+    group: Arc<SchedulingGroup>,
+    ticket: Arc<Ticket>,
+    cpu_timer: Option<Arc<CpuTimer>>,
+}
+
+impl<F: Future> Scheduled<F> {
+    pub(crate) fn new(
+        inner: F,
+        group: Arc<SchedulingGroup>,
+        cpu_timer: Option<Arc<CpuTimer>>,
+    ) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            group,
+            ticket: Arc::new(Ticket::default()),
+            cpu_timer,
+        }
+    }
+}
+
+impl<F: Future> Future for Scheduled<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if !SCHEDULER.acquire(&this.group, &this.ticket, cx.waker()) {
+            return Poll::Pending;
+        }
+        if let Some(cpu_timer) = &this.cpu_timer {
+            cpu_timer.start();
+        }
+        let start = Instant::now();
+        let result = this.inner.as_mut().poll(cx);
+        SCHEDULER.release(&this.group, start.elapsed());
+        if let Some(cpu_timer) = &this.cpu_timer {
+            cpu_timer.stop();
+            cpu_timer.check();
+        }
+        result
+    }
+}
+
+impl<F> Drop for Scheduled<F> {
+    fn drop(&mut self) {
+        SCHEDULER.cancel(&self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        task::{Wake, Waker},
+        time::Duration,
+    };
+
+    use super::{Priority, Scheduler, SchedulingGroup, Ticket};
+
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn fair_run_queue() {
+        let scheduler = Scheduler::new(1);
+        let heavy = SchedulingGroup::new(100, Priority::Normal);
+        let light = SchedulingGroup::new(100, Priority::Normal);
+        let high = SchedulingGroup::new(100, Priority::High);
+        let tickets: Vec<Arc<Ticket>> = (0..4).map(|_| Arc::default()).collect();
+        let flags: Vec<Arc<Flag>> = (0..4).map(|_| Arc::default()).collect();
+        let wakers: Vec<Waker> = flags.iter().map(|flag| Waker::from(flag.clone())).collect();
+
+        // The heavy environment takes the only slot and used a lot of CPU time
+        assert!(scheduler.acquire(&heavy, &tickets[0], &wakers[0]));
+        heavy.charge(Duration::from_secs(1));
+        assert!(!scheduler.acquire(&heavy, &tickets[1], &wakers[1]));
+        assert!(!scheduler.acquire(&light, &tickets[2], &wakers[2]));
+        assert_eq!(scheduler.metrics().run_queue_length, 2);
+
+        // The light environment runs next
+        scheduler.release(&heavy, Duration::from_millis(1));
+        assert!(flags[2].0.load(Ordering::SeqCst));
+        assert!(scheduler.acquire(&light, &tickets[2], &wakers[2]));
+
+        // Higher priority wins over lower virtual runtime
+        assert!(!scheduler.acquire(&high, &tickets[3], &wakers[3]));
+        high.charge(Duration::from_secs(10));
+        scheduler.release(&light, Duration::from_millis(1));
+        assert!(flags[3].0.load(Ordering::SeqCst));
+        assert!(!flags[1].0.load(Ordering::SeqCst));
+
+        // Cancelling a granted ticket passes the slot on
+        scheduler.cancel(&tickets[3]);
+        assert!(flags[1].0.load(Ordering::SeqCst));
+        let metrics = scheduler.metrics();
+        assert_eq!(metrics.running, 1);
+        assert_eq!(metrics.run_queue_length, 0);
+        assert_eq!(metrics.max_run_queue_length, 2);
+    }
+
+    #[test]
+    fn weights() {
+        let group = SchedulingGroup::new(200, Priority::Normal);
+        group.charge(Duration::from_nanos(100));
+        assert_eq!(group.vruntime.load(Ordering::SeqCst), 50);
+    }
+}
@@ -0,0 +1,401 @@
+/*!
+Snapshots capture the state of a suspended Wasm process, so that it can be restored later in the
+same or another runtime.
+
+Wasm code can't be stopped at an arbitrary point and continued somewhere else, so processes
+suspend themselves cooperatively:
+
+1. A [`Signal::Suspend`](crate::Signal::Suspend) is sent to the process (e.g. with
+   [`WasmProcess::suspend`](crate::WasmProcess::suspend)) and turned into a suspend message.
+2. Once the process receives the message and reaches a safe point, usually the top of its receive
+   loop, it calls `lunatic::process::suspend`. This stops the process.
+3. The exported memories, the exported mutable globals and all messages still waiting in the
+   mailbox are captured in a [`Snapshot`].
+4. [`Module::restore`](crate::module::Module::restore) spawns a new process from the same module,
+   restores the captured state and calls the exported `lunatic_resume` function.
+
+The call stack is not part of the snapshot. Globals that are not exported start with their
+initial values, and resources held by the process (process handles, TCP streams, environments,
+...) are not carried over. A process can't suspend while messages carrying resources are waiting
+in its mailbox.
+*/
+
+use std::{collections::HashSet, io::Write};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasmparser::{ExternalKind, ImportSectionEntryType, Parser, Payload};
+use wasmtime::{AsContextMut, Caller, Extern, Instance, Mutability, Store, Val};
+
+use crate::{
+    message::{DataMessage, Message},
+    process::ExitReason,
+    state::ProcessState,
+};
+
+const WASM_PAGE_SIZE: usize = 0x10000;
+
+/// The state of a suspended Wasm process.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    // Hash of the module the process was spawned from
+    module_hash: [u8; 32],
+    memories: Vec<(String, Vec<u8>)>,
+    globals: Vec<(String, GlobalValue)>,
+    messages: Vec<SnapshotMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    // Floats are stored as bits to preserve NaN payloads
+    F32(u32),
+    F64(u64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum SnapshotMessage {
+    Data(Option<i64>, Vec<u8>),
+    Signal(Option<i64>, ExitReason),
+}
+
+impl Snapshot {
+    /// Serializes the snapshot, e.g. to store it or send it to another node.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Deserializes a snapshot created with [`to_bytes`](Snapshot::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    // Returns true if the message can be part of a snapshot.
+    pub(crate) fn can_capture(message: &Message) -> bool {
+        match message {
+            Message::Data(data) => !data.has_resources(),
+            Message::Signal(..) => true,
+            Message::Upgrade(_, _) | Message::Suspend(_) => false,
+        }
+    }
+
+    // Captures the state of a stopped process. All `messages` must be capturable.
+    pub(crate) fn capture(
+        mut store: impl AsContextMut<Data = ProcessState>,
+        instance: &Instance,
+        messages: Vec<Message>,
+    ) -> Result<Self> {
+        let exports: Vec<_> = instance
+            .exports(&mut store)
+            .map(|export| (export.name().to_string(), export.into_extern()))
+            .collect();
+
+        let module_hash = Sha256::digest(&store.as_context().data().module.data()).into();
+        let mut memories = Vec::new();
+        let mut globals = Vec::new();
+        for (name, export) in exports {
+            match export {
+                Extern::Memory(memory) => {
+                    memories.push((name, memory.data(store.as_context()).to_vec()))
+                }
+                Extern::Global(global)
+                    if global.ty(store.as_context()).mutability() == Mutability::Var =>
+                {
+                    let value = match global.get(store.as_context_mut()) {
+                        Val::I32(value) => GlobalValue::I32(value),
+                        Val::I64(value) => GlobalValue::I64(value),
+                        Val::F32(value) => GlobalValue::F32(value),
+                        Val::F64(value) => GlobalValue::F64(value),
+                        _ => return Err(anyhow!("Global `{}` can't be captured", name)),
+                    };
+                    globals.push((name, value));
+                }
+                _ => {}
+            }
+        }
+        let messages = messages
+            .into_iter()
+            .map(|message| match message {
+                Message::Data(ref data) => {
+                    SnapshotMessage::Data(message.tag(), data.buffer().to_vec())
+                }
+                Message::Signal(tag, reason) => SnapshotMessage::Signal(tag, reason),
+                _ => unreachable!("only capturable messages are passed in"),
+            })
+            .collect();
+        Ok(Self {
+            module_hash,
+            memories,
+            globals,
+            messages,
+        })
+    }
+
+    // Captures the memories and globals of the process that is calling a host function.
+    pub(crate) fn capture_caller(caller: &mut Caller<ProcessState>) -> Result<Self> {
+        let instance = caller
+            .data()
+            .instance
+            .ok_or_else(|| anyhow!("Process is not running"))?;
+        Self::capture(caller, &instance, Vec::new())
+    }
+
+    // Returns true if the snapshot was taken from a process spawned from the `data` module.
+    // Fails if the module has memories or mutable globals that are not exported. They can't be
+    // captured, so a process forked from a snapshot would start with a mix of the parent's state
+    // and the module's initial state.
+    pub(crate) fn check_forkable(data: &[u8]) -> Result<()> {
+        let mut memories = 0;
+        let mut mutable_globals = HashSet::new();
+        let mut globals = 0;
+        let mut exported_memories = HashSet::new();
+        let mut exported_globals = HashSet::new();
+        for payload in Parser::new(0).parse_all(data) {
+            match payload? {
+                Payload::ImportSection(imports) => {
+                    for import in imports {
+                        match import?.ty {
+                            ImportSectionEntryType::Memory(_) => memories += 1,
+                            ImportSectionEntryType::Global(ty) => {
+                                if ty.mutable {
+                                    mutable_globals.insert(globals);
+                                }
+                                globals += 1;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::MemorySection(section) => memories += section.get_count(),
+                Payload::GlobalSection(section) => {
+                    for global in section {
+                        if global?.ty.mutable {
+                            mutable_globals.insert(globals);
+                        }
+                        globals += 1;
+                    }
+                }
+                Payload::ExportSection(exports) => {
+                    for export in exports {
+                        let export = export?;
+                        match export.kind {
+                            ExternalKind::Memory => exported_memories.insert(export.index),
+                            ExternalKind::Global => exported_globals.insert(export.index),
+                            _ => false,
+                        };
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(index) = (0..memories).find(|index| !exported_memories.contains(index)) {
+            return Err(anyhow!(
+                "Can't fork processes of a module with a memory that is not exported (index {})",
+                index
+            ));
+        }
+        if let Some(index) = mutable_globals
+            .iter()
+            .find(|index| !exported_globals.contains(index))
+        {
+            return Err(anyhow!(
+                "Can't fork processes of a module with a mutable global that is not exported \
+                 (index {})",
+                index
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn matches_module(&self, data: &[u8]) -> bool {
+        let hash: [u8; 32] = Sha256::digest(data).into();
+        self.module_hash == hash
+    }
+
+    // Restores memories and globals into a freshly instantiated module.
+    pub(crate) fn apply(&self, store: &mut Store<ProcessState>, instance: &Instance) -> Result<()> {
+        for (name, data) in self.memories.iter() {
+            let memory = instance
+                .get_memory(&mut *store, name)
+                .ok_or_else(|| anyhow!("Memory `{}` not found", name))?;
+            let size = memory.data_size(&*store);
+            if data.len() < size {
+                return Err(anyhow!("Memory `{}` is bigger than the snapshot", name));
+            }
+            // Growing memories is subject to the environment's memory limits
+            let pages = (data.len() - size) / WASM_PAGE_SIZE;
+            memory.grow(&mut *store, pages as u64)?;
+            memory.write(&mut *store, 0, data)?;
+        }
+        for (name, value) in self.globals.iter() {
+            let global = instance
+                .get_global(&mut *store, name)
+                .ok_or_else(|| anyhow!("Global `{}` not found", name))?;
+            let value = match value {
+                GlobalValue::I32(value) => Val::I32(*value),
+                GlobalValue::I64(value) => Val::I64(*value),
+                GlobalValue::F32(value) => Val::F32(*value),
+                GlobalValue::F64(value) => Val::F64(*value),
+            };
+            global.set(&mut *store, value)?;
+        }
+        Ok(())
+    }
+
+    // Messages that were waiting in the mailbox of the suspended process.
+    pub(crate) fn messages(&self) -> Result<Vec<Message>> {
+        self.messages
+            .iter()
+            .map(|message| match message {
+                SnapshotMessage::Data(tag, buffer) => {
+                    let mut data = DataMessage::new(*tag, buffer.len());
+                    data.write_all(buffer)?;
+                    Ok(Message::Data(data))
+                }
+                SnapshotMessage::Signal(tag, reason) => Ok(Message::Signal(*tag, *reason)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::channel::{bounded, unbounded};
+    use uuid::Uuid;
+
+    use super::Snapshot;
+    use crate::{
+        message::{DataMessage, Message},
+        EnvConfig, Environment, Process, Signal, WasmProcess,
+    };
+
+    #[async_std::test]
+    async fn suspend_and_restore() {
+        let mut config = EnvConfig::new(0x200000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.allow_namespace("lunatic::process::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::process" "suspend" (func $suspend (result i32)))
+                (memory (export "memory") 1)
+                (global $counter (export "counter") (mut i32) (i32.const 0))
+                (func (export "run")
+                    (i32.store (i32.const 0x10000) (i32.const 1234))
+                    (global.set $counter (i32.const 2))
+                    (if (i32.ne (call $receive (i64.const 0) (i32.const 0)) (i32.const 3))
+                        (then unreachable))
+                    ;; Give the next message time to arrive
+                    (call $sleep_ms (i64.const 50))
+                    (drop (call $suspend))
+                    unreachable)
+                (func (export "lunatic_resume")
+                    (if (i32.ne (global.get $counter) (i32.const 2))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 0x10000)) (i32.const 1234))
+                        (then unreachable))
+                    ;; The message that was waiting in the mailbox is restored
+                    (if (i32.ne (call $receive (i64.const 7) (i32.const 100)) (i32.const 0))
+                        (then unreachable)))
+                (start $grow)
+                (func $grow (drop (memory.grow (i32.const 1)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, process) = module.spawn("run", Vec::new(), None).await.unwrap();
+        let (sender, receiver) = bounded(1);
+        process.send(Signal::Suspend(sender));
+        let message = Message::Data(DataMessage::new(Some(7), 0));
+        process.send(Signal::Message(message));
+        let snapshot = receiver.recv().await.unwrap();
+        task.await;
+
+        // Snapshots can be restored after being serialized
+        let snapshot = Snapshot::from_bytes(&snapshot.to_bytes().unwrap()).unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .restore(&snapshot, Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        // Finished without failing
+        assert!(receiver.try_recv().is_err());
+
+        // Snapshots can only be restored from the same module
+        let raw_module = wat::parse_str(r#"(module (func (export "lunatic_resume")))"#).unwrap();
+        let other_module = environment.create_module(raw_module).await.unwrap();
+        assert!(other_module.restore(&snapshot, None).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn fork() {
+        let mut config = EnvConfig::new(0x200000, None);
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.allow_namespace("lunatic::process::*").unwrap();
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (import "lunatic::process" "fork" (func $fork (param i64 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (global $counter (export "counter") (mut i32) (i32.const 0))
+                (data (i32.const 0) "check")
+                (func (export "run")
+                    (drop (memory.grow (i32.const 1)))
+                    (i32.store (i32.const 0x10000) (i32.const 42))
+                    (global.set $counter (i32.const 7))
+                    (if (i32.ne
+                            (call $fork (i64.const 1) (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 8))
+                            (i32.const 0))
+                        (then unreachable))
+                    ;; Give the child time to finish, it kills the parent if it fails
+                    (drop (call $receive (i64.const 0) (i32.const 200)))
+                    ;; Writes of the child are not visible to the parent
+                    (if (i32.ne (i32.load (i32.const 0x10000)) (i32.const 42))
+                        (then unreachable)))
+                (func (export "check")
+                    (if (i32.ne (global.get $counter) (i32.const 7))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 0x10000)) (i32.const 42))
+                        (then unreachable))
+                    (i32.store (i32.const 0x10000) (i32.const 0))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("run", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        // Finished without failing
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn forkable_modules() {
+        let forkable = |wat: &str| Snapshot::check_forkable(&wat::parse_str(wat).unwrap()).is_ok();
+        assert!(forkable(
+            r#"(module (memory (export "memory") 1) (global (export "g") (mut i32) (i32.const 0)))"#
+        ));
+        // Immutable globals never change
+        assert!(forkable(r#"(module (global i32 (i32.const 0)))"#));
+        // Hidden state would be reset in the child
+        assert!(!forkable(r#"(module (memory 1))"#));
+        assert!(!forkable(
+            r#"(module (memory (export "memory") 1) (global (mut i32) (i32.const 0)))"#
+        ));
+    }
+}
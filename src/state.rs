@@ -9,12 +9,19 @@ use anyhow::Result;
 use async_std::channel::Sender;
 use async_std::net::{TcpListener, TcpStream};
 use uuid::Uuid;
-use wasmtime::ResourceLimiter;
+use wasi_common::file::FdFlags;
+use wasmtime::{Instance, ResourceLimiter};
 use wasmtime_wasi::{WasiCtx, WasiCtxBuilder};
 
 use crate::mailbox::MessageMailbox;
-use crate::module::Module;
+use crate::module::{Module, SpawnOptions};
 use crate::plugin::ModuleContext;
+use crate::preopen::preopen_dirs;
+use crate::quota::ProcessQuota;
+use crate::snapshot::Snapshot;
+use crate::stdio::Stream;
+use crate::virtual_time::Clock;
+use crate::wasi_socket::{SocketKind, WasiSockets};
 use crate::{message::Message, EnvConfig, Environment};
 use crate::{Process, Signal};
 
@@ -46,6 +53,8 @@ pub(crate) struct ProcessState {
     pub(crate) id: Uuid,
     // The module that this process was spawned from
     pub(crate) module: Module,
+    // The instance running inside this process, used to access globals from host functions
+    pub(crate) instance: Option<Instance>,
     // A space that can be used to temporarily store messages when sending or receiving them.
     // Messages can contain resources that need to be added across multiple host. Likewise,
     // receiving messages is done in two steps, first the message size is returned to allow the
@@ -61,8 +70,30 @@ pub(crate) struct ProcessState {
     pub(crate) errors: HashMapId<anyhow::Error>,
     // Resources
     pub(crate) resources: Resources,
+    // Resources counted against the environment's quotas
+    pub(crate) quota: ProcessQuota,
     // WASI
     pub(crate) wasi: WasiCtx,
+    // Set by `lunatic::process::code_change` to continue running the process with a new module,
+    // after the current instance was stopped.
+    pub(crate) code_change: Option<CodeChange>,
+    // Set by `lunatic::process::suspend` to capture a snapshot of the process, after the current
+    // instance was stopped.
+    pub(crate) suspend: Option<Suspend>,
+}
+
+// The channel the snapshot is sent to and the messages that were taken out of the mailbox.
+pub(crate) struct Suspend {
+    pub(crate) sender: Sender<Snapshot>,
+    pub(crate) messages: Vec<Message>,
+}
+
+// A new version of the module, the state handed over from the old version and the version the
+// process is moved to in the registry.
+pub(crate) struct CodeChange {
+    pub(crate) module: Module,
+    pub(crate) state: Vec<u8>,
+    pub(crate) version: Option<String>,
 }
 
 impl ProcessState {
@@ -72,29 +103,93 @@ impl ProcessState {
         signal_mailbox: Sender<Signal>,
         message_mailbox: MessageMailbox,
         config: &EnvConfig,
+        quota: ProcessQuota,
+        options: &SpawnOptions,
     ) -> Result<Self> {
-        let wasi = WasiCtxBuilder::new();
-        let wasi = wasi.inherit_stdio();
-        let wasi = match config.wasi_envs() {
+        // Options passed in at spawn override the environment's configuration
+        let stdin = options
+            .wasi_stdin
+            .as_ref()
+            .unwrap_or_else(|| config.wasi_stdin());
+        let stdout = options
+            .wasi_stdout
+            .as_ref()
+            .unwrap_or_else(|| config.wasi_stdout());
+        let stderr = options
+            .wasi_stderr
+            .as_ref()
+            .unwrap_or_else(|| config.wasi_stderr());
+        let wasi = WasiCtxBuilder::new()
+            .stdin(stdin.wasi_file())
+            .stdout(stdout.wasi_file(Stream::Stdout, id, &module))
+            .stderr(stderr.wasi_file(Stream::Stderr, id, &module));
+        let wasi = match options
+            .wasi_envs
+            .as_ref()
+            .or_else(|| config.wasi_envs().as_ref())
+        {
             Some(envs) => wasi.envs(envs)?,
             None => wasi,
         };
-        let wasi = match config.wasi_args() {
+        let wasi = match options
+            .wasi_args
+            .as_ref()
+            .or_else(|| config.wasi_args().as_ref())
+        {
             Some(args) => wasi.args(args)?,
             None => wasi,
         };
+        let mut wasi = wasi.build();
+        if let Clock::Virtual(time) = module.environment().clock() {
+            wasi.clocks = time.wasi_clocks();
+            wasi.random = time.process_rng();
+        }
+        preopen_dirs(&mut wasi, config, quota.env())?;
         let state = Self {
             id,
             module,
+            instance: None,
             message: None,
             signal_mailbox,
             message_mailbox,
             errors: HashMapId::new(),
             resources: Resources::default(),
-            wasi: wasi.build(),
+            quota,
+            wasi,
+            code_change: None,
+            suspend: None,
         };
         Ok(state)
     }
+
+    // Tries to reserve space for a newly opened TCP stream. Streams moved into the WASI file
+    // descriptor table and closed by the guest are released first.
+    pub(crate) fn try_add_tcp_stream(&mut self) -> Result<()> {
+        self.prune_wasi_sockets();
+        self.quota.try_add_tcp_stream()
+    }
+
+    // Moves the socket into the WASI file descriptor table and returns its file descriptor.
+    pub(crate) fn insert_wasi_socket(&mut self, socket: SocketKind, flags: FdFlags) -> u32 {
+        self.prune_wasi_sockets();
+        self.resources
+            .wasi_sockets
+            .insert(&mut self.wasi, socket, flags)
+    }
+
+    // Moves the socket entry along with a file descriptor renumbered by the guest. A socket that
+    // was replaced at the new number is closed by WASI and released first.
+    pub(crate) fn renumber_wasi_socket(&mut self, from: u32, to: u32) {
+        self.prune_wasi_sockets();
+        self.resources.wasi_sockets.renumber(from, to);
+    }
+
+    // Streams closed by the guest stop counting against the quota.
+    fn prune_wasi_sockets(&mut self) {
+        for _ in 0..self.resources.wasi_sockets.prune() {
+            self.quota.remove_tcp_stream();
+        }
+    }
 }
 
 impl Debug for ProcessState {
@@ -105,30 +200,30 @@ impl Debug for ProcessState {
     }
 }
 
-// Limit the maximum memory of the process depending on the environment it was spawned in.
+// Limit the maximum memory of the process depending on the environment it was spawned in and the
+// memory quota shared with all other processes in the environment.
 impl ResourceLimiter for ProcessState {
-    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
-        desired <= self.module.environment().config().max_memory()
+    // The maximum memory limits all memories of the process together.
+    fn memory_growing(&mut self, current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        let growth = desired - current;
+        self.quota.memory() + growth <= self.module.environment().config().max_memory()
+            && self.quota.try_grow_memory(growth)
     }
 
-    // TODO: What would be a reasonable table limit be?
     fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
-        desired < 10_000
+        desired <= self.module.environment().config().max_table_elements()
     }
 
-    // Allow one instance per store
     fn instances(&self) -> usize {
-        1
+        self.module.environment().config().max_instances()
     }
 
-    // Allow one table per store
     fn tables(&self) -> usize {
-        1
+        self.module.environment().config().max_tables()
     }
 
-    // Allow one memory per store
     fn memories(&self) -> usize {
-        1
+        self.module.environment().config().max_memories()
     }
 }
 
@@ -137,10 +232,12 @@ pub(crate) struct Resources {
     pub(crate) configs: HashMapId<EnvConfig>,
     pub(crate) environments: HashMapId<Environment>,
     pub(crate) modules: HashMapId<Module>,
+    pub(crate) spawn_options: HashMapId<SpawnOptions>,
     pub(crate) processes: HashMapId<Arc<dyn Process>>,
     pub(crate) dns_iterators: HashMapId<DnsIterator>,
     pub(crate) tcp_listeners: HashMapId<TcpListener>,
     pub(crate) tcp_streams: HashMapId<TcpStream>,
+    pub(crate) wasi_sockets: WasiSockets,
 }
 
 /// HashMap wrapper with incremental ID (u64) assignment.
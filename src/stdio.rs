@@ -0,0 +1,434 @@
+/*!
+Standard streams of WASI processes.
+
+By default processes share the stdin, stdout and stderr of the host. Each stream can be configured
+per environment ([`EnvConfig`](crate::EnvConfig)) and overridden per process
+([`SpawnOptions`](crate::SpawnOptions)), so that many CLI-style guests can run side by side
+without interleaving their output.
+
+Output can also be redirected into a stream of data messages sent to another process. Every write
+of the guest results in one message carrying the written bytes.
+
+Output can also be captured line by line as structured records, either logged with the `log` crate
+or collected in an [`OutputBuffer`] that the embedder drains. Each record carries the ID of the
+process, the name of its module and the stream it was written to. Lines longer than 64 KiB are
+split into multiple records, so that a guest can't make the host buffer unlimited output.
+*/
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use log::{Level, Record};
+use uuid::Uuid;
+use wasi_common::pipe::{ReadPipe, WritePipe};
+use wasmtime_wasi::{stdio, WasiFile};
+
+use crate::{
+    message::{DataMessage, Message},
+    module::Module,
+    Process, Signal,
+};
+
+/// Where the standard input of a process reads from.
+#[derive(Clone)]
+pub enum WasiStdin {
+    /// The standard input of the host.
+    Inherit,
+    /// Always empty.
+    Null,
+    /// Reads the bytes and then reaches the end of the stream.
+    Bytes(Vec<u8>),
+}
+
+impl Default for WasiStdin {
+    fn default() -> Self {
+        Self::Inherit
+    }
+}
+
+impl WasiStdin {
+    pub(crate) fn wasi_file(&self) -> Box<dyn WasiFile> {
+        match self {
+            Self::Inherit => Box::new(stdio::stdin()),
+            Self::Null => Box::new(ReadPipe::new(io::empty())),
+            Self::Bytes(bytes) => Box::new(ReadPipe::from(bytes.clone())),
+        }
+    }
+}
+
+/// Where the standard output or error of a process writes to.
+#[derive(Clone)]
+pub enum WasiOutput {
+    /// The same stream of the host.
+    Inherit,
+    /// Discards everything.
+    Null,
+    /// Sends each write as a data message with the tag to the process.
+    Process(Arc<dyn Process>, Option<i64>),
+    /// Logs each line with the `log` crate, under the `lunatic::stdout` or `lunatic::stderr`
+    /// target. The process ID, module name and stream are attached as key-values.
+    Log,
+    /// Collects each line in the buffer.
+    Buffer(OutputBuffer),
+}
+
+impl Default for WasiOutput {
+    fn default() -> Self {
+        Self::Inherit
+    }
+}
+
+impl WasiOutput {
+    pub(crate) fn wasi_file(&self, stream: Stream, id: Uuid, module: &Module) -> Box<dyn WasiFile> {
+        let sink = match self {
+            Self::Inherit => match stream {
+                Stream::Stdout => return Box::new(stdio::stdout()),
+                Stream::Stderr => return Box::new(stdio::stderr()),
+            },
+            Self::Null => return Box::new(WritePipe::new(io::sink())),
+            Self::Process(process, tag) => {
+                return Box::new(WritePipe::new(MessageWriter {
+                    process: process.clone(),
+                    tag: *tag,
+                }))
+            }
+            Self::Log => None,
+            Self::Buffer(buffer) => Some(buffer.clone()),
+        };
+        Box::new(WritePipe::new(LineWriter {
+            sink,
+            stream,
+            process_id: id,
+            module: module.name().map(String::from),
+            partial: Vec::new(),
+        }))
+    }
+}
+
+/// A standard output stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// A line written by a process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputRecord {
+    pub process_id: Uuid,
+    /// Name of the module the process was spawned from (see [`Module::name`]).
+    pub module: Option<String>,
+    pub stream: Stream,
+    /// The line without the trailing newline. Invalid UTF-8 is replaced.
+    pub line: String,
+}
+
+/// Number of lines an [`OutputBuffer`] holds by default.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 10_000;
+
+// Longer lines are split into multiple records.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// A buffer that collects output lines of processes.
+///
+/// The buffer holds a limited number of lines until it's drained with
+/// [`take`](OutputBuffer::take). Once it's full, the oldest lines are dropped to make room for new
+/// ones.
+#[derive(Clone)]
+pub struct OutputBuffer {
+    records: Arc<Mutex<Records>>,
+}
+
+struct Records {
+    lines: VecDeque<OutputRecord>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl Default for OutputBuffer {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_BUFFER_CAPACITY)
+    }
+}
+
+impl OutputBuffer {
+    /// Create a buffer holding up to [`DEFAULT_BUFFER_CAPACITY`] lines.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a buffer holding up to `capacity` lines.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            records: Arc::new(Mutex::new(Records {
+                lines: VecDeque::new(),
+                capacity,
+                dropped: 0,
+            })),
+        }
+    }
+
+    /// Removes and returns all lines collected so far.
+    pub fn take(&self) -> Vec<OutputRecord> {
+        self.records.lock().unwrap().lines.drain(..).collect()
+    }
+
+    /// Returns the number of lines dropped because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.records.lock().unwrap().dropped
+    }
+
+    fn push(&self, record: OutputRecord) {
+        let mut records = self.records.lock().unwrap();
+        if records.capacity == 0 {
+            records.dropped += 1;
+            return;
+        }
+        if records.lines.len() == records.capacity {
+            records.lines.pop_front();
+            records.dropped += 1;
+        }
+        records.lines.push_back(record);
+    }
+}
+
+// Splits writes into lines of at most `MAX_LINE_LENGTH` bytes and logs them or adds them to a
+// buffer. An unfinished last line is emitted once the process finishes.
+struct LineWriter {
+    // Logs the lines if no buffer is set
+    sink: Option<OutputBuffer>,
+    stream: Stream,
+    process_id: Uuid,
+    module: Option<String>,
+    partial: Vec<u8>,
+}
+
+impl LineWriter {
+    fn emit(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        match &self.sink {
+            Some(buffer) => buffer.push(OutputRecord {
+                process_id: self.process_id,
+                module: self.module.clone(),
+                stream: self.stream,
+                line: line.into_owned(),
+            }),
+            None => {
+                let (level, target) = match self.stream {
+                    Stream::Stdout => (Level::Info, "lunatic::stdout"),
+                    Stream::Stderr => (Level::Warn, "lunatic::stderr"),
+                };
+                let process_id = self.process_id.to_string();
+                let module = self.module.as_deref().unwrap_or("<unnamed>");
+                let stream = self.stream.to_string();
+                let key_values: &[(&str, &str)] = &[
+                    ("process_id", &process_id),
+                    ("module", module),
+                    ("stream", &stream),
+                ];
+                log::logger().log(
+                    &Record::builder()
+                        .level(level)
+                        .target(target)
+                        .args(format_args!("{} {}: {}", module, process_id, line))
+                        .key_values(&key_values)
+                        .build(),
+                );
+            }
+        }
+    }
+}
+
+impl Write for LineWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.emit(&line[..end]);
+        }
+        while self.partial.len() >= MAX_LINE_LENGTH {
+            let line: Vec<u8> = self.partial.drain(..MAX_LINE_LENGTH).collect();
+            self.emit(&line);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LineWriter {
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            self.emit(&self.partial);
+        }
+    }
+}
+
+// Turns writes into data messages sent to a process.
+struct MessageWriter {
+    process: Arc<dyn Process>,
+    tag: Option<i64>,
+}
+
+impl Write for MessageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut message = DataMessage::new(self.tag, buf.len());
+        message.write_all(buf)?;
+        self.process.send(Signal::Message(Message::Data(message)));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use async_std::channel::unbounded;
+    use uuid::Uuid;
+
+    use super::{LineWriter, OutputBuffer, Stream, WasiOutput, WasiStdin, MAX_LINE_LENGTH};
+    use crate::{
+        message::Message, EnvConfig, Environment, Process, Signal, SpawnOptions, WasmProcess,
+    };
+
+    #[async_std::test]
+    async fn redirect_stdio() {
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.set_wasi_stdin(WasiStdin::Null);
+        config.set_wasi_stdout(WasiOutput::Null);
+        let environment = Environment::new(config).unwrap();
+        // Echoes stdin to stdout
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "echo")
+                    (i32.store (i32.const 0) (i32.const 16))
+                    (i32.store (i32.const 4) (i32.const 16))
+                    (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+                    (i32.store (i32.const 4) (i32.load (i32.const 8)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let output = WasmProcess::new(Uuid::new_v4(), sender);
+
+        // The environment's configuration is used by default
+        let (task, _) = module.spawn("echo", Vec::new(), None).await.unwrap();
+        task.await;
+
+        let options = SpawnOptions {
+            wasi_stdin: Some(WasiStdin::Bytes(b"hello".to_vec())),
+            wasi_stdout: Some(WasiOutput::Process(Arc::new(output), Some(3))),
+            ..SpawnOptions::default()
+        };
+        let (task, _) = module
+            .spawn_with("echo", Vec::new(), options)
+            .await
+            .unwrap();
+        task.await;
+        let message = match receiver.try_recv() {
+            Ok(Signal::Message(message)) => message,
+            _ => panic!("Expected the echoed data"),
+        };
+        assert_eq!(message.tag(), Some(3));
+        assert!(matches!(message, Message::Data(data) if data.buffer() == b"hello"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[async_std::test]
+    async fn capture_output() {
+        let buffer = OutputBuffer::new();
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.set_wasi_stdout(WasiOutput::Buffer(buffer.clone()));
+        config.set_wasi_stderr(WasiOutput::Buffer(buffer.clone()));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module $tool
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\10\00\00\00\06\00\00\00")
+                (data (i32.const 8) "\18\00\00\00\04\00\00\00")
+                (data (i32.const 16) "one\ntw")
+                (data (i32.const 24) "err\n")
+                (func (export "write")
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 32)))
+                    (drop (call $fd_write (i32.const 2) (i32.const 8) (i32.const 1) (i32.const 32)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, process) = module.spawn("write", Vec::new(), None).await.unwrap();
+        task.await;
+
+        let records = buffer.take();
+        let lines: Vec<_> = records
+            .iter()
+            .map(|record| (record.stream, record.line.as_str()))
+            .collect();
+        // The unfinished line is emitted once the process finishes
+        assert_eq!(
+            lines,
+            [
+                (Stream::Stdout, "one"),
+                (Stream::Stderr, "err"),
+                (Stream::Stdout, "tw")
+            ]
+        );
+        assert!(records
+            .iter()
+            .all(|record| record.process_id == process.id()
+                && record.module.as_deref() == Some("tool")));
+        assert!(buffer.take().is_empty());
+    }
+
+    #[test]
+    fn bounded_output() {
+        let buffer = OutputBuffer::with_capacity(2);
+        let mut writer = LineWriter {
+            sink: Some(buffer.clone()),
+            stream: Stream::Stdout,
+            process_id: Uuid::new_v4(),
+            module: None,
+            partial: Vec::new(),
+        };
+        // Long lines are split and the oldest lines are dropped once the buffer is full
+        writer.write_all(&vec![b'a'; MAX_LINE_LENGTH + 1]).unwrap();
+        assert_eq!(writer.partial.len(), 1);
+        writer.write_all(b"\nb\n").unwrap();
+        let lines: Vec<_> = buffer
+            .take()
+            .into_iter()
+            .map(|record| record.line)
+            .collect();
+        assert_eq!(lines, ["a", "b"]);
+        assert_eq!(buffer.dropped(), 1);
+        assert!(buffer.take().is_empty());
+    }
+}
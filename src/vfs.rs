@@ -0,0 +1,1225 @@
+/*!
+In-memory file systems for WASI processes.
+
+A [`VirtualFs`] can be mounted into processes with
+[`EnvConfig::mount_virtual_fs`](crate::EnvConfig::mount_virtual_fs) instead of a host directory,
+giving them a file system that never touches the host disk. It can be pre-populated from a map of
+files ([`VirtualFs::from_files`]) or a tar archive ([`VirtualFs::from_tar`]).
+
+A shared mount is visible to all processes in the environment, changes made by one process can be
+seen by all others and by the embedder holding the [`VirtualFs`]. Otherwise each process starts
+with its own copy of the file system's content at the time of spawning.
+
+File contents are kept in host memory. Each file system has a size limit
+([`VirtualFs::set_max_size`], 1 GiB by default) that writes fail with `ENOSPC` beyond. Writes are
+also counted against the environment's disk quota
+([`EnvConfig::set_max_disk_usage`](crate::EnvConfig::set_max_disk_usage)), which should be set
+when running untrusted guests. Only regular files and directories are supported, symbolic links
+are not.
+*/
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    io::{self, IoSlice, IoSliceMut, SeekFrom},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use wasi_common::{
+    dir::{ReaddirCursor, ReaddirEntity},
+    file::{Advice, FdFlags, FileType, Filestat, OFlags},
+    Error, ErrorExt, SystemTimeSpec, WasiDir, WasiFile,
+};
+
+const ROOT: u64 = 1;
+const DEFAULT_MAX_SIZE: u64 = 1 << 30;
+
+/// An in-memory file system.
+///
+/// Cloning a `VirtualFs` returns a handle to the same file system.
+#[derive(Clone)]
+pub struct VirtualFs {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl Default for VirtualFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualFs {
+    /// Creates an empty file system.
+    pub fn new() -> Self {
+        let mut dirs = HashMap::new();
+        dirs.insert(ROOT, DirNode::new(SystemTime::now()));
+        Self {
+            tree: Arc::new(Mutex::new(Tree {
+                next_inode: ROOT + 1,
+                dirs,
+                usage: Usage::new(DEFAULT_MAX_SIZE),
+            })),
+        }
+    }
+
+    /// Creates a file system containing `files`, indexed by their paths.
+    pub fn from_files<P, C>(files: impl IntoIterator<Item = (P, C)>) -> Result<Self>
+    where
+        P: AsRef<str>,
+        C: Into<Vec<u8>>,
+    {
+        let fs = Self::new();
+        for (path, contents) in files {
+            fs.write_file(path.as_ref(), contents)?;
+        }
+        Ok(fs)
+    }
+
+    /// Creates a file system containing the files and directories of a tar archive.
+    ///
+    /// Entries other than regular files and directories (e.g. links) are skipped.
+    pub fn from_tar(archive: &[u8]) -> Result<Self> {
+        let fs = Self::new();
+        for entry in tar_entries(archive)? {
+            match entry {
+                TarEntry::File(path, contents) => fs.write_file(&path, contents)?,
+                TarEntry::Dir(path) => fs.create_dir_all(&path)?,
+            }
+        }
+        Ok(fs)
+    }
+
+    /// Returns the maximum number of bytes all files of the file system can hold together.
+    pub fn max_size(&self) -> u64 {
+        self.tree
+            .lock()
+            .unwrap()
+            .usage
+            .limit
+            .load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum number of bytes all files of the file system can hold together.
+    ///
+    /// Lowering the limit below the current size doesn't remove content, but no file can grow
+    /// until enough is removed.
+    pub fn set_max_size(&self, max_size: u64) {
+        let tree = self.tree.lock().unwrap();
+        tree.usage.limit.store(max_size, Ordering::Relaxed);
+    }
+
+    /// Creates a directory and all missing parents.
+    pub fn create_dir_all(&self, path: &str) -> Result<()> {
+        let mut tree = self.tree.lock().unwrap();
+        tree.create_dir_all(&components(path)?)?;
+        Ok(())
+    }
+
+    /// Writes `contents` into the file at `path`, replacing existing content.
+    ///
+    /// Missing parent directories are created.
+    pub fn write_file(&self, path: &str, contents: impl Into<Vec<u8>>) -> Result<()> {
+        let components = components(path)?;
+        let (name, parents) = components
+            .split_last()
+            .ok_or_else(|| anyhow!("Path `{}` is not a file", path))?;
+        let mut tree = self.tree.lock().unwrap();
+        let parent = tree.create_dir_all(parents)?;
+        let now = SystemTime::now();
+        match tree.dir(parent)?.entries.get(*name).cloned() {
+            Some(Entry::File(file)) => {
+                let mut file = file.lock().unwrap();
+                file.replace(contents.into())?;
+                file.mtim = now;
+            }
+            Some(Entry::Dir(_)) => return Err(anyhow!("Path `{}` is a directory", path)),
+            None => {
+                let inode = tree.next_inode();
+                let file = FileNode::new(inode, &tree.usage, contents.into(), now)?;
+                tree.dir_mut(parent)?
+                    .insert(name.to_string(), Entry::File(file), now);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the content of the file at `path`, if it exists.
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        let tree = self.tree.lock().unwrap();
+        match tree.lookup(ROOT, &components(path).ok()?) {
+            Ok(Entry::File(file)) => Some(file.lock().unwrap().data.clone()),
+            _ => None,
+        }
+    }
+
+    // Returns an independent file system with the same content.
+    pub(crate) fn deep_copy(&self) -> Self {
+        let tree = self.tree.lock().unwrap();
+        let usage = Usage::new(tree.usage.limit.load(Ordering::Relaxed));
+        // Hard links need to keep pointing to the same file in the copy
+        let mut files: HashMap<u64, Arc<Mutex<FileNode>>> = HashMap::new();
+        let dirs = tree
+            .dirs
+            .iter()
+            .map(|(inode, dir)| {
+                let entries = dir
+                    .entries
+                    .iter()
+                    .map(|(name, entry)| {
+                        let entry = match entry {
+                            Entry::File(file) => {
+                                let file = file.lock().unwrap();
+                                let copy = files.entry(file.inode).or_insert_with(|| {
+                                    usage.force_reserve(file.data.len() as u64);
+                                    Arc::new(Mutex::new(FileNode {
+                                        inode: file.inode,
+                                        usage: usage.clone(),
+                                        data: file.data.clone(),
+                                        nlink: file.nlink,
+                                        atim: file.atim,
+                                        mtim: file.mtim,
+                                    }))
+                                });
+                                Entry::File(copy.clone())
+                            }
+                            Entry::Dir(inode) => Entry::Dir(*inode),
+                        };
+                        (name.clone(), entry)
+                    })
+                    .collect();
+                let copy = DirNode {
+                    entries,
+                    atim: dir.atim,
+                    mtim: dir.mtim,
+                };
+                (*inode, copy)
+            })
+            .collect();
+        Self {
+            tree: Arc::new(Mutex::new(Tree {
+                next_inode: tree.next_inode,
+                dirs,
+                usage,
+            })),
+        }
+    }
+
+    // Returns the root directory, ready to be preopened in a WASI context.
+    pub(crate) fn root(&self) -> Box<dyn WasiDir> {
+        Box::new(VirtualDir {
+            fs: self.clone(),
+            inode: ROOT,
+        })
+    }
+}
+
+/// A [`VirtualFs`] mounted into all processes of an environment.
+#[derive(Clone)]
+pub struct VirtualMount {
+    pub(crate) fs: VirtualFs,
+    pub(crate) guest_path: String,
+    pub(crate) shared: bool,
+}
+
+impl VirtualMount {
+    pub fn fs(&self) -> &VirtualFs {
+        &self.fs
+    }
+
+    pub fn guest_path(&self) -> &str {
+        &self.guest_path
+    }
+
+    pub fn shared(&self) -> bool {
+        self.shared
+    }
+
+    // Returns the root directory seen by a newly spawned process.
+    pub(crate) fn root(&self) -> Box<dyn WasiDir> {
+        if self.shared {
+            self.fs.root()
+        } else {
+            self.fs.deep_copy().root()
+        }
+    }
+}
+
+// Errors not covered by `ErrorExt`, translated into the matching `Errno` for the guest.
+fn os_error(code: i32) -> Error {
+    io::Error::from_raw_os_error(code).into()
+}
+
+// Splits a path relative to a directory into its components.
+//
+// Like with host directories, absolute paths and paths leading outside of the directory are not
+// allowed.
+fn components(path: &str) -> Result<Vec<&str>, Error> {
+    if path.starts_with('/') {
+        return Err(Error::not_capable().context("absolute path"));
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                if components.pop().is_none() {
+                    return Err(Error::not_capable().context("path outside of the directory"));
+                }
+            }
+            name => components.push(name),
+        }
+    }
+    Ok(components)
+}
+
+struct Tree {
+    next_inode: u64,
+    dirs: HashMap<u64, DirNode>,
+    usage: Arc<Usage>,
+}
+
+// Bytes held by the files of a file system, bounded by its size limit.
+struct Usage {
+    used: AtomicU64,
+    limit: AtomicU64,
+}
+
+impl Usage {
+    fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            used: AtomicU64::new(0),
+            limit: AtomicU64::new(limit),
+        })
+    }
+
+    fn try_reserve(&self, bytes: u64) -> Result<(), Error> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|used| *used <= limit)
+            })
+            .map(|_| ())
+            .map_err(|_| os_error(libc::ENOSPC).context("file system size limit reached"))
+    }
+
+    fn force_reserve(&self, bytes: u64) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Tree {
+    fn next_inode(&mut self) -> u64 {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        inode
+    }
+
+    fn dir(&self, inode: u64) -> Result<&DirNode, Error> {
+        self.dirs
+            .get(&inode)
+            .ok_or_else(|| Error::not_found().context("directory was removed"))
+    }
+
+    fn dir_mut(&mut self, inode: u64) -> Result<&mut DirNode, Error> {
+        self.dirs
+            .get_mut(&inode)
+            .ok_or_else(|| Error::not_found().context("directory was removed"))
+    }
+
+    // Finds the entry at `components`, relative to the directory `dir`.
+    fn lookup(&self, dir: u64, components: &[&str]) -> Result<Entry, Error> {
+        let mut entry = Entry::Dir(dir);
+        for component in components {
+            entry = match entry {
+                Entry::Dir(inode) => self
+                    .dir(inode)?
+                    .entries
+                    .get(*component)
+                    .cloned()
+                    .ok_or_else(Error::not_found)?,
+                Entry::File(_) => return Err(Error::not_dir()),
+            };
+        }
+        Ok(entry)
+    }
+
+    // Returns the directory containing `path` and the name of the entry inside of it.
+    fn parent<'a>(&self, dir: u64, path: &'a str) -> Result<(u64, &'a str), Error> {
+        let components = components(path)?;
+        let (name, parents) = match components.split_last() {
+            Some(split) => split,
+            // The directory itself
+            None => return Err(Error::exist()),
+        };
+        match self.lookup(dir, parents)? {
+            Entry::Dir(parent) => Ok((parent, name)),
+            Entry::File(_) => Err(Error::not_dir()),
+        }
+    }
+
+    fn create_dir_all(&mut self, components: &[&str]) -> Result<u64, Error> {
+        let mut dir = ROOT;
+        for component in components {
+            dir = match self.dir(dir)?.entries.get(*component) {
+                Some(Entry::Dir(inode)) => *inode,
+                Some(Entry::File(_)) => return Err(Error::not_dir()),
+                None => self.create_dir(dir, component)?,
+            };
+        }
+        Ok(dir)
+    }
+
+    fn create_dir(&mut self, parent: u64, name: &str) -> Result<u64, Error> {
+        let now = SystemTime::now();
+        let inode = self.next_inode();
+        self.dir_mut(parent)?
+            .insert(name.to_string(), Entry::Dir(inode), now);
+        self.dirs.insert(inode, DirNode::new(now));
+        Ok(inode)
+    }
+
+    // Removes a directory and everything inside of it.
+    fn remove_dir(&mut self, inode: u64) {
+        if let Some(dir) = self.dirs.remove(&inode) {
+            for entry in dir.entries.into_values() {
+                self.release(entry);
+            }
+        }
+    }
+
+    // Called when an entry is removed from its parent.
+    fn release(&mut self, entry: Entry) {
+        match entry {
+            Entry::File(file) => file.lock().unwrap().nlink -= 1,
+            Entry::Dir(inode) => self.remove_dir(inode),
+        }
+    }
+
+    // Returns true if `descendant` is `dir` or inside of it.
+    fn contains(&self, dir: u64, descendant: u64) -> bool {
+        dir == descendant
+            || self.dirs.get(&dir).map_or(false, |node| {
+                node.entries.values().any(|entry| match entry {
+                    Entry::Dir(inode) => self.contains(*inode, descendant),
+                    Entry::File(_) => false,
+                })
+            })
+    }
+
+    fn filestat(&self, entry: &Entry) -> Result<Filestat, Error> {
+        match entry {
+            Entry::File(file) => Ok(file.lock().unwrap().filestat()),
+            Entry::Dir(inode) => {
+                let dir = self.dir(*inode)?;
+                Ok(Filestat {
+                    device_id: 0,
+                    inode: *inode,
+                    filetype: FileType::Directory,
+                    nlink: 1,
+                    size: 0,
+                    atim: Some(dir.atim),
+                    mtim: Some(dir.mtim),
+                    ctim: Some(dir.mtim),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Entry {
+    File(Arc<Mutex<FileNode>>),
+    Dir(u64),
+}
+
+struct DirNode {
+    entries: BTreeMap<String, Entry>,
+    atim: SystemTime,
+    mtim: SystemTime,
+}
+
+impl DirNode {
+    fn new(now: SystemTime) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            atim: now,
+            mtim: now,
+        }
+    }
+
+    fn insert(&mut self, name: String, entry: Entry, now: SystemTime) {
+        self.entries.insert(name, entry);
+        self.mtim = now;
+    }
+}
+
+// Files are kept alive by open handles after they are removed from all directories.
+struct FileNode {
+    inode: u64,
+    usage: Arc<Usage>,
+    data: Vec<u8>,
+    nlink: u64,
+    atim: SystemTime,
+    mtim: SystemTime,
+}
+
+impl FileNode {
+    fn new(
+        inode: u64,
+        usage: &Arc<Usage>,
+        data: Vec<u8>,
+        now: SystemTime,
+    ) -> Result<Arc<Mutex<Self>>, Error> {
+        usage.try_reserve(data.len() as u64)?;
+        Ok(Arc::new(Mutex::new(Self {
+            inode,
+            usage: usage.clone(),
+            data,
+            nlink: 1,
+            atim: now,
+            mtim: now,
+        })))
+    }
+
+    // Grows or shrinks the file, new bytes are zeroed.
+    fn resize(&mut self, size: u64) -> Result<(), Error> {
+        let current = self.data.len() as u64;
+        if size > current {
+            self.usage.try_reserve(size - current)?;
+        } else {
+            self.usage.release(current - size);
+        }
+        // Sizes beyond the address space are rejected by the size limit already
+        self.data.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn replace(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        self.usage.try_reserve(data.len() as u64)?;
+        self.usage.release(self.data.len() as u64);
+        self.data = data;
+        Ok(())
+    }
+
+    fn filestat(&self) -> Filestat {
+        Filestat {
+            device_id: 0,
+            inode: self.inode,
+            filetype: FileType::RegularFile,
+            nlink: self.nlink,
+            size: self.data.len() as u64,
+            atim: Some(self.atim),
+            mtim: Some(self.mtim),
+            ctim: Some(self.mtim),
+        }
+    }
+
+    fn read_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> u64 {
+        let mut position = (offset as usize).min(self.data.len());
+        let start = position;
+        for buf in bufs {
+            let len = buf.len().min(self.data.len() - position);
+            buf[..len].copy_from_slice(&self.data[position..position + len]);
+            position += len;
+        }
+        (position - start) as u64
+    }
+
+    fn write_at(&mut self, bufs: &[IoSlice<'_>], offset: u64) -> Result<u64, Error> {
+        let len = bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| os_error(libc::EFBIG))?;
+        if end > self.data.len() as u64 {
+            self.resize(end)?;
+        }
+        let mut position = offset as usize;
+        for buf in bufs {
+            self.data[position..position + buf.len()].copy_from_slice(buf);
+            position += buf.len();
+        }
+        self.mtim = SystemTime::now();
+        Ok(len)
+    }
+}
+
+impl Drop for FileNode {
+    fn drop(&mut self) {
+        self.usage.release(self.data.len() as u64);
+    }
+}
+
+fn timespec(spec: Option<SystemTimeSpec>, current: SystemTime) -> SystemTime {
+    match spec {
+        Some(SystemTimeSpec::SymbolicNow) => SystemTime::now(),
+        Some(SystemTimeSpec::Absolute(time)) => time.into_std(),
+        None => current,
+    }
+}
+
+// A directory inside of a `VirtualFs`.
+struct VirtualDir {
+    fs: VirtualFs,
+    inode: u64,
+}
+
+#[async_trait]
+impl WasiDir for VirtualDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        _symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let mut tree = self.fs.tree.lock().unwrap();
+        let (parent, name) = tree.parent(self.inode, path)?;
+        let file = match tree.dir(parent)?.entries.get(name).cloned() {
+            Some(Entry::File(file)) => {
+                if oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE) {
+                    return Err(Error::exist());
+                }
+                file
+            }
+            Some(Entry::Dir(_)) => return Err(os_error(libc::EISDIR)),
+            None if oflags.contains(OFlags::CREATE) => {
+                let now = SystemTime::now();
+                let inode = tree.next_inode();
+                let file = FileNode::new(inode, &tree.usage, Vec::new(), now)?;
+                tree.dir_mut(parent)?
+                    .insert(name.to_string(), Entry::File(file.clone()), now);
+                file
+            }
+            None => return Err(Error::not_found()),
+        };
+        drop(tree);
+        if oflags.contains(OFlags::TRUNCATE) {
+            if !write {
+                return Err(Error::not_capable().context("truncating requires write access"));
+            }
+            let mut file = file.lock().unwrap();
+            file.resize(0)?;
+            file.mtim = SystemTime::now();
+        }
+        Ok(Box::new(VirtualFile {
+            node: file,
+            position: Mutex::new(0),
+            flags: fdflags,
+            read,
+            write,
+        }))
+    }
+
+    async fn open_dir(&self, _symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let tree = self.fs.tree.lock().unwrap();
+        match tree.lookup(self.inode, &components(path)?)? {
+            Entry::Dir(inode) => Ok(Box::new(VirtualDir {
+                fs: self.fs.clone(),
+                inode,
+            })),
+            Entry::File(_) => Err(Error::not_dir()),
+        }
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        let mut tree = self.fs.tree.lock().unwrap();
+        let (parent, name) = tree.parent(self.inode, path)?;
+        if tree.dir(parent)?.entries.contains_key(name) {
+            return Err(Error::exist());
+        }
+        tree.create_dir(parent, name)?;
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let tree = self.fs.tree.lock().unwrap();
+        let dir = tree.dir(self.inode)?;
+        let mut entries = vec![
+            (".".to_string(), self.inode, FileType::Directory),
+            ("..".to_string(), self.inode, FileType::Directory),
+        ];
+        for (name, entry) in dir.entries.iter() {
+            let (inode, filetype) = match entry {
+                Entry::File(file) => (file.lock().unwrap().inode, FileType::RegularFile),
+                Entry::Dir(inode) => (*inode, FileType::Directory),
+            };
+            entries.push((name.clone(), inode, filetype));
+        }
+        let entries = entries
+            .into_iter()
+            .enumerate()
+            .skip(u64::from(cursor) as usize)
+            .map(|(index, (name, inode, filetype))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(index as u64 + 1),
+                    inode,
+                    name,
+                    filetype,
+                })
+            });
+        Ok(Box::new(entries))
+    }
+
+    async fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::not_supported().context("symbolic links"))
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        let mut tree = self.fs.tree.lock().unwrap();
+        let (parent, name) = tree.parent(self.inode, path)?;
+        match tree.dir(parent)?.entries.get(name) {
+            Some(Entry::Dir(inode)) => {
+                if !tree.dir(*inode)?.entries.is_empty() {
+                    return Err(os_error(libc::ENOTEMPTY));
+                }
+            }
+            Some(Entry::File(_)) => return Err(Error::not_dir()),
+            None => return Err(Error::not_found()),
+        }
+        let entry = tree.dir_mut(parent)?.entries.remove(name).unwrap();
+        tree.dir_mut(parent)?.mtim = SystemTime::now();
+        tree.release(entry);
+        Ok(())
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let mut tree = self.fs.tree.lock().unwrap();
+        let (parent, name) = tree.parent(self.inode, path)?;
+        match tree.dir(parent)?.entries.get(name) {
+            Some(Entry::File(_)) => {}
+            Some(Entry::Dir(_)) => return Err(os_error(libc::EISDIR)),
+            None => return Err(Error::not_found()),
+        }
+        let entry = tree.dir_mut(parent)?.entries.remove(name).unwrap();
+        tree.dir_mut(parent)?.mtim = SystemTime::now();
+        tree.release(entry);
+        Ok(())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        let tree = self.fs.tree.lock().unwrap();
+        // Everything exists as a regular file or directory
+        tree.lookup(self.inode, &components(path)?)?;
+        Err(Error::invalid_argument().context("not a symbolic link"))
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        let tree = self.fs.tree.lock().unwrap();
+        tree.filestat(&Entry::Dir(self.inode))
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        _follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        let tree = self.fs.tree.lock().unwrap();
+        let entry = tree.lookup(self.inode, &components(path)?)?;
+        tree.filestat(&entry)
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        let dest_dir = dest_dir
+            .as_any()
+            .downcast_ref::<VirtualDir>()
+            .filter(|dest_dir| Arc::ptr_eq(&dest_dir.fs.tree, &self.fs.tree))
+            .ok_or_else(|| Error::badf().context("not a directory of the same file system"))?;
+        let mut tree = self.fs.tree.lock().unwrap();
+        let (parent, name) = tree.parent(self.inode, path)?;
+        let (dest_parent, dest_name) = tree.parent(dest_dir.inode, dest_path)?;
+        let entry = tree
+            .dir(parent)?
+            .entries
+            .get(name)
+            .cloned()
+            .ok_or_else(Error::not_found)?;
+        if parent == dest_parent && name == dest_name {
+            return Ok(());
+        }
+        match (&entry, tree.dir(dest_parent)?.entries.get(dest_name)) {
+            (Entry::Dir(inode), _) if tree.contains(*inode, dest_parent) => {
+                return Err(Error::invalid_argument().context("directory moved into itself"))
+            }
+            (Entry::File(_), Some(Entry::Dir(_))) => return Err(os_error(libc::EISDIR)),
+            (Entry::Dir(_), Some(Entry::File(_))) => return Err(Error::not_dir()),
+            (Entry::Dir(_), Some(Entry::Dir(inode))) if !tree.dir(*inode)?.entries.is_empty() => {
+                return Err(os_error(libc::ENOTEMPTY))
+            }
+            _ => {}
+        }
+        let now = SystemTime::now();
+        tree.dir_mut(parent)?.entries.remove(name);
+        tree.dir_mut(parent)?.mtim = now;
+        let replaced = tree
+            .dir_mut(dest_parent)?
+            .entries
+            .insert(dest_name.to_string(), entry);
+        tree.dir_mut(dest_parent)?.mtim = now;
+        if let Some(replaced) = replaced {
+            tree.release(replaced);
+        }
+        Ok(())
+    }
+
+    async fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        let target_dir = target_dir
+            .as_any()
+            .downcast_ref::<VirtualDir>()
+            .filter(|target_dir| Arc::ptr_eq(&target_dir.fs.tree, &self.fs.tree))
+            .ok_or_else(|| Error::badf().context("not a directory of the same file system"))?;
+        let mut tree = self.fs.tree.lock().unwrap();
+        let file = match tree.lookup(self.inode, &components(path)?)? {
+            Entry::File(file) => file,
+            Entry::Dir(_) => return Err(os_error(libc::EPERM)),
+        };
+        let (target_parent, target_name) = tree.parent(target_dir.inode, target_path)?;
+        if tree.dir(target_parent)?.entries.contains_key(target_name) {
+            return Err(Error::exist());
+        }
+        file.lock().unwrap().nlink += 1;
+        tree.dir_mut(target_parent)?.insert(
+            target_name.to_string(),
+            Entry::File(file),
+            SystemTime::now(),
+        );
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        let mut tree = self.fs.tree.lock().unwrap();
+        match tree.lookup(self.inode, &components(path)?)? {
+            Entry::File(file) => {
+                let mut file = file.lock().unwrap();
+                file.atim = timespec(atime, file.atim);
+                file.mtim = timespec(mtime, file.mtim);
+            }
+            Entry::Dir(inode) => {
+                let dir = tree.dir_mut(inode)?;
+                dir.atim = timespec(atime, dir.atim);
+                dir.mtim = timespec(mtime, dir.mtim);
+            }
+        }
+        Ok(())
+    }
+}
+
+// An open file inside of a `VirtualFs`.
+struct VirtualFile {
+    node: Arc<Mutex<FileNode>>,
+    position: Mutex<u64>,
+    flags: FdFlags,
+    read: bool,
+    write: bool,
+}
+
+impl VirtualFile {
+    fn check_read(&self) -> Result<(), Error> {
+        if self.read {
+            Ok(())
+        } else {
+            Err(Error::badf().context("file not opened for reading"))
+        }
+    }
+
+    fn check_write(&self) -> Result<(), Error> {
+        if self.write {
+            Ok(())
+        } else {
+            Err(Error::badf().context("file not opened for writing"))
+        }
+    }
+}
+
+#[async_trait]
+impl WasiFile for VirtualFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn datasync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        Ok(self.flags)
+    }
+
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        if !(FdFlags::APPEND | FdFlags::NONBLOCK).contains(flags) {
+            return Err(Error::invalid_argument().context("cannot set sync flags"));
+        }
+        self.flags = flags;
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(self.node.lock().unwrap().filestat())
+    }
+
+    async fn set_filestat_size(&self, size: u64) -> Result<(), Error> {
+        self.check_write()?;
+        let mut file = self.node.lock().unwrap();
+        file.resize(size)?;
+        file.mtim = SystemTime::now();
+        Ok(())
+    }
+
+    async fn advise(&self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn allocate(&self, offset: u64, len: u64) -> Result<(), Error> {
+        self.check_write()?;
+        let mut file = self.node.lock().unwrap();
+        let size = offset
+            .checked_add(len)
+            .ok_or_else(|| os_error(libc::EFBIG))?;
+        if size > file.data.len() as u64 {
+            file.resize(size)?;
+        }
+        Ok(())
+    }
+
+    async fn set_times(
+        &self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        let mut file = self.node.lock().unwrap();
+        file.atim = timespec(atime, file.atim);
+        file.mtim = timespec(mtime, file.mtim);
+        Ok(())
+    }
+
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        self.check_read()?;
+        let mut position = self.position.lock().unwrap();
+        let read = self.node.lock().unwrap().read_at(bufs, *position);
+        *position += read;
+        Ok(read)
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        self.check_read()?;
+        Ok(self.node.lock().unwrap().read_at(bufs, offset))
+    }
+
+    async fn write_vectored<'a>(&self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        self.check_write()?;
+        let mut position = self.position.lock().unwrap();
+        let mut file = self.node.lock().unwrap();
+        if self.flags.contains(FdFlags::APPEND) {
+            *position = file.data.len() as u64;
+        }
+        let written = file.write_at(bufs, *position)?;
+        *position += written;
+        Ok(written)
+    }
+
+    async fn write_vectored_at<'a>(&self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        self.check_write()?;
+        self.node.lock().unwrap().write_at(bufs, offset)
+    }
+
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => add_offset(*position, offset),
+            SeekFrom::End(offset) => {
+                add_offset(self.node.lock().unwrap().data.len() as u64, offset)
+            }
+        };
+        *position = new_position.ok_or_else(Error::invalid_argument)?;
+        Ok(*position)
+    }
+
+    async fn peek(&self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.check_read()?;
+        let position = self.position.lock().unwrap();
+        let read = self
+            .node
+            .lock()
+            .unwrap()
+            .read_at(&mut [IoSliceMut::new(buf)], *position);
+        Ok(read)
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        let position = *self.position.lock().unwrap();
+        let size = self.node.lock().unwrap().data.len() as u64;
+        Ok(size.saturating_sub(position))
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn add_offset(position: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        position.checked_sub(offset.unsigned_abs())
+    } else {
+        position.checked_add(offset as u64)
+    }
+}
+
+enum TarEntry {
+    File(String, Vec<u8>),
+    Dir(String),
+}
+
+// Parses the regular files and directories of a ustar archive, including GNU long names and pax
+// path overrides.
+fn tar_entries(archive: &[u8]) -> Result<Vec<TarEntry>> {
+    let mut entries = Vec::new();
+    let mut long_name = None;
+    let mut offset = 0;
+    while offset < archive.len() {
+        let header = archive
+            .get(offset..offset + 512)
+            .ok_or_else(|| anyhow!("Tar archive is truncated"))?;
+        // The archive ends with zero blocks
+        if header.iter().all(|byte| *byte == 0) {
+            break;
+        }
+        let size = tar_number(&header[124..136])?;
+        let data_start = offset + 512;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or_else(|| anyhow!("Tar archive is truncated"))?;
+        offset = data_start + (size + 511) / 512 * 512;
+
+        let typeflag = header[156];
+        match typeflag {
+            // GNU long name of the next entry
+            b'L' => {
+                long_name = Some(tar_string(data));
+                continue;
+            }
+            // Pax extended header of the next entry
+            b'x' => {
+                long_name = pax_path(data).or(long_name);
+                continue;
+            }
+            _ => {}
+        }
+        let path = match long_name.take() {
+            Some(path) => path,
+            None => {
+                let name = tar_string(&header[0..100]);
+                let prefix = tar_string(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    format!("{}/{}", prefix, name)
+                } else {
+                    name
+                }
+            }
+        };
+        let path = path.trim_start_matches("./").trim_start_matches('/');
+        match typeflag {
+            b'0' | 0 | b'7' => entries.push(TarEntry::File(path.to_string(), data.to_vec())),
+            b'5' => entries.push(TarEntry::Dir(path.to_string())),
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+fn tar_string(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// Numeric header fields are stored as octal strings.
+fn tar_number(bytes: &[u8]) -> Result<usize> {
+    let number = tar_string(bytes);
+    let number = number.trim_matches(|c: char| c == ' ' || c == '\0');
+    if number.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(number, 8).map_err(|_| anyhow!("Invalid number in tar header"))
+}
+
+// Pax records have the format "<length> <key>=<value>\n".
+fn pax_path(data: &[u8]) -> Option<String> {
+    let records = String::from_utf8_lossy(data);
+    records.lines().find_map(|record| {
+        let (_, record) = record.split_once(' ')?;
+        let (key, value) = record.split_once('=')?;
+        if key == "path" {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSlice;
+
+    use wasi_common::file::{FdFlags, OFlags};
+
+    use super::VirtualFs;
+    use crate::{EnvConfig, Environment};
+
+    fn tar_header(path: &str, typeflag: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0; 512];
+        header[..path.len()].copy_from_slice(path.as_bytes());
+        header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header
+    }
+
+    #[test]
+    fn from_tar() {
+        let long_path = format!("{}/file.txt", "a".repeat(120));
+        let mut archive = tar_header("./etc/", b'5', 0);
+        archive.extend(tar_header("./etc/app.toml", b'0', 5));
+        archive.extend(b"debug");
+        archive.resize(3 * 512, 0);
+        archive.extend(tar_header("././@LongLink", b'L', long_path.len()));
+        archive.extend(long_path.as_bytes());
+        archive.resize(5 * 512, 0);
+        archive.extend(tar_header("truncated", b'0', 4));
+        archive.extend(b"long");
+        archive.resize(8 * 512, 0);
+
+        let fs = VirtualFs::from_tar(&archive).unwrap();
+        assert_eq!(fs.read_file("etc/app.toml").unwrap(), b"debug");
+        assert_eq!(fs.read_file(&long_path).unwrap(), b"long");
+        assert!(fs.read_file("etc").is_none());
+        assert!(VirtualFs::from_tar(&archive[..3 * 512 + 100]).is_err());
+    }
+
+    #[async_std::test]
+    async fn mount_virtual_fs() {
+        let shared = VirtualFs::new();
+        let private = VirtualFs::from_files([("data/config.txt", "hello")]).unwrap();
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.mount_virtual_fs(shared.clone(), "/shared", true);
+        config.mount_virtual_fs(private.clone(), "/private", false);
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\64\00\00\00\05\00\00\00")
+                (data (i32.const 32) "data/config.txt")
+                (data (i32.const 48) "out.txt")
+                (func (export "run")
+                    ;; Read the private config.txt, then truncate it
+                    (drop (call $path_open (i32.const 4) (i32.const 0) (i32.const 32) (i32.const 15)
+                        (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    (drop (call $fd_read (i32.load (i32.const 20)) (i32.const 0) (i32.const 1) (i32.const 16)))
+                    (drop (call $path_open (i32.const 4) (i32.const 0) (i32.const 32) (i32.const 15)
+                        (i32.const 8) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    ;; Write it into the shared file system
+                    (drop (call $path_open (i32.const 3) (i32.const 0) (i32.const 48) (i32.const 7)
+                        (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 0) (i32.const 20)))
+                    (drop (call $fd_write (i32.load (i32.const 20)) (i32.const 0) (i32.const 1) (i32.const 16)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, _) = module.spawn("run", Vec::new(), None).await.unwrap();
+        task.await;
+
+        // Changes to the shared file system are visible, the private one was copied
+        assert_eq!(shared.read_file("out.txt").unwrap(), b"hello");
+        assert_eq!(private.read_file("data/config.txt").unwrap(), b"hello");
+    }
+
+    #[async_std::test]
+    async fn size_limit() {
+        let fs = VirtualFs::from_files([("a.txt", "1234")]).unwrap();
+        fs.set_max_size(8);
+        assert!(fs.write_file("b.txt", "123456789").is_err());
+        fs.write_file("b.txt", "1234").unwrap();
+
+        let file = fs
+            .root()
+            .open_file(
+                false,
+                "a.txt",
+                OFlags::empty(),
+                true,
+                true,
+                FdFlags::empty(),
+            )
+            .await
+            .unwrap();
+        // Sizes and offsets chosen by the guest fail instead of allocating host memory
+        assert!(file.set_filestat_size(1 << 46).await.is_err());
+        assert!(file.allocate(u64::MAX, 2).await.is_err());
+        let buf = [IoSlice::new(b"xy")];
+        assert!(file.write_vectored_at(&buf, u64::MAX - 1).await.is_err());
+        assert!(file.write_vectored_at(&buf, 3).await.is_err());
+
+        // Removed content frees space
+        file.set_filestat_size(0).await.unwrap();
+        assert_eq!(file.write_vectored_at(&buf, 2).await.unwrap(), 2);
+        assert_eq!(fs.read_file("a.txt").unwrap(), b"\0\0xy");
+    }
+}
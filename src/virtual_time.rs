@@ -0,0 +1,249 @@
+/*!
+Deterministic time and randomness.
+
+If an environment is configured with a seed
+([`EnvConfig::set_deterministic_seed`](crate::EnvConfig::set_deterministic_seed)), processes inside
+of it don't observe the host's clocks or entropy:
+
+* WASI clocks (`clock_time_get`) return a virtual time that starts at a fixed point and is shared
+  by all processes of the environment. It only moves forward when processes sleep, time out or read
+  it. Each read advances it by a microsecond, so that guests busy-waiting on the clock still make
+  progress.
+* WASI `random_get` is backed by a random number generator seeded from the environment's seed, in
+  the order in which processes are spawned.
+* `lunatic::process::sleep_ms` advances the virtual time without waiting.
+* Receive timeouts fire once the process yielded a number of times, chosen by the environment's
+  random number generator, without getting a matching message. The virtual time then advances to
+  the deadline.
+
+Clock subscriptions of WASI `poll_oneoff` and networking timeouts still use the host's time.
+
+Together with a deterministic schedule, runs can be reproduced from the seed. Different seeds
+result in different orders of timeouts and incoming messages.
+*/
+
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use cap_rand::{rngs::StdRng, Rng, RngCore, SeedableRng};
+use wasi_common::{WasiClocks, WasiMonotonicClock, WasiSystemClock};
+
+// The virtual system time starts at 2021-01-01 00:00:00 UTC.
+const EPOCH_OFFSET: Duration = Duration::from_secs(1_609_459_200);
+// Advanced on each read of the clock.
+const READ_STEP: Duration = Duration::from_micros(1);
+// Upper bound of yields before a receive times out.
+const MAX_TIMEOUT_YIELDS: u32 = 64;
+
+// The clock used by all processes of an environment.
+#[derive(Clone)]
+pub(crate) enum Clock {
+    Real,
+    Virtual(Arc<VirtualTime>),
+}
+
+impl Clock {
+    pub(crate) fn new(seed: Option<u64>) -> Self {
+        match seed {
+            Some(seed) => Clock::Virtual(Arc::new(VirtualTime::new(seed))),
+            None => Clock::Real,
+        }
+    }
+
+    pub(crate) async fn sleep(&self, duration: Duration) {
+        match self {
+            Clock::Real => async_std::task::sleep(duration).await,
+            Clock::Virtual(time) => {
+                time.advance_to(time.elapsed() + duration);
+                async_std::task::yield_now().await;
+            }
+        }
+    }
+
+    // Waits for the future to finish, returns `None` if the timeout expires first. A `duration`
+    // of `None` never times out.
+    pub(crate) async fn timeout<F: Future>(
+        &self,
+        duration: Option<Duration>,
+        future: F,
+    ) -> Option<F::Output> {
+        let duration = match duration {
+            Some(duration) => duration,
+            None => return Some(future.await),
+        };
+        match self {
+            Clock::Real => async_std::future::timeout(duration, future).await.ok(),
+            Clock::Virtual(time) => {
+                let deadline = time.elapsed() + duration;
+                let yields = time.rng.lock().unwrap().gen_range(1..=MAX_TIMEOUT_YIELDS);
+                let mut future = Box::pin(future);
+                for _ in 0..yields {
+                    let poll = std::future::poll_fn(|cx| Poll::Ready(future.as_mut().poll(cx)));
+                    if let Poll::Ready(output) = poll.await {
+                        return Some(output);
+                    }
+                    async_std::task::yield_now().await;
+                }
+                time.advance_to(deadline);
+                None
+            }
+        }
+    }
+}
+
+pub(crate) struct VirtualTime {
+    // Real instant the virtual monotonic clock starts from, only used as a base.
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    rng: Mutex<StdRng>,
+}
+
+impl VirtualTime {
+    fn new(seed: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    fn advance_to(&self, time: Duration) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed = (*elapsed).max(time);
+    }
+
+    // Returns the current time and advances it by one step.
+    fn read(&self) -> Duration {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed += READ_STEP;
+        *elapsed
+    }
+
+    // Random number generator for a newly spawned process.
+    pub(crate) fn process_rng(&self) -> Box<dyn RngCore + Send + Sync> {
+        let seed = self.rng.lock().unwrap().next_u64();
+        Box::new(StdRng::seed_from_u64(seed))
+    }
+
+    pub(crate) fn wasi_clocks(self: &Arc<Self>) -> WasiClocks {
+        WasiClocks {
+            system: Box::new(VirtualSystemClock(self.clone())),
+            monotonic: Box::new(VirtualMonotonicClock(self.clone())),
+            creation_time: cap_std::time::Instant::from_std(self.start),
+        }
+    }
+}
+
+struct VirtualSystemClock(Arc<VirtualTime>);
+
+impl WasiSystemClock for VirtualSystemClock {
+    fn resolution(&self) -> cap_std::time::Duration {
+        READ_STEP
+    }
+
+    fn now(&self, _precision: cap_std::time::Duration) -> cap_std::time::SystemTime {
+        cap_std::time::SystemTime::from_std(UNIX_EPOCH + EPOCH_OFFSET + self.0.read())
+    }
+}
+
+struct VirtualMonotonicClock(Arc<VirtualTime>);
+
+impl WasiMonotonicClock for VirtualMonotonicClock {
+    fn resolution(&self) -> cap_std::time::Duration {
+        READ_STEP
+    }
+
+    fn now(&self, _precision: cap_std::time::Duration) -> cap_std::time::Instant {
+        cap_std::time::Instant::from_std(self.0.start + self.0.read())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryInto, sync::Arc, time::Duration};
+
+    use async_std::channel::unbounded;
+    use uuid::Uuid;
+
+    use super::EPOCH_OFFSET;
+    use crate::{message::Message, stdio::WasiOutput, EnvConfig, Environment, Signal, WasmProcess};
+
+    // Returns the wall clock times, the receive result and the random bytes observed by a
+    // process.
+    async fn run(seed: u64) -> (u64, u64, u64, u32, Vec<u8>) {
+        let (sender, receiver) = unbounded();
+        let output = WasmProcess::new(Uuid::new_v4(), sender);
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.allow_namespace("lunatic::message::*").unwrap();
+        config.allow_namespace("lunatic::process::*").unwrap();
+        config.set_wasi_stdout(WasiOutput::Process(Arc::new(output), None));
+        config.set_deterministic_seed(Some(seed));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "clock_time_get" (func $clock_time_get (param i32 i64 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "random_get" (func $random_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::message" "receive" (func $receive (param i64 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\10\00\00\00\28\00\00\00")
+                (func (export "run")
+                    (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 16)))
+                    (call $sleep_ms (i64.const 1000))
+                    (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 24)))
+                    (i32.store (i32.const 32) (call $receive (i64.const 0) (i32.const 5000)))
+                    (drop (call $clock_time_get (i32.const 0) (i64.const 1) (i32.const 40)))
+                    (drop (call $random_get (i32.const 48) (i32.const 8)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8)))))
+            "#,
+        )
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, _) = module.spawn("run", Vec::new(), None).await.unwrap();
+        task.await;
+        let buffer = match receiver.try_recv() {
+            Ok(Signal::Message(Message::Data(data))) => data.buffer().to_vec(),
+            _ => panic!("Expected the observed values"),
+        };
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(buffer[offset..offset + 8].try_into().unwrap());
+        let result = u32::from_le_bytes(buffer[16..20].try_into().unwrap());
+        (
+            u64_at(0),
+            u64_at(8),
+            u64_at(24),
+            result,
+            buffer[32..40].to_vec(),
+        )
+    }
+
+    #[async_std::test]
+    async fn deterministic_run() {
+        let (start, after_sleep, after_timeout, result, random) = run(7).await;
+        assert_eq!(
+            start,
+            (EPOCH_OFFSET + Duration::from_micros(1)).as_nanos() as u64
+        );
+        assert!(after_sleep - start >= 1_000_000_000);
+        assert_eq!(result, 9027);
+        assert!(after_timeout - after_sleep >= 5_000_000_000);
+
+        // The same seed reproduces the run, randomness depends on the seed
+        assert_eq!(
+            run(7).await,
+            (start, after_sleep, after_timeout, result, random.clone())
+        );
+        assert_ne!(run(8).await.4, random);
+    }
+}
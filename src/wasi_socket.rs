@@ -0,0 +1,448 @@
+/*!
+TCP sockets as WASI file descriptors.
+
+Streams and listeners created with the `lunatic::networking` API (or received in a message) can be
+moved into the WASI file descriptor table of a process. Guests compiled against WASI then use them
+as regular sockets:
+
+* `fd_read`, `fd_write` and `fd_close` work on streams, like on any other file.
+* `sock_recv`, `sock_send`, `sock_shutdown` and `sock_accept` work on streams and listeners.
+* `fd_renumber` moves streams and listeners to another file descriptor.
+
+Reads, writes and accepts are implemented by the runtime and only suspend the process while
+waiting, so that it can still be killed or time out. If the `NONBLOCK` flag is set
+(`fd_fdstat_set_flags`), they return `EAGAIN` instead of waiting. At most 64 KiB are transferred
+per call.
+
+The runtime keeps its own index of socket file descriptors next to the WASI table, `fd_renumber`
+updates both. Reading or writing at an offset (`fd_pread`, `fd_pwrite`) fails with `ESPIPE`.
+Sockets are not supported as `poll_oneoff` subscriptions, polling them returns `ENOTSUP`.
+*/
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    convert::TryFrom,
+    future::Future,
+    io::{self, IoSlice, IoSliceMut},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    task::Poll,
+};
+
+use async_std::net::{TcpListener, TcpStream};
+use async_trait::async_trait;
+use wasi_common::{
+    file::{Advice, FdFlags, FileCaps, FileType, Filestat},
+    snapshots::preview_1::types::Errno,
+    Error, ErrorExt, SystemTimeSpec, WasiCtx, WasiFile,
+};
+
+// The first file descriptor after the standard streams.
+const FIRST_FD: u32 = 3;
+
+// A socket shared between the WASI file descriptor table and the index of the process.
+#[derive(Debug)]
+pub(crate) struct WasiSocket {
+    kind: SocketKind,
+    nonblocking: AtomicBool,
+}
+
+#[derive(Debug)]
+pub(crate) enum SocketKind {
+    Stream(TcpStream),
+    Listener(TcpListener),
+}
+
+impl WasiSocket {
+    pub(crate) fn kind(&self) -> &SocketKind {
+        &self.kind
+    }
+
+    // Waits for the operation to finish, or fails with `EAGAIN` if it would block and the socket
+    // is in non-blocking mode.
+    pub(crate) async fn io<T, F>(&self, operation: F) -> io::Result<T>
+    where
+        F: Future<Output = io::Result<T>>,
+    {
+        if !self.nonblocking.load(Ordering::Relaxed) {
+            return operation.await;
+        }
+        let mut operation = Box::pin(operation);
+        std::future::poll_fn(|cx| match operation.as_mut().poll(cx) {
+            Poll::Ready(result) => Poll::Ready(result),
+            Poll::Pending => Poll::Ready(Err(io::ErrorKind::WouldBlock.into())),
+        })
+        .await
+    }
+}
+
+// Socket file descriptors of a process.
+//
+// Entries don't keep the sockets alive. Once the guest closes the file descriptor, the entry can't
+// be upgraded anymore and is removed on the next prune.
+#[derive(Debug, Default)]
+pub(crate) struct WasiSockets {
+    sockets: HashMap<u32, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    socket: Weak<WasiSocket>,
+    // Streams count against the environment's quota, listeners don't.
+    stream: bool,
+}
+
+impl WasiSockets {
+    // Adds the socket to the lowest free file descriptor of the WASI table and returns it. Closed
+    // sockets need to be pruned before, so that their entries are not overwritten.
+    pub(crate) fn insert(&mut self, wasi: &mut WasiCtx, kind: SocketKind, flags: FdFlags) -> u32 {
+        let fd = (FIRST_FD..)
+            .find(|fd| !wasi.table().contains_key(*fd))
+            .expect("file descriptors are not exhausted");
+        let stream = matches!(kind, SocketKind::Stream(_));
+        let caps = match kind {
+            SocketKind::Stream(_) => {
+                FileCaps::READ
+                    | FileCaps::WRITE
+                    | FileCaps::FDSTAT_SET_FLAGS
+                    | FileCaps::FILESTAT_GET
+                    | FileCaps::POLL_READWRITE
+            }
+            SocketKind::Listener(_) => {
+                FileCaps::FDSTAT_SET_FLAGS | FileCaps::FILESTAT_GET | FileCaps::POLL_READWRITE
+            }
+        };
+        let socket = Arc::new(WasiSocket {
+            kind,
+            nonblocking: AtomicBool::new(flags.contains(FdFlags::NONBLOCK)),
+        });
+        let entry = Entry {
+            socket: Arc::downgrade(&socket),
+            stream,
+        };
+        self.sockets.insert(fd, entry);
+        wasi.insert_file(fd, Box::new(SocketFile(socket)), caps);
+        fd
+    }
+
+    // Moves the entry of a renumbered file descriptor, if it's a socket.
+    pub(crate) fn renumber(&mut self, from: u32, to: u32) {
+        if let Some(entry) = self.sockets.remove(&from) {
+            self.sockets.insert(to, entry);
+        }
+    }
+
+    pub(crate) fn get(&self, fd: u32) -> Option<Arc<WasiSocket>> {
+        self.sockets.get(&fd)?.socket.upgrade()
+    }
+
+    // Removes the entries of closed sockets and returns the number of closed streams among them.
+    pub(crate) fn prune(&mut self) -> usize {
+        let mut closed_streams = 0;
+        self.sockets.retain(|_, entry| {
+            let open = entry.socket.strong_count() > 0;
+            if !open && entry.stream {
+                closed_streams += 1;
+            }
+            open
+        });
+        closed_streams
+    }
+}
+
+// The entry of a socket in the WASI file descriptor table. Reading and writing is implemented by
+// the runtime's `fd_read` and `fd_write`, as WASI files can't wait without blocking the thread.
+struct SocketFile(Arc<WasiSocket>);
+
+#[async_trait]
+impl WasiFile for SocketFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn datasync(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::SocketStream)
+    }
+
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        if self.0.nonblocking.load(Ordering::Relaxed) {
+            Ok(FdFlags::NONBLOCK)
+        } else {
+            Ok(FdFlags::empty())
+        }
+    }
+
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        if !FdFlags::NONBLOCK.contains(flags) {
+            return Err(Error::invalid_argument().context("sockets only support NONBLOCK"));
+        }
+        self.0
+            .nonblocking
+            .store(flags.contains(FdFlags::NONBLOCK), Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: 0,
+            inode: 0,
+            filetype: FileType::SocketStream,
+            nlink: 0,
+            size: 0,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+
+    async fn set_filestat_size(&self, _size: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn advise(&self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Err(Error::seek_pipe())
+    }
+
+    async fn allocate(&self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::seek_pipe())
+    }
+
+    async fn set_times(
+        &self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn read_vectored<'a>(&self, _bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        _bufs: &mut [IoSliceMut<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+
+    async fn write_vectored<'a>(&self, _bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn write_vectored_at<'a>(
+        &self,
+        _bufs: &[IoSlice<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+
+    async fn seek(&self, _pos: io::SeekFrom) -> Result<u64, Error> {
+        Err(Error::seek_pipe())
+    }
+
+    async fn peek(&self, _buf: &mut [u8]) -> Result<u64, Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Err(Error::not_supported())
+    }
+}
+
+// Converts errors of socket operations into WASI error numbers.
+pub(crate) fn errno(error: io::Error) -> Errno {
+    match error.kind() {
+        io::ErrorKind::WouldBlock => Errno::Again,
+        io::ErrorKind::ConnectionRefused => Errno::Connrefused,
+        io::ErrorKind::ConnectionReset => Errno::Connreset,
+        io::ErrorKind::ConnectionAborted => Errno::Connaborted,
+        io::ErrorKind::NotConnected => Errno::Notconn,
+        io::ErrorKind::AddrInUse => Errno::Addrinuse,
+        io::ErrorKind::AddrNotAvailable => Errno::Addrnotavail,
+        io::ErrorKind::BrokenPipe => Errno::Pipe,
+        io::ErrorKind::TimedOut => Errno::Timedout,
+        io::ErrorKind::InvalidInput => Errno::Inval,
+        _ => Errno::try_from(error).unwrap_or(Errno::Io),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_std::{
+        channel::unbounded,
+        io::{ReadExt, WriteExt},
+        net::TcpListener,
+    };
+    use uuid::Uuid;
+
+    use crate::{message::Message, stdio::WasiOutput, EnvConfig, Environment, Signal, WasmProcess};
+
+    #[async_std::test]
+    async fn tcp_stream_as_wasi_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut ping = [0; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            stream.write_all(b"pong").await.unwrap();
+            // The guest shuts down its writing half after receiving
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await.unwrap();
+            // Closing the file descriptor frees the quota for another stream
+            listener.accept().await.unwrap();
+            (ping, rest)
+        });
+
+        let (sender, receiver) = unbounded();
+        let output = WasmProcess::new(Uuid::new_v4(), sender);
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.allow_namespace("lunatic::networking::*").unwrap();
+        config.set_wasi_stdout(WasiOutput::Process(Arc::new(output), None));
+        config.set_max_tcp_streams(Some(1));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(format!(
+            r#"
+            (module
+                (import "lunatic::networking" "tcp_connect" (func $tcp_connect (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_stream_into_wasi_fd" (func $into_wasi_fd (param i64) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+                (import "wasi_snapshot_preview1" "sock_recv" (func $sock_recv (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "sock_shutdown" (func $sock_shutdown (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (data (i32.const 16) "ping")
+                (data (i32.const 24) "\10\00\00\00\04\00\00\00")
+                (data (i32.const 32) "\30\00\00\00\02\00\00\00")
+                (data (i32.const 56) "\30\00\00\00\08\00\00\00")
+                (data (i32.const 72) "\32\00\00\00\02\00\00\00")
+                (func (export "run") (local $fd i32)
+                    (drop (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const {0}) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 8)))
+                    (local.set $fd (call $into_wasi_fd (i64.load (i32.const 8))))
+                    (drop (call $fd_write (local.get $fd) (i32.const 24) (i32.const 1) (i32.const 64)))
+                    (drop (call $fd_read (local.get $fd) (i32.const 32) (i32.const 1) (i32.const 64)))
+                    ;; RECV_WAITALL
+                    (drop (call $sock_recv (local.get $fd) (i32.const 72) (i32.const 1) (i32.const 2) (i32.const 40) (i32.const 44)))
+                    ;; SHUT_WR
+                    (drop (call $sock_shutdown (local.get $fd) (i32.const 2)))
+                    (drop (call $fd_close (local.get $fd)))
+                    (i32.store (i32.const 52) (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const {0}) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 80)))
+                    (drop (call $fd_write (i32.const 1) (i32.const 56) (i32.const 1) (i32.const 64)))))
+            "#,
+            port
+        ))
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (task, _) = module.spawn("run", Vec::new(), None).await.unwrap();
+        task.await;
+
+        let (ping, rest) = server.await;
+        assert_eq!(&ping, b"ping");
+        assert!(rest.is_empty());
+        match receiver.try_recv() {
+            // The received data followed by the result of the second connect
+            Ok(Signal::Message(Message::Data(data))) => {
+                assert_eq!(data.buffer(), b"pong\0\0\0\0")
+            }
+            _ => panic!("Expected the received data on stdout"),
+        }
+    }
+
+    #[async_std::test]
+    async fn renumber_and_poll_tcp_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = async_std::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut ping = [0; 4];
+            stream.read_exact(&mut ping).await.unwrap();
+            // Closing the renumbered file descriptor frees the quota for another stream
+            listener.accept().await.unwrap();
+            ping
+        });
+
+        let mut config = EnvConfig::new(0x100000, None);
+        config.allow_namespace("wasi_snapshot_preview1::*").unwrap();
+        config.allow_namespace("lunatic::networking::*").unwrap();
+        config.set_max_tcp_streams(Some(1));
+        let environment = Environment::new(config).unwrap();
+        let raw_module = wat::parse_str(format!(
+            r#"
+            (module
+                (import "lunatic::networking" "tcp_connect" (func $tcp_connect (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_stream_into_wasi_fd" (func $into_wasi_fd (param i64) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_renumber" (func $fd_renumber (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+                (import "wasi_snapshot_preview1" "poll_oneoff" (func $poll_oneoff (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "sock_send" (func $sock_send (param i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (data (i32.const 16) "ping")
+                (data (i32.const 24) "\10\00\00\00\04\00\00\00")
+                ;; A read subscription (event type 1) on file descriptor 10
+                (data (i32.const 104) "\01")
+                (data (i32.const 112) "\0a\00\00\00")
+                (func (export "run") (local $fd i32)
+                    (if (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const {0}) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 8))
+                        (then unreachable))
+                    (local.set $fd (call $into_wasi_fd (i64.load (i32.const 8))))
+                    (if (call $fd_renumber (local.get $fd) (i32.const 10))
+                        (then unreachable))
+                    ;; The stream is only found under its new number (EBADF)
+                    (if (i32.ne (call $sock_send (local.get $fd) (i32.const 24) (i32.const 1) (i32.const 0) (i32.const 64)) (i32.const 8))
+                        (then unreachable))
+                    (if (call $sock_send (i32.const 10) (i32.const 24) (i32.const 1) (i32.const 0) (i32.const 64))
+                        (then unreachable))
+                    ;; Sockets can't be polled (ENOTSUP)
+                    (if (i32.ne (call $poll_oneoff (i32.const 96) (i32.const 160) (i32.const 1) (i32.const 200)) (i32.const 58))
+                        (then unreachable))
+                    (if (call $fd_close (i32.const 10))
+                        (then unreachable))
+                    (if (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const {0}) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 8))
+                        (then unreachable))))
+            "#,
+            port
+        ))
+        .unwrap();
+        let module = environment.create_module(raw_module).await.unwrap();
+        let (sender, receiver) = unbounded();
+        let parent = WasmProcess::new(Uuid::new_v4(), sender);
+        let (task, _) = module
+            .spawn("run", Vec::new(), Some((Some(1), parent)))
+            .await
+            .unwrap();
+        task.await;
+
+        // The process finished normally
+        assert!(matches!(receiver.recv().await, Ok(Signal::Link(_, _))));
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(&server.await, b"ping");
+    }
+}
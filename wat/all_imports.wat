@@ -10,6 +10,7 @@
     (import "lunatic::message" "read_data" (func (param i32 i32) (result i32)))
     (import "lunatic::message" "seek_data" (func (param i64)))
     (import "lunatic::message" "get_tag" (func (result i64)))
+    (import "lunatic::message" "get_exit_reason" (func (result i32)))
     (import "lunatic::message" "data_size" (func (result i64)))
     (import "lunatic::message" "push_process" (func (param i64) (result i64)))
     (import "lunatic::message" "take_process" (func (param i64) (result i64)))
@@ -31,20 +32,38 @@
     (import "lunatic::networking" "tcp_write_vectored" (func (param i64 i32 i32 i32 i32) (result i32)))
     (import "lunatic::networking" "tcp_read" (func (param i64 i32 i32 i32 i32) (result i32)))
     (import "lunatic::networking" "tcp_flush" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "tcp_stream_into_wasi_fd" (func (param i64) (result i32)))
+    (import "lunatic::networking" "tcp_listener_into_wasi_fd" (func (param i64) (result i32)))
     
     (import "lunatic::process" "create_config" (func (param i64 i64) (result i64)))
     (import "lunatic::process" "drop_config" (func (param i64)))
     (import "lunatic::process" "allow_namespace" (func (param i64 i32 i32)))
+    (import "lunatic::process" "deny_namespace" (func (param i64 i32 i32)))
+    (import "lunatic::process" "set_resource_limits" (func (param i64 i32 i32 i32 i32)))
     (import "lunatic::process" "add_plugin" (func (param i64 i32 i32 i32) (result i32)))
     (import "lunatic::process" "create_environment" (func (param i64 i32) (result i32)))
     (import "lunatic::process" "drop_environment" (func (param i64)))
     (import "lunatic::process" "add_module" (func (param i64 i32 i32 i32) (result i32)))
     (import "lunatic::process" "add_this_module" (func (param i64 i32) (result i32)))
     (import "lunatic::process" "drop_module" (func (param i64)))
-    (import "lunatic::process" "spawn" (func (param i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::process" "inherit_spawn" (func (param i64 i32 i32 i32 i32  i32) (result i32)))
+    (import "lunatic::process" "fork" (func (param i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "create_spawn_options" (func (result i64)))
+    (import "lunatic::process" "drop_spawn_options" (func (param i64)))
+    (import "lunatic::process" "spawn_options_set_message" (func (param i64)))
+    (import "lunatic::process" "spawn_options_set_link" (func (param i64 i64)))
+    (import "lunatic::process" "spawn_options_set_monitor" (func (param i64 i64)))
+    (import "lunatic::process" "spawn_options_register" (func (param i64 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_options_add_arg" (func (param i64 i32 i32)))
+    (import "lunatic::process" "spawn_options_add_env" (func (param i64 i32 i32 i32 i32)))
+    (import "lunatic::process" "spawn_options_set_stdin" (func (param i64 i32 i32)))
+    (import "lunatic::process" "spawn_options_set_stdout" (func (param i64 i64 i64)))
+    (import "lunatic::process" "spawn_options_set_stderr" (func (param i64 i64 i64)))
+    (import "lunatic::process" "spawn_with" (func (param i64 i32 i32 i32 i32 i64 i32) (result i32)))
     (import "lunatic::process" "drop_process" (func (param i64)))
     (import "lunatic::process" "clone_process" (func (param i64) (result i64)))
+    (import "lunatic::process" "fuel_used" (func (result i64)))
     (import "lunatic::process" "sleep_ms" (func (param i64)))
     (import "lunatic::process" "die_when_link_dies" (func (param i32)))
     (import "lunatic::process" "this" (func (result i64)))
@@ -52,11 +71,58 @@
     (import "lunatic::process" "this_env" (func (result i64)))
     (import "lunatic::process" "link" (func (param i64 i64)))
     (import "lunatic::process" "unlink" (func (param i64)))
+    (import "lunatic::process" "upgrade" (func (param i64 i64 i32 i32) (result i32)))
+    (import "lunatic::process" "code_change" (func (param i32 i32) (result i32)))
+    (import "lunatic::process" "suspend" (func (result i32)))
     (import "lunatic::process" "register" (func (param i32 i32 i32 i32 i64 i64) (result i32)))
     (import "lunatic::process" "unregister" (func (param i32 i32 i32 i32 i64) (result i32)))
     (import "lunatic::process" "lookup" (func (param i32 i32 i32 i32 i32) (result i32)))
 
-    ;; TODO: Add all WASI imports
+    (import "wasi_snapshot_preview1" "args_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "args_sizes_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "clock_res_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "clock_time_get" (func (param i32 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "environ_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "environ_sizes_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_advise" (func (param i32 i64 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_allocate" (func (param i32 i64 i64) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_close" (func (param i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_datasync" (func (param i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_fdstat_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_fdstat_set_flags" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_fdstat_set_rights" (func (param i32 i64 i64) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_filestat_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_filestat_set_size" (func (param i32 i64) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_filestat_set_times" (func (param i32 i64 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_pread" (func (param i32 i32 i32 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_prestat_dir_name" (func (param i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_prestat_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_pwrite" (func (param i32 i32 i32 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_read" (func (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_readdir" (func (param i32 i32 i32 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_renumber" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_seek" (func (param i32 i64 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_sync" (func (param i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_tell" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "fd_write" (func (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_create_directory" (func (param i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_filestat_get" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_filestat_set_times" (func (param i32 i32 i32 i32 i64 i64 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_link" (func (param i32 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_open" (func (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_readlink" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_remove_directory" (func (param i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_rename" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_symlink" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "path_unlink_file" (func (param i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "poll_oneoff" (func (param i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+    (import "wasi_snapshot_preview1" "proc_raise" (func (param i32) (result i32)))
+    (import "wasi_snapshot_preview1" "random_get" (func (param i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "sched_yield" (func (result i32)))
+    (import "wasi_snapshot_preview1" "sock_recv" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "sock_send" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "wasi_snapshot_preview1" "sock_shutdown" (func (param i32 i32) (result i32)))
 
     (func (export "hello") nop)
 )
\ No newline at end of file